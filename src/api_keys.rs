@@ -0,0 +1,393 @@
+use std::{
+    cmp::Reverse,
+    future::{ready, Ready},
+};
+
+use actix_web::{dev::Payload, http::header, web, FromRequest, HttpRequest};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{NaiveDateTime, Utc};
+use log::info;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config,
+    error::{ApiError, ApiResult},
+    filter::glob_set,
+    github_oidc::{GithubClaims, GithubOidc},
+    report::Request,
+    storage::{Record, TypedDb},
+};
+
+/// Header containing the api key
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+const TOKEN_PREFIX: &str = "lch_";
+
+/// Stored by the index of its variant with sled, new variants go at the end
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    /// Send reports, and update the baseline of the base branch
+    Upload,
+    /// Delete reports
+    Delete,
+    /// Manage the api keys, it isn't restricted to the repositories of the key
+    Admin,
+    /// Read the reports, when the viewer authentication is enabled
+    Read,
+}
+
+/// An api key stored in the database, only the hash of the token is kept
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    /// Glob patterns matched against the `owner/project` name of the repository
+    pub repositories: Vec<String>,
+    /// Glob patterns matched against the host of the git url of an upload, the `owner/project`
+    /// name is the same on every host
+    pub git_hosts: Vec<String>,
+    pub permissions: Vec<Permission>,
+    pub created_at: NaiveDateTime,
+    pub revoked_at: Option<NaiveDateTime>,
+    pub(crate) hash: Vec<u8>,
+}
+
+impl Record for ApiKey {
+    const COLLECTION: &'static str = "api_keys";
+    const VERSION: u32 = 2;
+}
+
+/// Body of `POST /admin/api-keys`
+#[derive(Debug, Deserialize)]
+pub struct NewApiKey {
+    pub name: String,
+    pub repositories: Vec<String>,
+    pub git_hosts: Vec<String>,
+    pub permissions: Vec<Permission>,
+}
+
+/// An api key as returned by the admin endpoints, without its hash
+#[derive(Debug, Serialize)]
+pub struct ApiKeyInfo<'a> {
+    pub id: &'a str,
+    pub name: &'a str,
+    pub repositories: &'a [String],
+    pub git_hosts: &'a [String],
+    pub permissions: &'a [Permission],
+    pub created_at: NaiveDateTime,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+fn hash_token(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
+
+impl ApiKey {
+    /// Create and store a new key, the returned token is the only time it is visible
+    pub fn create(storage: &TypedDb, new_key: NewApiKey) -> ApiResult<(Self, String)> {
+        glob_set(new_key.repositories.iter().map(String::as_str))?;
+        glob_set(new_key.git_hosts.iter().map(String::as_str))?;
+
+        let mut rng = rand::thread_rng();
+        let id = format!("{:016x}", rng.gen::<u64>());
+        let secret = URL_SAFE_NO_PAD.encode(rng.gen::<[u8; 32]>());
+        let token = format!("{TOKEN_PREFIX}{id}_{secret}");
+
+        let key = Self {
+            id,
+            name: new_key.name,
+            repositories: new_key.repositories,
+            git_hosts: new_key.git_hosts,
+            permissions: new_key.permissions,
+            created_at: Utc::now().naive_utc(),
+            revoked_at: None,
+            hash: hash_token(&token),
+        };
+        storage.insert(&key.id, &key)?;
+        Ok((key, token))
+    }
+
+    /// The key of the token, if it exists and isn't revoked
+    pub fn find(storage: &TypedDb, token: &str) -> ApiResult<Option<Self>> {
+        // The id is hex, so the first `_` separates it from the secret
+        let Some((id, _secret)) = token
+            .strip_prefix(TOKEN_PREFIX)
+            .and_then(|rest| rest.split_once('_'))
+        else {
+            return Ok(None);
+        };
+        let key: Option<Self> = storage.get(id)?;
+        Ok(key.filter(|key| key.revoked_at.is_none() && key.hash == hash_token(token)))
+    }
+
+    /// Every key, revoked ones included, the most recent first
+    pub fn list(storage: &TypedDb) -> ApiResult<Vec<Self>> {
+        let mut keys: Vec<Self> = storage.get_all()?;
+        keys.sort_by_key(|key| Reverse(key.created_at));
+        Ok(keys)
+    }
+
+    /// Revoked keys are kept so they can still be listed
+    pub fn revoke(storage: &TypedDb, id: &str) -> ApiResult<Self> {
+        let mut key: Self = storage
+            .get(id)?
+            .ok_or_else(|| ApiError::ApiKeyNotFound(id.to_string()))?;
+        if key.revoked_at.is_none() {
+            key.revoked_at = Some(Utc::now().naive_utc());
+            storage.insert(&key.id, &key)?;
+        }
+        Ok(key)
+    }
+
+    pub fn info(&self) -> ApiKeyInfo<'_> {
+        ApiKeyInfo {
+            id: &self.id,
+            name: &self.name,
+            repositories: &self.repositories,
+            git_hosts: &self.git_hosts,
+            permissions: &self.permissions,
+            created_at: self.created_at,
+            revoked_at: self.revoked_at,
+        }
+    }
+
+    pub fn allows(&self, repository: &str, permission: Permission) -> ApiResult<bool> {
+        Ok(self.permissions.contains(&permission)
+            && glob_set(self.repositories.iter().map(String::as_str))?.is_match(repository))
+    }
+
+    /// The local sources have no host, they are limited to the local roots of the server
+    pub fn allows_git_host(&self, host: Option<&str>) -> ApiResult<bool> {
+        match host {
+            Some(host) => Ok(glob_set(self.git_hosts.iter().map(String::as_str))?.is_match(host)),
+            None => Ok(true),
+        }
+    }
+}
+
+/// Caller authenticated by the [API_KEY_HEADER] header, or a GitHub Actions OIDC token in the `Authorization` header
+#[derive(Debug)]
+pub enum Principal {
+    /// The `api_key` of the server configuration, it has every permission
+    Bootstrap,
+    ApiKey(ApiKey),
+    /// A workflow, it can only upload and read reports of its own repository
+    GithubActions(GithubClaims),
+}
+
+impl Principal {
+    pub fn authenticate(req: &HttpRequest) -> ApiResult<Self> {
+        let bearer = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        if let Some(jwt) = bearer {
+            let github_oidc = GithubOidc::global().ok_or_else(|| {
+                ApiError::InvalidToken("OIDC authentication isn't enabled".to_string())
+            })?;
+            let claims = github_oidc.verify(jwt.trim())?;
+            if !github_oidc.allows(&claims.repository)? {
+                return Err(ApiError::Forbidden(format!(
+                    "the tokens of {} aren't allowed",
+                    claims.repository
+                )));
+            }
+            info!(
+                "OIDC token of {}, workflow: {}, ref: {}",
+                claims.repository, claims.workflow, claims.git_ref
+            );
+            return Ok(Self::GithubActions(claims));
+        }
+
+        let token = req
+            .headers()
+            .get(API_KEY_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .ok_or(ApiError::Unauthorized)?;
+
+        // Comparing the hashes, so the comparison time doesn't depend on the key
+        if config::get()
+            .api_key
+            .as_ref()
+            .is_some_and(|api_key| hash_token(api_key) == hash_token(token))
+        {
+            return Ok(Self::Bootstrap);
+        }
+
+        let storage = req
+            .app_data::<web::Data<TypedDb>>()
+            .expect("storage in the app data");
+        ApiKey::find(storage, token)?
+            .map(Self::ApiKey)
+            .ok_or(ApiError::Unauthorized)
+    }
+
+    /// Fails with [ApiError::Forbidden] if the caller doesn't have the permission on the repository
+    pub fn authorize(&self, repository: &str, permission: Permission) -> ApiResult<()> {
+        let allowed = match self {
+            Self::Bootstrap => true,
+            Self::ApiKey(key) => key.allows(repository, permission)?,
+            Self::GithubActions(claims) => {
+                matches!(permission, Permission::Upload | Permission::Read)
+                    && claims.repository.eq_ignore_ascii_case(repository)
+            }
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(ApiError::Forbidden(format!(
+                "{permission:?} on {repository} isn't allowed"
+            )))
+        }
+    }
+
+    /// An OIDC token is authorized by [Request::git], which must be the url of its repository.
+    ///
+    /// Its name isn't compared with [Request::raw_repository_name], which falls back to the whole
+    /// url for the names that [config::REPOSITORY_REGEX] doesn't capture.
+    /// An api key must also allow the host of [Request::git], see [ApiKey::git_hosts].
+    pub fn authorize_upload(&self, request: &Request) -> ApiResult<()> {
+        match self {
+            Self::GithubActions(claims) => {
+                let github_host = &config::get().github_oidc.github_host;
+                if claims.matches_git_url(&request.git, github_host) {
+                    Ok(())
+                } else {
+                    Err(ApiError::Forbidden(format!(
+                        "the token of {} can't upload reports for {}",
+                        claims.repository, request.git
+                    )))
+                }
+            }
+            Self::ApiKey(key) => {
+                self.authorize(&request.raw_repository_name(), Permission::Upload)?;
+                let host = request.git_host();
+                if key.allows_git_host(host.as_deref())? {
+                    Ok(())
+                } else {
+                    Err(ApiError::Forbidden(format!(
+                        "the key can't upload reports from {}",
+                        host.unwrap_or_default()
+                    )))
+                }
+            }
+            Self::Bootstrap => self.authorize(&request.raw_repository_name(), Permission::Upload),
+        }
+    }
+
+    /// Name of the caller in the audit log
+    pub fn name(&self) -> String {
+        match self {
+            Self::Bootstrap => "bootstrap api key".to_string(),
+            Self::ApiKey(key) => format!("api key {} ({})", key.name, key.id),
+            Self::GithubActions(claims) => {
+                format!("github actions {} ({})", claims.repository, claims.workflow)
+            }
+        }
+    }
+
+    pub fn authorize_admin(&self) -> ApiResult<()> {
+        match self {
+            Self::Bootstrap => Ok(()),
+            Self::ApiKey(key) if key.permissions.contains(&Permission::Admin) => Ok(()),
+            Self::ApiKey(_) | Self::GithubActions(_) => {
+                Err(ApiError::Forbidden("Admin isn't allowed".to_string()))
+            }
+        }
+    }
+}
+
+impl FromRequest for Principal {
+    type Error = ApiError;
+    type Future = Ready<ApiResult<Self>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(Self::authenticate(req))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{memory_db, request};
+
+    #[test]
+    fn test_api_keys() {
+        let storage = memory_db();
+
+        let (key, token) = ApiKey::create(
+            &storage,
+            NewApiKey {
+                name: "ci".to_string(),
+                repositories: vec!["GreeFine/*".to_string()],
+                git_hosts: vec!["github.com".to_string()],
+                permissions: vec![Permission::Upload],
+            },
+        )
+        .unwrap();
+        assert!(token.starts_with("lch_"));
+
+        let found = ApiKey::find(&storage, &token).unwrap().unwrap();
+        assert_eq!(found.id, key.id);
+        assert!(ApiKey::find(&storage, &format!("{token}x"))
+            .unwrap()
+            .is_none());
+        assert!(ApiKey::find(&storage, "secret").unwrap().is_none());
+
+        let principal = Principal::ApiKey(found);
+        principal
+            .authorize("GreeFine/llvm-cov-host", Permission::Upload)
+            .unwrap();
+        assert!(principal
+            .authorize("GreeFine/llvm-cov-host", Permission::Delete)
+            .is_err());
+        assert!(principal
+            .authorize("other/project", Permission::Upload)
+            .is_err());
+        assert!(principal.authorize_admin().is_err());
+
+        // The same `owner/project` on another host is another repository
+        for git in [
+            "https://github.com/GreeFine/app.git",
+            "git@github.com:GreeFine/app.git",
+            "https://GitHub.com/GreeFine/app",
+            "/srv/mirrors/GreeFine/app.git",
+        ] {
+            principal.authorize_upload(&request(git, "main")).unwrap();
+        }
+        for git in [
+            "https://evil.example/GreeFine/app.git",
+            "git@evil.example:GreeFine/app.git",
+        ] {
+            assert!(matches!(
+                principal.authorize_upload(&request(git, "main")),
+                Err(ApiError::Forbidden(_))
+            ));
+        }
+        Principal::Bootstrap
+            .authorize("other/project", Permission::Delete)
+            .unwrap();
+
+        let revoked = ApiKey::revoke(&storage, &key.id).unwrap();
+        assert!(revoked.revoked_at.is_some());
+        assert!(ApiKey::find(&storage, &token).unwrap().is_none());
+        assert_eq!(ApiKey::list(&storage).unwrap().len(), 1);
+        assert!(ApiKey::revoke(&storage, "unknown").is_err());
+
+        assert!(ApiKey::create(
+            &storage,
+            NewApiKey {
+                name: "invalid".to_string(),
+                repositories: vec!["[".to_string()],
+                git_hosts: vec![],
+                permissions: vec![],
+            },
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,469 @@
+use std::{
+    fmt::Debug,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::bail;
+
+use crate::{
+    config::{ArtifactBackend, OutputConfig, ServerConfig},
+    s3::S3Store,
+    utils::remove_path,
+};
+
+/// Keys are `/` separated relative paths, without `.` or `..`, like the paths of `/view`
+pub fn is_valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && key
+            .split('/')
+            .all(|part| !part.is_empty() && part != "." && part != "..")
+}
+
+/// Where the JSON reports and the HTML reports are kept, the directories of a key are the
+/// prefixes before its `/`
+pub trait ArtifactStore: Debug + Send + Sync {
+    fn put(&self, key: &str, content: &[u8]) -> anyhow::Result<()>;
+
+    fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>>;
+
+    fn exists(&self, key: &str) -> anyhow::Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    /// Keys and sizes of the artifacts in the directory and its subdirectories
+    fn list(&self, dir: &str) -> anyhow::Result<Vec<(String, u64)>>;
+
+    /// Remove the artifact, and return its size if it existed
+    fn delete(&self, key: &str) -> anyhow::Result<Option<u64>>;
+
+    /// Remove the directory, and return the size of its artifacts if there were any
+    fn delete_dir(&self, dir: &str) -> anyhow::Result<Option<u64>> {
+        let artifacts = self.list(dir)?;
+        let mut size = None;
+        for (key, _) in artifacts {
+            if let Some(removed) = self.delete(&key)? {
+                size = Some(size.unwrap_or(0) + removed);
+            }
+        }
+        Ok(size)
+    }
+
+    /// Store the local file, it can be moved
+    fn put_file(&self, key: &str, path: &Path) -> anyhow::Result<()> {
+        self.put(key, &fs::read(path)?)
+    }
+
+    /// Store the files of the local directory in `dir`, they can be moved
+    fn put_dir(&self, dir: &str, path: &Path) -> anyhow::Result<()> {
+        put_files(self, dir, path)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> anyhow::Result<()> {
+        let Some(content) = self.get(from)? else {
+            bail!("no artifact {from}");
+        };
+        self.put(to, &content)?;
+        self.delete(from)?;
+        Ok(())
+    }
+}
+
+fn put_files<S: ArtifactStore + ?Sized>(store: &S, dir: &str, path: &Path) -> anyhow::Result<()> {
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some(name) = name.to_str() else {
+            bail!("artifact with a non UTF-8 name {:?}", entry.path());
+        };
+        let key = format!("{dir}/{name}");
+        if entry.file_type()?.is_dir() {
+            put_files(store, &key, &entry.path())?;
+        } else {
+            store.put_file(&key, &entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Artifacts in a local directory, at the path of their key
+#[derive(Debug, Clone)]
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+impl LocalStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn path(&self, key: &str) -> anyhow::Result<PathBuf> {
+        if !is_valid_key(key) {
+            bail!("invalid artifact key {key:?}");
+        }
+        Ok(self.root.join(key))
+    }
+
+    fn create_parent(path: &Path) -> io::Result<()> {
+        match path.parent() {
+            Some(parent) => fs::create_dir_all(parent),
+            None => Ok(()),
+        }
+    }
+
+    /// Remove the directories left empty by a removal, up to the root
+    fn remove_empty_parents(&self, path: &Path) {
+        let mut dir = path.parent();
+        while let Some(parent) = dir.filter(|parent| *parent != self.root) {
+            if fs::remove_dir(parent).is_err() {
+                break;
+            }
+            dir = parent.parent();
+        }
+    }
+
+    fn rename_path(&self, from: &Path, to: &Path) -> anyhow::Result<()> {
+        Self::create_parent(to)?;
+        if fs::rename(from, to).is_err() {
+            fs::copy(from, to)?;
+        }
+        Ok(())
+    }
+
+    fn list_files(&self, path: &Path, key: &str, files: &mut Vec<(String, u64)>) -> io::Result<()> {
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let key = format!("{key}/{}", name.to_string_lossy());
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                self.list_files(&entry.path(), &key, files)?;
+            } else {
+                files.push((key, metadata.len()));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ArtifactStore for LocalStore {
+    fn put(&self, key: &str, content: &[u8]) -> anyhow::Result<()> {
+        let path = self.path(key)?;
+        Self::create_parent(&path)?;
+        fs::write(path, content)?;
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let path = self.path(key)?;
+        if !path.is_file() {
+            return Ok(None);
+        }
+        Ok(Some(fs::read(path)?))
+    }
+
+    fn exists(&self, key: &str) -> anyhow::Result<bool> {
+        Ok(self.path(key)?.is_file())
+    }
+
+    fn list(&self, dir: &str) -> anyhow::Result<Vec<(String, u64)>> {
+        let path = self.path(dir)?;
+        let mut files = vec![];
+        if path.is_dir() {
+            self.list_files(&path, dir, &mut files)?;
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    fn delete(&self, key: &str) -> anyhow::Result<Option<u64>> {
+        let path = self.path(key)?;
+        if path.is_dir() {
+            return Ok(None);
+        }
+        let size = remove_path(&path)?;
+        self.remove_empty_parents(&path);
+        Ok(size)
+    }
+
+    fn delete_dir(&self, dir: &str) -> anyhow::Result<Option<u64>> {
+        let path = self.path(dir)?;
+        let size = remove_path(&path)?;
+        self.remove_empty_parents(&path);
+        Ok(size)
+    }
+
+    fn put_file(&self, key: &str, path: &Path) -> anyhow::Result<()> {
+        self.rename_path(path, &self.path(key)?)
+    }
+
+    fn put_dir(&self, dir: &str, path: &Path) -> anyhow::Result<()> {
+        let target = self.path(dir)?;
+        remove_path(&target)?;
+        Self::create_parent(&target)?;
+        // Copied when the temporary directory is on another file system
+        if fs::rename(path, &target).is_err() {
+            put_files(self, dir, path)?;
+        }
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> anyhow::Result<()> {
+        let from = self.path(from)?;
+        self.rename_path(&from, &self.path(to)?)?;
+        self.remove_empty_parents(&from);
+        Ok(())
+    }
+}
+
+/// The stores of the JSON reports, keyed by [crate::report::Request::unique_name], and of the
+/// HTML reports, in `<unique name>/<upload id>/`
+#[derive(Debug, Clone)]
+pub struct Artifacts {
+    pub json: Arc<dyn ArtifactStore>,
+    pub html: Arc<dyn ArtifactStore>,
+}
+
+impl Artifacts {
+    /// In the directories of the output configuration
+    pub fn local(output: &OutputConfig) -> Self {
+        Self {
+            json: Arc::new(LocalStore::new(&output.json_reports_dir)),
+            html: Arc::new(LocalStore::new(&output.html_reports_dir)),
+        }
+    }
+
+    pub fn open(config: &ServerConfig) -> Self {
+        match config.output.artifact_backend {
+            ArtifactBackend::Local => Self::local(&config.output),
+            ArtifactBackend::S3 => Self {
+                json: Arc::new(S3Store::new(&config.s3, "json-reports/")),
+                html: Arc::new(S3Store::new(&config.s3, "html-reports/")),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::S3Config, test_utils::sha256_hex};
+
+    /// An S3 compatible stand-in, like MinIO, with the objects in memory, and pages of 2 keys for
+    /// `ListObjectsV2`
+    fn serve_object_store() -> String {
+        use std::collections::BTreeMap;
+        use std::io::{BufRead, BufReader, Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let mut objects = BTreeMap::<String, Vec<u8>>::new();
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut headers = std::collections::HashMap::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        headers.insert(name.to_lowercase(), value.trim().to_string());
+                    }
+                }
+                let content_length = headers
+                    .get("content-length")
+                    .map_or(0, |length| length.parse().unwrap());
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+
+                let mut parts = request_line.split(' ');
+                let (method, target) = (parts.next().unwrap(), parts.next().unwrap());
+                let url = url::Url::parse(&format!("http://localhost{target}")).unwrap();
+                let path = url.path().strip_prefix("/bucket/").unwrap_or_default();
+                let key = urlencoding_decode(path);
+                let signed = headers
+                    .get("authorization")
+                    .is_some_and(|auth| auth.starts_with("AWS4-HMAC-SHA256 Credential=minio/"));
+                let hash = headers.get("x-amz-content-sha256").cloned();
+                let (status, response) = if !signed || hash != Some(sha256_hex(&body)) {
+                    (
+                        "403 Forbidden",
+                        b"<Error><Code>AccessDenied</Code></Error>".to_vec(),
+                    )
+                } else {
+                    match method {
+                        "PUT" => {
+                            objects.insert(key, body);
+                            ("200 OK", vec![])
+                        }
+                        "GET" | "HEAD" if key.is_empty() => {
+                            let query: std::collections::HashMap<_, _> =
+                                url.query_pairs().into_owned().collect();
+                            let prefix = query.get("prefix").cloned().unwrap_or_default();
+                            let start =
+                                query.get("continuation-token").cloned().unwrap_or_default();
+                            let keys: Vec<_> = objects
+                                .range(start..)
+                                .filter(|(key, _)| key.starts_with(&prefix))
+                                .collect();
+                            let mut xml = String::from("<ListBucketResult>");
+                            for (key, content) in keys.iter().take(2) {
+                                let key = key.replace('&', "&amp;");
+                                xml += &format!(
+                                    "<Contents><Key>{key}</Key><Size>{}</Size></Contents>",
+                                    content.len()
+                                );
+                            }
+                            match keys.get(2) {
+                                Some((next, _)) => {
+                                    xml += &format!("<IsTruncated>true</IsTruncated><NextContinuationToken>{next}</NextContinuationToken>")
+                                }
+                                None => xml += "<IsTruncated>false</IsTruncated>",
+                            }
+                            ("200 OK", format!("{xml}</ListBucketResult>").into_bytes())
+                        }
+                        "GET" | "HEAD" => match objects.get(&key) {
+                            Some(content) => ("200 OK", content.clone()),
+                            None => (
+                                "404 Not Found",
+                                b"<Error><Code>NoSuchKey</Code></Error>".to_vec(),
+                            ),
+                        },
+                        "DELETE" => {
+                            objects.remove(&key);
+                            ("204 No Content", vec![])
+                        }
+                        _ => ("405 Method Not Allowed", vec![]),
+                    }
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    response.len()
+                )
+                .unwrap();
+                if method != "HEAD" {
+                    stream.write_all(&response).unwrap();
+                }
+            }
+        });
+        address
+    }
+
+    fn urlencoding_decode(path: &str) -> String {
+        url::form_urlencoded::parse(format!("key={}", path.replace('+', "%2B")).as_bytes())
+            .next()
+            .map(|(_, key)| key.into_owned())
+            .unwrap_or_default()
+    }
+
+    /// The same behavior with the local and the S3 stores
+    fn check_artifact_store(store: &dyn ArtifactStore) {
+        store.put("app-main/1/index.html", b"index").unwrap();
+        store.put("app-main/1/src/lib.rs.html", b"lib").unwrap();
+        store.put("app-main/2/index.html", b"second").unwrap();
+        store.put("app-main/2/a & b.html", b"ab").unwrap();
+        store.put("app-feature/1/index.html", b"feature").unwrap();
+        assert_eq!(
+            store.get("app-main/1/index.html").unwrap().as_deref(),
+            Some(&b"index"[..])
+        );
+        assert!(store.get("app-main/3/index.html").unwrap().is_none());
+        assert_eq!(
+            store.list("app-main").unwrap(),
+            vec![
+                ("app-main/1/index.html".to_string(), 5),
+                ("app-main/1/src/lib.rs.html".to_string(), 3),
+                ("app-main/2/a & b.html".to_string(), 2),
+                ("app-main/2/index.html".to_string(), 6),
+            ]
+        );
+        assert!(store.list("app-fix").unwrap().is_empty());
+
+        assert_eq!(store.delete("app-main/2/a & b.html").unwrap(), Some(2));
+        assert_eq!(store.delete("app-main/2/a & b.html").unwrap(), None);
+        assert_eq!(store.delete_dir("app-main/1").unwrap(), Some(8));
+        assert_eq!(store.delete_dir("app-main/1").unwrap(), None);
+        assert_eq!(store.list("app-main").unwrap().len(), 1);
+
+        store
+            .rename("app-main/2/index.html", "app-main/3/index.html")
+            .unwrap();
+        assert!(store.get("app-main/2/index.html").unwrap().is_none());
+        assert!(store.get("app-main/3/index.html").unwrap().is_some());
+        assert!(store.rename("app-main/2/index.html", "app-main/4").is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("html/src")).unwrap();
+        fs::write(dir.path().join("html/index.html"), "uploaded").unwrap();
+        fs::write(dir.path().join("html/src/main.rs.html"), "main").unwrap();
+        fs::write(dir.path().join("report.json"), "{}").unwrap();
+        store
+            .put_dir("app-fix/1", &dir.path().join("html"))
+            .unwrap();
+        store
+            .put_file("app-fix.json", &dir.path().join("report.json"))
+            .unwrap();
+        assert_eq!(
+            store.list("app-fix").unwrap(),
+            vec![
+                ("app-fix/1/index.html".to_string(), 8),
+                ("app-fix/1/src/main.rs.html".to_string(), 4),
+            ]
+        );
+        assert_eq!(
+            store.get("app-fix.json").unwrap().as_deref(),
+            Some(&b"{}"[..])
+        );
+
+        for invalid in [
+            "",
+            "/app",
+            "app/../etc",
+            "app//index.html",
+            "app/./index.html",
+        ] {
+            assert!(store.put(invalid, b"").is_err(), "{invalid}");
+            assert!(store.get(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_artifact_stores() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        check_artifact_store(&LocalStore::new(dir));
+        assert!(dir.join("app-fix/1/src/main.rs.html").exists());
+        // The directories left empty are removed
+        assert!(!dir.join("app-main/1").exists());
+
+        let config = S3Config {
+            endpoint: serve_object_store(),
+            bucket: "bucket".to_string(),
+            prefix: "llvm-cov-host/".to_string(),
+            access_key_id: "minio".to_string(),
+            secret_access_key: "minio123".to_string(),
+            ..S3Config::default()
+        };
+        check_artifact_store(&S3Store::new(&config, "html-reports/"));
+        // The stores of the JSON and of the HTML reports don't share their keys
+        let json = S3Store::new(&config, "json-reports/");
+        assert!(json.get("app-fix.json").unwrap().is_none());
+        assert!(json.list("app-main").unwrap().is_empty());
+
+        let unsigned = S3Store::new(
+            &S3Config {
+                access_key_id: "other".to_string(),
+                ..config
+            },
+            "html-reports/",
+        );
+        assert!(unsigned.get("app-main/3/index.html").is_err());
+    }
+}
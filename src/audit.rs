@@ -0,0 +1,322 @@
+use actix_web::HttpRequest;
+use chrono::{NaiveDateTime, Utc};
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api_keys::Principal,
+    config,
+    error::ApiResult,
+    report::Request,
+    storage::{Record, TypedDb},
+};
+
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    ReportUpload,
+    /// An upload of the base branch replaced the report the other branches are compared to
+    BaselineChange,
+    ApiKeyCreation,
+    ApiKeyRevocation,
+    UserCreation,
+    UserDeletion,
+    /// A report, branch or repository deleted through the api
+    ReportDeletion,
+    /// Removal of the reports, branches and clones by the retention policy
+    GarbageCollection,
+    /// An archive of the records and reports, see [crate::backup]
+    Export,
+    Import,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOutcome {
+    Success,
+    Failure,
+}
+
+/// Caller of an audited request
+#[derive(Debug, Clone)]
+pub struct Actor {
+    pub name: String,
+    pub client_ip: Option<String>,
+}
+
+impl Actor {
+    pub fn new(req: &HttpRequest, principal: &Principal) -> Self {
+        Self {
+            name: principal.name(),
+            client_ip: client_ip(req),
+        }
+    }
+}
+
+/// Address of the client, the `Forwarded` headers are only read from the trusted proxies
+pub fn client_ip(req: &HttpRequest) -> Option<String> {
+    let peer = req.peer_addr()?.ip();
+    if config::get().viewer_auth.trusted_proxies.contains(&peer) {
+        if let Some(forwarded) = req.connection_info().realip_remote_addr() {
+            return Some(forwarded.to_string());
+        }
+    }
+    Some(peer.to_string())
+}
+
+/// An entry of the audit log, entries are never modified or removed
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AuditEntry {
+    pub id: u64,
+    pub date: NaiveDateTime,
+    pub action: AuditAction,
+    pub actor: String,
+    pub client_ip: Option<String>,
+    pub repository: Option<String>,
+    pub branch: Option<String>,
+    pub commit: Option<String>,
+    /// Id of the api key, or name of the user, of the admin actions
+    pub target: Option<String>,
+    /// Coverage of the uploads, or the error of a failure
+    pub details: Option<String>,
+    pub outcome: AuditOutcome,
+}
+
+impl Record for AuditEntry {
+    const COLLECTION: &'static str = "audit_log";
+    const VERSION: u32 = 1;
+}
+
+/// Query of `GET /admin/audit` and `/audit`, the entries are the most recent first
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditQuery {
+    /// Starting at 1
+    pub page: Option<usize>,
+    pub per_page: Option<usize>,
+    pub action: Option<AuditAction>,
+    pub repository: Option<String>,
+    pub actor: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuditPage {
+    pub entries: Vec<AuditEntry>,
+    pub page: usize,
+    pub per_page: usize,
+    pub next_page: Option<usize>,
+}
+
+impl AuditQuery {
+    /// The filters and page size, as a query string for the links to the other pages
+    pub fn filters(&self) -> String {
+        let mut query = url::form_urlencoded::Serializer::new(String::new());
+        if let Some(per_page) = self.per_page {
+            query.append_pair("per_page", &per_page.to_string());
+        }
+        if let Some(action) = self.action {
+            query.append_pair("action", action.as_str());
+        }
+        if let Some(repository) = &self.repository {
+            query.append_pair("repository", repository);
+        }
+        if let Some(actor) = &self.actor {
+            query.append_pair("actor", actor);
+        }
+        query.finish()
+    }
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::ReportUpload => "report_upload",
+            Self::BaselineChange => "baseline_change",
+            Self::ApiKeyCreation => "api_key_creation",
+            Self::ApiKeyRevocation => "api_key_revocation",
+            Self::UserCreation => "user_creation",
+            Self::UserDeletion => "user_deletion",
+            Self::ReportDeletion => "report_deletion",
+            Self::GarbageCollection => "garbage_collection",
+            Self::Export => "export",
+            Self::Import => "import",
+        }
+    }
+}
+
+impl AuditOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Success => "success",
+            Self::Failure => "failure",
+        }
+    }
+}
+
+impl AuditEntry {
+    pub fn new(actor: &Actor, action: AuditAction) -> Self {
+        Self {
+            id: 0,
+            date: Utc::now().naive_utc(),
+            action,
+            actor: actor.name.clone(),
+            client_ip: actor.client_ip.clone(),
+            repository: None,
+            branch: None,
+            commit: None,
+            target: None,
+            details: None,
+            outcome: AuditOutcome::Success,
+        }
+    }
+
+    /// Upload of the request, the commit is completed once the sources are checked out
+    pub fn upload(actor: &Actor, request: &Request) -> Self {
+        Self {
+            repository: Some(request.raw_repository_name()),
+            branch: Some(request.branch.clone()),
+            commit: request.commit.clone(),
+            ..Self::new(actor, AuditAction::ReportUpload)
+        }
+    }
+
+    /// Append the entry to the log, a failure to do so is only logged as the action already happened
+    pub fn record(mut self, storage: &TypedDb) {
+        let result = storage.generate_id().and_then(|id| {
+            self.id = id;
+            self.date = Utc::now().naive_utc();
+            // Zero padded, so the keys are in the order of the ids
+            storage.insert(&format!("{id:020}"), &self)
+        });
+        if let Err(e) = result {
+            error!("Failed to record the audit entry {self:?}: {e:#}");
+        }
+    }
+
+    /// Record the outcome of the action, with the error as details of a failure
+    pub fn record_result<T>(mut self, storage: &TypedDb, result: &ApiResult<T>) {
+        if let Err(e) = result {
+            self.outcome = AuditOutcome::Failure;
+            self.details = Some(e.to_string());
+        }
+        self.record(storage);
+    }
+
+    fn matches(&self, query: &AuditQuery) -> bool {
+        query.action.is_none_or(|action| self.action == action)
+            && query
+                .repository
+                .as_ref()
+                .is_none_or(|repository| self.repository.as_ref() == Some(repository))
+            && query
+                .actor
+                .as_ref()
+                .is_none_or(|actor| self.actor.contains(actor.as_str()))
+    }
+
+    pub fn page(storage: &TypedDb, query: &AuditQuery) -> ApiResult<AuditPage> {
+        let page = query.page.unwrap_or(1).max(1);
+        let per_page = query
+            .per_page
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE);
+
+        let mut entries = Vec::with_capacity(per_page + 1);
+        let matching = storage.iter::<Self>()?.filter(|entry| {
+            entry
+                .as_ref()
+                .map_or(true, |entry: &Self| entry.matches(query))
+        });
+        // One more entry than the page, to know if there is a next one
+        for entry in matching.skip((page - 1) * per_page).take(per_page + 1) {
+            entries.push(entry?);
+        }
+        let next_page = (entries.len() > per_page).then(|| {
+            entries.truncate(per_page);
+            page + 1
+        });
+        Ok(AuditPage {
+            entries,
+            page,
+            per_page,
+            next_page,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{error::ApiError, test_utils::memory_db};
+
+    #[test]
+    fn test_audit_log() {
+        let storage = memory_db();
+        let actor = Actor {
+            name: "api key ci (0123)".to_string(),
+            client_ip: Some("10.0.0.1".to_string()),
+        };
+
+        for branch in ["main", "feature", "main"] {
+            let entry = AuditEntry {
+                repository: Some("GreeFine/llvm-cov-host".to_string()),
+                branch: Some(branch.to_string()),
+                commit: Some("0a1b2c".to_string()),
+                ..AuditEntry::new(&actor, AuditAction::ReportUpload)
+            };
+            entry.record_result(&storage, &Ok::<_, ApiError>(()));
+        }
+        AuditEntry {
+            target: Some("alice".to_string()),
+            ..AuditEntry::new(&actor, AuditAction::UserDeletion)
+        }
+        .record_result(
+            &storage,
+            &Err::<(), _>(ApiError::UserNotFound("alice".to_string())),
+        );
+
+        // Most recent first
+        let page = AuditEntry::page(&storage, &AuditQuery::default()).unwrap();
+        assert_eq!(page.entries.len(), 4);
+        assert_eq!(page.next_page, None);
+        let deletion = &page.entries[0];
+        assert_eq!(deletion.action, AuditAction::UserDeletion);
+        assert_eq!(deletion.outcome, AuditOutcome::Failure);
+        assert_eq!(deletion.details.as_deref(), Some("user alice not found"));
+        assert_eq!(deletion.client_ip.as_deref(), Some("10.0.0.1"));
+        assert!(page.entries.windows(2).all(|w| w[0].id > w[1].id));
+
+        let uploads = AuditQuery {
+            action: Some(AuditAction::ReportUpload),
+            per_page: Some(2),
+            ..Default::default()
+        };
+        let first_page = AuditEntry::page(&storage, &uploads).unwrap();
+        assert_eq!(first_page.entries.len(), 2);
+        assert_eq!(first_page.next_page, Some(2));
+        assert_eq!(first_page.entries[1].branch.as_deref(), Some("feature"));
+        let second_page = AuditEntry::page(
+            &storage,
+            &AuditQuery {
+                page: first_page.next_page,
+                ..uploads
+            },
+        )
+        .unwrap();
+        assert_eq!(second_page.entries.len(), 1);
+        assert_eq!(second_page.next_page, None);
+        assert_eq!(second_page.entries[0].outcome, AuditOutcome::Success);
+
+        let query = AuditQuery {
+            repository: Some("GreeFine/other".to_string()),
+            ..Default::default()
+        };
+        assert!(AuditEntry::page(&storage, &query)
+            .unwrap()
+            .entries
+            .is_empty());
+        assert_eq!(query.filters(), "repository=GreeFine%2Fother");
+    }
+}
@@ -0,0 +1,803 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use anyhow::{anyhow, bail};
+use chrono::{NaiveDateTime, Utc};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api_keys::ApiKey,
+    artifacts::{is_valid_key, ArtifactStore, Artifacts},
+    audit::AuditEntry,
+    config,
+    error::{ApiError, ApiResult},
+    migrations,
+    raw_reports::{self, Baseline, RawReport},
+    report::{migrate_names, BaseBranch, ReportHistory, ReportRepository},
+    retention,
+    storage::{Encoding, Record, TypedDb},
+    users::User,
+    utils::TempDir,
+};
+
+/// Incremented when the layout of the archive changes
+const FORMAT_VERSION: u32 = 1;
+
+/// First entry of the archive
+const MANIFEST: &str = "manifest.json";
+
+/// A collection of the archive, in `records/{name}.jsonl` with a `{"key", "value"}` per line
+#[derive(Debug)]
+struct BackupCollection {
+    name: &'static str,
+    version: u32,
+    export: fn(&TypedDb, &mut dyn Write) -> anyhow::Result<usize>,
+    import: fn(&TypedDb, u32, &str, Pass) -> anyhow::Result<bool>,
+    /// The existing records are never overwritten, they are skipped instead
+    append_only: bool,
+}
+
+impl BackupCollection {
+    const fn of<T: Record>() -> Self {
+        Self {
+            name: T::COLLECTION,
+            version: T::VERSION,
+            export: export_records::<T>,
+            import: import_record::<T>,
+            append_only: false,
+        }
+    }
+
+    const fn append_only<T: Record>() -> Self {
+        Self {
+            append_only: true,
+            ..Self::of::<T>()
+        }
+    }
+
+    fn pass(&self, pass: Pass) -> Pass {
+        match pass {
+            Pass::Write(OnConflict::Overwrite) if self.append_only => Pass::Write(OnConflict::Skip),
+            pass => pass,
+        }
+    }
+}
+
+/// The sessions and the OIDC sign ins aren't kept, they expire within hours
+const COLLECTIONS: &[BackupCollection] = &[
+    BackupCollection::of::<ApiKey>(),
+    // An archive can't rewrite the history of the instance
+    BackupCollection::append_only::<AuditEntry>(),
+    BackupCollection::of::<BaseBranch>(),
+    // Only the baselines of the archives written before the reports were compressed
+    BackupCollection::of::<Baseline>(),
+    BackupCollection::of::<ReportHistory>(),
+    BackupCollection::of::<ReportRepository>(),
+    BackupCollection::of::<User>(),
+];
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Manifest {
+    format_version: u32,
+    created: NaiveDateTime,
+    /// Schema version of the records of each collection, older ones are upgraded by the import
+    collections: BTreeMap<String, u32>,
+    /// Next generated id of the exported database, the import doesn't reuse the ids below it
+    next_id: u64,
+    /// Whether the HTML reports are included
+    html: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct BackupRecord<T> {
+    key: String,
+    value: T,
+}
+
+/// What happens to a record or an artifact of the archive that already exists
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnConflict {
+    /// Nothing is imported if any of them exists
+    #[default]
+    Fail,
+    Skip,
+    Overwrite,
+}
+
+impl FromStr for OnConflict {
+    type Err = anyhow::Error;
+
+    fn from_str(on_conflict: &str) -> Result<Self, Self::Err> {
+        match on_conflict {
+            "fail" => Ok(Self::Fail),
+            "skip" => Ok(Self::Skip),
+            "overwrite" => Ok(Self::Overwrite),
+            _ => {
+                bail!("unknown conflict handling {on_conflict:?}, expected fail, skip or overwrite")
+            }
+        }
+    }
+}
+
+/// Query of `GET /admin/export`
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    /// Include the HTML reports, they can be generated again from the JSON reports
+    #[serde(default)]
+    pub html: bool,
+}
+
+/// Query of `POST /admin/import`
+#[derive(Debug, Deserialize)]
+pub struct ImportQuery {
+    #[serde(default)]
+    pub on_conflict: OnConflict,
+}
+
+/// The import reads the archive twice, to validate it and find the conflicts before writing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Pass {
+    Check,
+    Write(OnConflict),
+}
+
+impl Pass {
+    fn writes(self, exists: bool) -> bool {
+        match self {
+            Self::Check => false,
+            Self::Write(on_conflict) => !exists || on_conflict == OnConflict::Overwrite,
+        }
+    }
+}
+
+/// What an export wrote
+#[derive(Debug, Default, Serialize)]
+pub struct ExportReport {
+    pub records: usize,
+    pub artifacts: usize,
+}
+
+impl Display for ExportReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "exported {} records, {} artifacts",
+            self.records, self.artifacts
+        )
+    }
+}
+
+/// What an import wrote, the existing records and artifacts are skipped or overwritten
+#[derive(Debug, Default, Serialize)]
+pub struct ImportReport {
+    pub imported_records: usize,
+    pub skipped_records: usize,
+    pub overwritten_records: usize,
+    pub imported_artifacts: usize,
+    pub skipped_artifacts: usize,
+    pub overwritten_artifacts: usize,
+}
+
+impl ImportReport {
+    fn count(&mut self, artifact: bool, exists: bool, pass: Pass) {
+        let count = match (artifact, exists, pass.writes(exists)) {
+            (false, false, _) => &mut self.imported_records,
+            (false, true, false) => &mut self.skipped_records,
+            (false, true, true) => &mut self.overwritten_records,
+            (true, false, _) => &mut self.imported_artifacts,
+            (true, true, false) => &mut self.skipped_artifacts,
+            (true, true, true) => &mut self.overwritten_artifacts,
+        };
+        *count += 1;
+    }
+}
+
+impl Display for ImportReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "imported {} records, {} artifacts, skipped {} records, {} artifacts, overwrote {} records, {} artifacts",
+            self.imported_records,
+            self.imported_artifacts,
+            self.skipped_records,
+            self.skipped_artifacts,
+            self.overwritten_records,
+            self.overwritten_artifacts
+        )
+    }
+}
+
+fn export_records<T: Record>(storage: &TypedDb, output: &mut dyn Write) -> anyhow::Result<usize> {
+    let mut exported = 0;
+    for res in storage.entries::<T>(false)? {
+        let (key, value) = res?;
+        serde_json::to_writer(&mut *output, &BackupRecord { key, value })?;
+        output.write_all(b"\n")?;
+        exported += 1;
+    }
+    Ok(exported)
+}
+
+/// Decode a line of the records of `version`, write it if the pass does, and return whether the
+/// key already exists
+fn import_record<T: Record>(
+    storage: &TypedDb,
+    version: u32,
+    line: &str,
+    pass: Pass,
+) -> anyhow::Result<bool> {
+    let record: BackupRecord<serde_json::Value> = serde_json::from_str(line)?;
+    let value = serde_json::to_vec(&record.value)?;
+    let value = migrations::upgrade_record(T::COLLECTION, version, Encoding::Json, value)?;
+    let value: T = serde_json::from_slice(&value)
+        .map_err(|e| anyhow!("{} record {:?}: {e}", T::COLLECTION, record.key))?;
+    let exists = storage.backend().get(T::COLLECTION, &record.key)?.is_some();
+    if pass.writes(exists) {
+        storage.insert(&record.key, &value)?;
+    }
+    Ok(exists)
+}
+
+fn append(builder: &mut tar::Builder<impl Write>, path: &str, content: &[u8]) -> io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(content.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(Utc::now().timestamp().max(0) as u64);
+    header.set_cksum();
+    builder.append_data(&mut header, path, content)
+}
+
+/// Write a tar.gz archive of the records and of the JSON reports, and of the HTML reports with
+/// `html`
+pub fn export(
+    storage: &TypedDb,
+    artifacts: &Artifacts,
+    output: impl Write,
+    html: bool,
+) -> anyhow::Result<ExportReport> {
+    // The garbage collection doesn't remove the reports while they are exported
+    let _output = retention::output_guard();
+    let mut builder = tar::Builder::new(GzEncoder::new(output, Compression::default()));
+    let manifest = Manifest {
+        format_version: FORMAT_VERSION,
+        created: Utc::now().naive_utc(),
+        collections: COLLECTIONS
+            .iter()
+            .map(|collection| (collection.name.to_string(), collection.version))
+            .collect(),
+        next_id: storage.generate_id()?,
+        html,
+    };
+    append(
+        &mut builder,
+        MANIFEST,
+        &serde_json::to_vec_pretty(&manifest)?,
+    )?;
+
+    let mut report = ExportReport::default();
+    // The size of an entry comes before its content, the records are written to a file first
+    let work_dir = TempDir::new(&format!("export-{}", manifest.next_id))?;
+    for collection in COLLECTIONS {
+        let path = work_dir.path().join(collection.name);
+        let mut file = BufWriter::new(File::create(&path)?);
+        report.records += (collection.export)(storage, &mut file)?;
+        file.into_inner().map_err(io::IntoInnerError::into_error)?;
+        builder.append_path_with_name(&path, format!("records/{}.jsonl", collection.name))?;
+    }
+    for res in storage.entries::<ReportRepository>(false)? {
+        let (name, _) = res?;
+        // Uncompressed, the archive is gzipped
+        if let Some(content) = raw_reports::load(storage, artifacts.json.as_ref(), &name)? {
+            append(&mut builder, &format!("json-reports/{name}"), &content)?;
+            report.artifacts += 1;
+        }
+        if !html {
+            continue;
+        }
+        for (key, _) in artifacts.html.list(&name)? {
+            if let Some(content) = artifacts.html.get(&key)? {
+                append(&mut builder, &format!("html-reports/{key}"), &content)?;
+                report.artifacts += 1;
+            }
+        }
+    }
+    builder.into_inner()?.finish()?.flush()?;
+    Ok(report)
+}
+
+/// Store an artifact of the archive if the pass does, and return whether it already exists
+fn import_artifact(
+    store: &dyn ArtifactStore,
+    key: &str,
+    mut entry: impl Read,
+    pass: Pass,
+) -> anyhow::Result<bool> {
+    let exists = store.exists(key)?;
+    if pass.writes(exists) {
+        let mut content = vec![];
+        entry.read_to_end(&mut content)?;
+        store.put(key, &content)?;
+    }
+    Ok(exists)
+}
+
+/// Store the JSON report of the branch if the pass does, and return whether the branch already
+/// has one
+fn import_json_report(
+    storage: &TypedDb,
+    json: &dyn ArtifactStore,
+    name: &str,
+    mut entry: impl Read,
+    pass: Pass,
+) -> anyhow::Result<bool> {
+    if !is_valid_key(name) {
+        bail!("invalid JSON report name {name:?}");
+    }
+    let exists = storage.get::<RawReport>(name)?.is_some();
+    if pass.writes(exists) {
+        let mut content = vec![];
+        entry.read_to_end(&mut content)?;
+        raw_reports::store(storage, json, name, &content)?;
+    }
+    Ok(exists)
+}
+
+/// Read the archive, and return the existing records and artifacts
+fn read_archive(
+    storage: &TypedDb,
+    artifacts: &Artifacts,
+    archive: &Path,
+    pass: Pass,
+    report: &mut ImportReport,
+) -> anyhow::Result<Vec<String>> {
+    let file = BufReader::new(File::open(archive)?);
+    let mut archive = tar::Archive::new(GzDecoder::new(file));
+    let mut entries = archive.entries()?;
+    let manifest: Manifest = match entries.next() {
+        Some(entry) => {
+            let entry = entry?;
+            if entry.path()? != Path::new(MANIFEST) {
+                bail!("the first entry isn't {MANIFEST}");
+            }
+            serde_json::from_reader(entry)?
+        }
+        None => bail!("empty archive"),
+    };
+    if manifest.format_version != FORMAT_VERSION {
+        bail!(
+            "format version {}, instead of {FORMAT_VERSION}",
+            manifest.format_version
+        );
+    }
+
+    let mut existing = vec![];
+    for entry in entries {
+        let entry = entry?;
+        let path = entry.path()?;
+        let path = path
+            .to_str()
+            .ok_or_else(|| anyhow!("non UTF-8 entry {path:?}"))?
+            .to_string();
+        if let Some(file) = path.strip_prefix("records/") {
+            let name = file.strip_suffix(".jsonl").unwrap_or(file);
+            let collection = COLLECTIONS
+                .iter()
+                .find(|collection| collection.name == name)
+                .ok_or_else(|| anyhow!("unknown collection {name}"))?;
+            let version = *manifest
+                .collections
+                .get(name)
+                .ok_or_else(|| anyhow!("no version of the {name} records in {MANIFEST}"))?;
+            let pass = collection.pass(pass);
+            for (line_number, line) in BufReader::new(entry).lines().enumerate() {
+                let line = line?;
+                let exists = (collection.import)(storage, version, &line, pass)
+                    .map_err(|e| anyhow!("{path} line {}: {e:#}", line_number + 1))?;
+                if exists {
+                    existing.push(format!("{name} {}", line_key(&line)));
+                }
+                report.count(false, exists, pass);
+            }
+        } else {
+            let exists = if let Some(name) = path.strip_prefix("json-reports/") {
+                import_json_report(storage, artifacts.json.as_ref(), name, entry, pass)?
+            } else if let Some(key) = path.strip_prefix("html-reports/") {
+                import_artifact(artifacts.html.as_ref(), key, entry, pass)?
+            } else {
+                bail!("unexpected entry {path}");
+            };
+            if exists {
+                existing.push(path.clone());
+            }
+            report.count(true, exists, pass);
+        }
+    }
+    if let Pass::Write(_) = pass {
+        // The baselines of the archives written before the reports were compressed
+        raw_reports::migrate(storage, artifacts)?;
+        // The repositories of the archives written before they were recorded with the branches
+        migrations::backfill_report_repositories(storage)?;
+        // The names of the archives written before the names were unique
+        migrate_names(storage, artifacts, &config::get().output)?;
+        storage.backend().advance_ids(manifest.next_id)?;
+        storage.backend().flush()?;
+    }
+    Ok(existing)
+}
+
+fn line_key(line: &str) -> String {
+    serde_json::from_str::<BackupRecord<serde::de::IgnoredAny>>(line)
+        .map(|record| record.key)
+        .unwrap_or_default()
+}
+
+/// Restore an archive of [export], nothing is written if it is invalid, or with
+/// [OnConflict::Fail] if a record or an artifact already exists
+pub fn import(
+    storage: &TypedDb,
+    artifacts: &Artifacts,
+    archive: &Path,
+    on_conflict: OnConflict,
+) -> ApiResult<ImportReport> {
+    let _output = retention::output_guard();
+    let existing = read_archive(
+        storage,
+        artifacts,
+        archive,
+        Pass::Check,
+        &mut ImportReport::default(),
+    )
+    .map_err(|e| ApiError::InvalidBackup(format!("{e:#}")))?;
+    if on_conflict == OnConflict::Fail && !existing.is_empty() {
+        return Err(ApiError::BackupConflict(format!(
+            "{} records and artifacts already exist, like {}",
+            existing.len(),
+            existing[0]
+        )));
+    }
+    let mut report = ImportReport::default();
+    read_archive(
+        storage,
+        artifacts,
+        archive,
+        Pass::Write(on_conflict),
+        &mut report,
+    )?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{
+        artifacts::LocalStore,
+        audit::AuditQuery,
+        compare::Comparison,
+        config::OutputConfig,
+        report::{branch_name, report_repository},
+        test_utils::{load_snapshot, sqlite_storage},
+        users::Session,
+    };
+
+    /// A tar.gz archive with the manifest, and the records of the collections at their version
+    fn backup_archive(path: &Path, collections: &[(&str, u32, &str)]) {
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+            fs::File::create(path).unwrap(),
+            flate2::Compression::default(),
+        ));
+        let versions: std::collections::BTreeMap<_, _> = collections
+            .iter()
+            .map(|(name, version, _)| (name.to_string(), *version))
+            .collect();
+        let manifest = serde_json::json!({
+            "format_version": 1,
+            "created": "2024-06-01T00:00:00",
+            "collections": versions,
+            "next_id": 0,
+            "html": false,
+        })
+        .to_string();
+        let mut entries = vec![("manifest.json".to_string(), manifest)];
+        for (name, _, lines) in collections {
+            entries.push((format!("records/{name}.jsonl"), lines.to_string()));
+        }
+        for (path, content) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, content.as_bytes())
+                .unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+    }
+
+    #[test]
+    fn test_backup() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        let local_artifacts = |name: &str| Artifacts {
+            json: std::sync::Arc::new(LocalStore::new(dir.join(name).join("json"))),
+            html: std::sync::Arc::new(LocalStore::new(dir.join(name).join("html"))),
+        };
+        let source = load_snapshot("storage-v1");
+        migrations::run(&source).unwrap();
+        for _ in 0..100 {
+            source.generate_id().unwrap();
+        }
+        let source_artifacts = local_artifacts("source");
+        for name in ["greefine-app-main", "greefine-app-feature"] {
+            source_artifacts.json.put(name, b"{}").unwrap();
+            for page in ["index.html", "src/lib.rs.html"] {
+                let key = format!("{name}/20240502T100000123456789/{page}");
+                source_artifacts.html.put(&key, b"html").unwrap();
+            }
+        }
+        // Not a report of the database
+        source_artifacts.json.put("unknown-main", b"{}").unwrap();
+
+        // The baseline of the snapshot, written before the reports were compressed
+        let legacy = dir.join("legacy.tar.gz");
+        export(
+            &source,
+            &source_artifacts,
+            fs::File::create(&legacy).unwrap(),
+            false,
+        )
+        .unwrap();
+        let destination = sqlite_storage();
+        migrations::run(&destination).unwrap();
+        let destination_artifacts = local_artifacts("legacy");
+        import(
+            &destination,
+            &destination_artifacts,
+            &legacy,
+            OnConflict::Fail,
+        )
+        .unwrap();
+        // Under the names of the current version
+        let main = branch_name("GreeFine/app", "main");
+        let feature = branch_name("GreeFine/app", "feature");
+        assert!(raw_reports::load_report(
+            &destination,
+            destination_artifacts.json.as_ref(),
+            "GreeFine/app",
+            "main"
+        )
+        .unwrap()
+        .is_some());
+        assert!(destination.iter::<Baseline>().unwrap().next().is_none());
+        let history: Vec<ReportHistory> = destination.get_all().unwrap();
+        assert_eq!(history[0].name, feature);
+        assert_eq!(
+            report_repository(&destination, &main).unwrap().as_deref(),
+            Some("GreeFine/app")
+        );
+
+        // The two identical reports share a blob
+        assert_eq!(raw_reports::migrate(&source, &source_artifacts).unwrap(), 2);
+        assert_eq!(source_artifacts.json.list("sha256").unwrap().len(), 1);
+        assert!(!source_artifacts.json.exists("greefine-app-main").unwrap());
+        let output = OutputConfig {
+            repositories_dir: dir.join("repositories"),
+            sources_dir: dir.join("sources"),
+            ..OutputConfig::default()
+        };
+        assert_eq!(
+            migrate_names(&source, &source_artifacts, &output).unwrap(),
+            2
+        );
+
+        let archive = dir.join("backup.tar.gz");
+        let report = export(
+            &source,
+            &source_artifacts,
+            fs::File::create(&archive).unwrap(),
+            true,
+        )
+        .unwrap();
+        // Without the session of the snapshot
+        assert_eq!((report.records, report.artifacts), (8, 6));
+        let json_only = dir.join("json-only.tar.gz");
+        let report = export(
+            &source,
+            &source_artifacts,
+            fs::File::create(&json_only).unwrap(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(report.artifacts, 2);
+
+        // Into another backend, at the current versions with the indexes
+        let destination = sqlite_storage();
+        migrations::run(&destination).unwrap();
+        let destination_artifacts = local_artifacts("destination");
+        let report = import(
+            &destination,
+            &destination_artifacts,
+            &archive,
+            OnConflict::Fail,
+        )
+        .unwrap();
+        assert_eq!((report.imported_records, report.imported_artifacts), (8, 6));
+        let latest = ReportHistory::latest_per_branch(&destination, None).unwrap();
+        assert_eq!(latest.len(), 2);
+        assert_eq!(
+            destination
+                .get::<BaseBranch>("GreeFine/app")
+                .unwrap()
+                .unwrap()
+                .branch,
+            "main"
+        );
+        let load = |name| {
+            raw_reports::load(&destination, destination_artifacts.json.as_ref(), name).unwrap()
+        };
+        assert_eq!(load(&main).as_deref(), Some(&b"{}"[..]));
+        let user = User::authenticate(&destination, "alice", "correct horse").unwrap();
+        assert_eq!(user.unwrap().groups, vec!["dev".to_string()]);
+        assert_eq!(ApiKey::list(&destination).unwrap().len(), 1);
+        assert_eq!(
+            AuditEntry::page(&destination, &AuditQuery::default())
+                .unwrap()
+                .entries
+                .len(),
+            1
+        );
+        assert!(destination.iter::<Session>().unwrap().next().is_none());
+        assert!(destination.generate_id().unwrap() >= 100);
+        assert!(destination_artifacts
+            .html
+            .exists(&format!(
+                "{feature}/20240502T100000123456789/src/lib.rs.html"
+            ))
+            .unwrap());
+        assert!(!destination_artifacts.json.exists("unknown-main").unwrap());
+
+        // Nothing is written when a record or an artifact exists
+        raw_reports::store(
+            &destination,
+            destination_artifacts.json.as_ref(),
+            &main,
+            b"{\"new\": true}",
+        )
+        .unwrap();
+        destination.remove::<User>("alice").unwrap();
+        assert!(matches!(
+            import(
+                &destination,
+                &destination_artifacts,
+                &archive,
+                OnConflict::Fail
+            ),
+            Err(ApiError::BackupConflict(_))
+        ));
+        assert!(User::get(&destination, "alice").unwrap().is_none());
+        let report = import(
+            &destination,
+            &destination_artifacts,
+            &json_only,
+            OnConflict::Skip,
+        )
+        .unwrap();
+        assert_eq!((report.imported_records, report.skipped_records), (1, 7));
+        assert_eq!(report.skipped_artifacts, 2);
+        assert!(User::get(&destination, "alice").unwrap().is_some());
+        assert_eq!(load(&main).as_deref(), Some(&b"{\"new\": true}"[..]));
+        let report = import(
+            &destination,
+            &destination_artifacts,
+            &archive,
+            OnConflict::Overwrite,
+        )
+        .unwrap();
+        // Except the audit log
+        assert_eq!(
+            (
+                report.overwritten_records,
+                report.skipped_records,
+                report.overwritten_artifacts
+            ),
+            (7, 1, 6)
+        );
+        assert_eq!(load(&main).as_deref(), Some(&b"{}"[..]));
+        // The blob of the replaced report is removed
+        assert_eq!(destination_artifacts.json.list("sha256").unwrap().len(), 1);
+        assert_eq!(destination.get_all::<ReportHistory>().unwrap().len(), 2);
+
+        // An archive doesn't rewrite the existing audit entries
+        let (key, mut entry) = destination
+            .entries::<AuditEntry>(false)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        let actor = entry.actor.clone();
+        entry.actor = "forged".to_string();
+        let forged = serde_json::to_string(&BackupRecord { key, value: entry }).unwrap();
+        backup_archive(&archive, &[("audit_log", AuditEntry::VERSION, &forged)]);
+        let report = import(
+            &destination,
+            &destination_artifacts,
+            &archive,
+            OnConflict::Overwrite,
+        )
+        .unwrap();
+        assert_eq!((report.overwritten_records, report.skipped_records), (0, 1));
+        let entries = AuditEntry::page(&destination, &AuditQuery::default())
+            .unwrap()
+            .entries;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].actor, actor);
+
+        // The records of a previous version are upgraded
+        let comparison = serde_json::to_string(&Comparison::default()).unwrap();
+        let history_v1 = format!(
+            r#"{{"key":"2024-05-01 00:00:00","value":{{"repository_name":"GreeFine/app","branch":"main","name":"greefine-app-main","comparison":{comparison},"date":"2024-05-01T00:00:00"}}}}"#
+        );
+        let upgraded = sqlite_storage();
+        migrations::run(&upgraded).unwrap();
+        backup_archive(&archive, &[("report_history", 1, &history_v1)]);
+        import(
+            &upgraded,
+            &local_artifacts("upgraded"),
+            &archive,
+            OnConflict::Fail,
+        )
+        .unwrap();
+        let history = upgraded
+            .get::<ReportHistory>("2024-05-01 00:00:00")
+            .unwrap()
+            .unwrap();
+        assert_eq!((history.branch.as_str(), history.commit), ("main", None));
+        assert_eq!(
+            ReportHistory::latest_per_branch(&upgraded, None)
+                .unwrap()
+                .len(),
+            1
+        );
+
+        // Invalid archives aren't imported
+        let invalid_archives: [&[(&str, u32, &str)]; 4] = [
+            &[("report_history", 99, &history_v1)],
+            &[("sessions", 1, "")],
+            &[("report_history", 1, "{}")],
+            &[("users", 1, "{\"key\": \"bob\", \"value\": {}}")],
+        ];
+        for collections in invalid_archives {
+            backup_archive(&archive, collections);
+            assert!(matches!(
+                import(
+                    &upgraded,
+                    &local_artifacts("upgraded"),
+                    &archive,
+                    OnConflict::Overwrite
+                ),
+                Err(ApiError::InvalidBackup(_))
+            ));
+        }
+        fs::write(&archive, "not a tar.gz").unwrap();
+        assert!(matches!(
+            import(
+                &upgraded,
+                &local_artifacts("upgraded"),
+                &archive,
+                OnConflict::Fail
+            ),
+            Err(ApiError::InvalidBackup(_))
+        ));
+        assert_eq!(upgraded.get_all::<ReportHistory>().unwrap().len(), 1);
+        assert!(User::list(&upgraded).unwrap().is_empty());
+    }
+}
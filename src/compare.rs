@@ -2,13 +2,13 @@ use std::fmt::Display;
 
 use serde::{Deserialize, Serialize};
 
-use crate::{config, model::Report, storage::TypedDb};
+use crate::{artifacts::ArtifactStore, model::Report, raw_reports, storage::TypedDb};
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Comparison {
-    base: Option<f64>,
-    new: f64,
-    diff: Option<f64>,
+    pub base: Option<f64>,
+    pub new: f64,
+    pub diff: Option<f64>,
 }
 
 impl Display for Comparison {
@@ -21,7 +21,7 @@ impl Display for Comparison {
     }
 }
 
-fn float_precision_two(value: f64) -> f64 {
+pub fn float_precision_two(value: f64) -> f64 {
     (value * 100f64).round() / 100f64
 }
 
@@ -39,17 +39,19 @@ fn function_coverage(base: Option<Report>, new: &Report) -> Comparison {
     }
 }
 
-pub fn default_branch(
+/// Compare the report with the last report of the base branch of the repository.
+///
+/// Called before the report is stored, so a report of the base branch is compared with the
+/// previous one, and becomes the new base.
+pub fn base_branch(
     storage: &TypedDb,
+    json: &dyn ArtifactStore,
     report: &Report,
-    branch: &str,
+    repository_name: &str,
+    base_branch: &str,
 ) -> anyhow::Result<Comparison> {
-    let base_report = storage.get::<Report>(branch)?;
-    let result = function_coverage(base_report, report);
-    if branch == config::DEFAULT_REPORT_BRANCH {
-        storage.insert(branch, report)?;
-    }
-    Ok(result)
+    let base_report = raw_reports::load_report(storage, json, repository_name, base_branch)?;
+    Ok(function_coverage(base_report, report))
 }
 
 #[test]
@@ -1,16 +1,904 @@
-use std::sync::LazyLock;
+use std::{
+    env, fs, io,
+    net::IpAddr,
+    path::PathBuf,
+    str::FromStr,
+    sync::{LazyLock, OnceLock},
+};
 
+use anyhow::{bail, Context};
 use regex::Regex;
+use serde::Deserialize;
 
-pub const JSON_REPORTS_DIR: &str = "./output/json-reports/";
-pub const HTML_REPORTS_DIR: &str = "./output/html-reports/";
-pub const REPOSITORIES_DIR: &str = "./output/repositories/";
-pub const SLED_DIR: &str = "./output/persistance";
+use crate::filter::glob_set;
 
 pub static REPOSITORY_REGEX: LazyLock<Regex> = LazyLock::new(|| {
     Regex::new(r#"^(.*@.*:|https:\/\/.*\.*.\/)(?<name>[A-z-]{0,100}\/[A-z-]{0,100})(\.git)?$"#)
         .unwrap()
 });
 
-/// Name of the branch that is used as comparison to calculate the difference in coverage of other branches
-pub const DEFAULT_REPORT_BRANCH: &str = "main";
+/// Local repositories, `file://` urls or paths, the name is taken from the two last path components
+pub static LOCAL_REPOSITORY_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"^(file:\/\/)?\.{0,2}\/(.*\/)?(?<name>[\w.-]{1,100}\/[\w.-]{1,100}?)(\.git)?\/?$"#)
+        .unwrap()
+});
+
+/// Path of the server configuration file, can be changed with ENV key CONFIG_PATH
+pub const DEFAULT_CONFIG_PATH: &str = "./llvm-cov-host.toml";
+
+static CONFIG: OnceLock<ServerConfig> = OnceLock::new();
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Key with every permission, used to create the api keys stored in the database
+    pub api_key: Option<String>,
+    pub bind_address: String,
+    pub port: u16,
+    /// Maximum size of a json report upload once decompressed, in bytes
+    pub json_limit: usize,
+    /// Maximum size of a backup archive sent to `POST /admin/import`, in bytes
+    pub import_limit: usize,
+    /// Maximum size of a sources archive sent to `PUT /report/sources`, in bytes
+    pub sources_limit: usize,
+    /// Maximum size of the files of a sources archive once extracted, in bytes
+    pub sources_unpacked_limit: usize,
+    /// Name of the branch that is used as comparison to calculate the difference in coverage of other branches
+    pub default_report_branch: String,
+    pub output: OutputConfig,
+    pub s3: S3Config,
+    pub ssh: SshConfig,
+    pub git: GitConfig,
+    pub github_oidc: GithubOidcConfig,
+    pub viewer_auth: ViewerAuthConfig,
+    pub retention: RetentionConfig,
+    pub notifications: NotificationsConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputConfig {
+    pub json_reports_dir: PathBuf,
+    pub html_reports_dir: PathBuf,
+    pub repositories_dir: PathBuf,
+    /// Sources uploaded with a report, for repositories we can't clone
+    pub sources_dir: PathBuf,
+    /// Store of the JSON and HTML reports, `json_reports_dir` and `html_reports_dir` with `local`
+    pub artifact_backend: ArtifactBackend,
+    /// Database of the records, in `sled_dir` or `sqlite_path`
+    pub storage_backend: StorageBackend,
+    pub sled_dir: PathBuf,
+    pub sqlite_path: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageBackend {
+    #[default]
+    Sled,
+    /// A single file, its records can be queried in SQL and backed up with the sqlite3 tools
+    Sqlite,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArtifactBackend {
+    #[default]
+    Local,
+    /// The bucket of the `[s3]` configuration, shared by the replicas of the server
+    S3,
+}
+
+impl FromStr for ArtifactBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(backend: &str) -> Result<Self, Self::Err> {
+        match backend {
+            "local" => Ok(Self::Local),
+            "s3" => Ok(Self::S3),
+            _ => bail!("unknown artifact backend {backend}, expected local or s3"),
+        }
+    }
+}
+
+/// S3 compatible object storage of the reports, see [crate::s3::S3Store]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct S3Config {
+    /// Like `https://s3.eu-west-1.amazonaws.com`, or the url of a MinIO server
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    /// Prepended to the keys, like `llvm-cov-host/`
+    pub prefix: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// `{endpoint}/{bucket}/{key}` urls, instead of the `{bucket}.{endpoint host}` virtual host
+    pub path_style: bool,
+}
+
+impl Default for S3Config {
+    fn default() -> Self {
+        Self {
+            endpoint: String::new(),
+            bucket: String::new(),
+            region: "us-east-1".to_string(),
+            prefix: String::new(),
+            access_key_id: String::new(),
+            secret_access_key: String::new(),
+            path_style: true,
+        }
+    }
+}
+
+impl FromStr for StorageBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(backend: &str) -> Result<Self, Self::Err> {
+        match backend {
+            "sled" => Ok(Self::Sled),
+            "sqlite" => Ok(Self::Sqlite),
+            _ => bail!("unknown storage backend {backend}, expected sled or sqlite"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SshConfig {
+    /// Key used for the repositories without a deploy key, default to id_ed25519 in the home .ssh directory
+    pub key_path: PathBuf,
+    pub key_passphrase: Option<String>,
+    /// Registry of per-repository keys, see [crate::deploy_keys::DeployKeys]
+    pub deploy_keys_path: Option<PathBuf>,
+    /// Default to known_hosts in the home .ssh directory
+    pub known_hosts_path: PathBuf,
+    /// `host=SHA256:fingerprint`, a host can appear multiple times.
+    /// A host that isn't on the default port is written `[host]:port`, as in known_hosts
+    pub pinned_fingerprints: Vec<String>,
+    /// Add unknown hosts to the known_hosts file on their first connection
+    pub trust_on_first_use: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GitConfig {
+    /// See [crate::git::CloneOptions]
+    pub clone_depth: i32,
+    pub sparse_checkout: bool,
+    /// Directories of the local repositories and mirrors that uploads can use as git source
+    pub local_roots: Vec<PathBuf>,
+}
+
+/// Webhooks the repository configurations can notify, see [crate::notify]
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotificationsConfig {
+    /// Urls the webhooks must be under, like `https://chat.example.com/hooks/`, none if empty
+    pub allowed_webhooks: Vec<String>,
+}
+
+/// Garbage collection of the reports, HTML exports and clones, see [crate::retention]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    /// Uploads kept in the history of each branch, with their HTML report, all of them if 0
+    pub keep_reports_per_branch: usize,
+    /// Branches without an upload for this many days are removed, never if 0
+    pub branch_max_age_days: u32,
+    /// Clones and uploaded sources unused for this many days are removed, never if 0
+    pub clone_max_age_days: u32,
+    pub gc_interval_minutes: u64,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            keep_reports_per_branch: 0,
+            branch_max_age_days: 0,
+            clone_max_age_days: 0,
+            gc_interval_minutes: 60,
+        }
+    }
+}
+
+/// Verification of the GitHub Actions OIDC tokens, disabled without a JWKS
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GithubOidcConfig {
+    /// Url of the keys of the issuer, like `https://token.actions.githubusercontent.com/.well-known/jwks`
+    pub jwks_url: Option<String>,
+    /// Local JWKS file, takes precedence over the url
+    pub jwks_path: Option<PathBuf>,
+    pub issuer: String,
+    /// Audience requested by the workflow, tokens minted for other services are refused
+    pub audience: String,
+    /// Host of the repositories the tokens can upload reports for
+    pub github_host: String,
+    /// Glob patterns matched against the `owner/repository` of the tokens, any repository of
+    /// the host could mint a token for the audience otherwise. Required when enabled
+    pub allowed_repositories: Vec<String>,
+}
+
+impl Default for GithubOidcConfig {
+    fn default() -> Self {
+        Self {
+            jwks_url: None,
+            jwks_path: None,
+            issuer: "https://token.actions.githubusercontent.com".to_string(),
+            audience: "llvm-cov-host".to_string(),
+            github_host: "github.com".to_string(),
+            allowed_repositories: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ViewerAuthMode {
+    /// The dashboard and the reports are public
+    #[default]
+    None,
+    /// Users stored in the database, signed in with a session cookie
+    Local,
+    /// The user is authenticated by a reverse proxy, and set in a header
+    TrustedHeader,
+    /// Sign in with an OpenID Connect provider, the user is kept in a session cookie
+    Oidc,
+}
+
+impl FromStr for ViewerAuthMode {
+    type Err = anyhow::Error;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "none" => Ok(Self::None),
+            "local" => Ok(Self::Local),
+            "trusted_header" => Ok(Self::TrustedHeader),
+            "oidc" => Ok(Self::Oidc),
+            _ => bail!(
+                "unknown viewer auth mode {mode}, expected none, local, trusted_header or oidc"
+            ),
+        }
+    }
+}
+
+/// Read access to the repositories, for the users or the groups
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessRule {
+    pub users: Vec<String>,
+    pub groups: Vec<String>,
+    /// Glob patterns matched against the `owner/project` name of the repository
+    pub repositories: Vec<String>,
+}
+
+/// Users and groups of the viewer authentication allowed to browse the audit log
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminRule {
+    pub users: Vec<String>,
+    pub groups: Vec<String>,
+}
+
+/// Authentication of the dashboard, the reports api and the HTML reports
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ViewerAuthConfig {
+    pub mode: ViewerAuthMode,
+    pub session_ttl_hours: i64,
+    /// Only send the session cookie over https
+    pub secure_cookie: bool,
+    /// Header containing the username in `trusted_header` mode
+    pub user_header: String,
+    /// Header containing the comma separated groups of the user in `trusted_header` mode
+    pub groups_header: Option<String>,
+    /// Addresses of the reverse proxies allowed to set the headers, required in `trusted_header` mode
+    pub trusted_proxies: Vec<IpAddr>,
+    pub oidc: OidcLoginConfig,
+    pub access: Vec<AccessRule>,
+    pub admins: AdminRule,
+}
+
+/// OpenID Connect provider of the `oidc` viewer authentication, like Keycloak or Dex
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OidcLoginConfig {
+    /// The endpoints are discovered from `{issuer}/.well-known/openid-configuration` if not set
+    pub issuer: String,
+    pub client_id: String,
+    /// Not needed by public clients, PKCE is always used
+    pub client_secret: Option<String>,
+    /// Url of `/oidc/callback` on this server, as registered in the provider
+    pub redirect_url: String,
+    pub scopes: Vec<String>,
+    pub username_claim: String,
+    /// Claim with the groups matched by the access rules, a list or a single string
+    pub groups_claim: String,
+    pub authorization_endpoint: Option<String>,
+    pub token_endpoint: Option<String>,
+    /// Claims are also read from the userinfo endpoint, for providers without an id token like GitHub
+    pub userinfo_endpoint: Option<String>,
+    pub jwks_uri: Option<String>,
+}
+
+impl Default for OidcLoginConfig {
+    fn default() -> Self {
+        Self {
+            issuer: String::new(),
+            client_id: String::new(),
+            client_secret: None,
+            redirect_url: String::new(),
+            scopes: ["openid", "profile", "email", "groups"]
+                .map(String::from)
+                .to_vec(),
+            username_claim: "preferred_username".to_string(),
+            groups_claim: "groups".to_string(),
+            authorization_endpoint: None,
+            token_endpoint: None,
+            userinfo_endpoint: None,
+            jwks_uri: None,
+        }
+    }
+}
+
+impl Default for ViewerAuthConfig {
+    fn default() -> Self {
+        Self {
+            mode: ViewerAuthMode::None,
+            session_ttl_hours: 24,
+            secure_cookie: true,
+            user_header: "X-Forwarded-User".to_string(),
+            groups_header: None,
+            trusted_proxies: vec![],
+            oidc: OidcLoginConfig::default(),
+            access: vec![],
+            admins: AdminRule::default(),
+        }
+    }
+}
+
+impl GithubOidcConfig {
+    pub fn enabled(&self) -> bool {
+        self.jwks_url.is_some() || self.jwks_path.is_some()
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            api_key: None,
+            bind_address: "0.0.0.0".to_string(),
+            port: 8080,
+            json_limit: 1024 * 1024 * 1000,
+            import_limit: 1024 * 1024 * 1024 * 10,
+            sources_limit: 1024 * 1024 * 1024,
+            sources_unpacked_limit: 1024 * 1024 * 1024 * 5,
+            default_report_branch: "main".to_string(),
+            output: OutputConfig::default(),
+            s3: S3Config::default(),
+            ssh: SshConfig::default(),
+            git: GitConfig::default(),
+            github_oidc: GithubOidcConfig::default(),
+            viewer_auth: ViewerAuthConfig::default(),
+            retention: RetentionConfig::default(),
+            notifications: NotificationsConfig::default(),
+        }
+    }
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        Self {
+            json_reports_dir: "./output/json-reports/".into(),
+            html_reports_dir: "./output/html-reports/".into(),
+            repositories_dir: "./output/repositories/".into(),
+            sources_dir: "./output/sources/".into(),
+            artifact_backend: ArtifactBackend::Local,
+            storage_backend: StorageBackend::Sled,
+            sled_dir: "./output/persistance".into(),
+            sqlite_path: "./output/llvm-cov-host.sqlite".into(),
+        }
+    }
+}
+
+impl Default for SshConfig {
+    fn default() -> Self {
+        let home = env::var("HOME").unwrap_or_default();
+        Self {
+            key_path: format!("{home}/.ssh/id_ed25519").into(),
+            key_passphrase: None,
+            deploy_keys_path: None,
+            known_hosts_path: format!("{home}/.ssh/known_hosts").into(),
+            pinned_fingerprints: vec![],
+            trust_on_first_use: false,
+        }
+    }
+}
+
+fn parse_env<T: FromStr>(key: &str, value: String) -> anyhow::Result<T>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    value
+        .parse()
+        .with_context(|| format!("invalid value for ENV key {key}: {value}"))
+}
+
+fn parse_bool(key: &str, value: String) -> anyhow::Result<bool> {
+    match value.to_lowercase().as_str() {
+        "true" | "1" | "yes" => Ok(true),
+        "false" | "0" | "no" => Ok(false),
+        _ => bail!("invalid value for ENV key {key}: {value}, expected true or false"),
+    }
+}
+
+impl ServerConfig {
+    /// Parse the configuration file, then apply the overrides of the environment
+    pub fn from_sources(
+        content: Option<&str>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> anyhow::Result<Self> {
+        let mut config: Self = match content {
+            Some(content) => toml::from_str(content).context("parsing server configuration")?,
+            None => Self::default(),
+        };
+
+        macro_rules! override_with {
+            ($key:literal, $field:expr, $parse:expr) => {
+                if let Some(value) = env($key) {
+                    $field = $parse($key, value)?;
+                }
+            };
+        }
+        let string = |_: &str, value: String| anyhow::Ok(value);
+        let path = |_: &str, value: String| anyhow::Ok(PathBuf::from(value));
+
+        if let Some(api_key) = env("API_KEY") {
+            config.api_key = Some(api_key);
+        }
+        override_with!("BIND_ADDRESS", config.bind_address, string);
+        override_with!("PORT", config.port, parse_env);
+        override_with!("JSON_LIMIT", config.json_limit, parse_env);
+        override_with!("IMPORT_LIMIT", config.import_limit, parse_env);
+        override_with!("SOURCES_LIMIT", config.sources_limit, parse_env);
+        override_with!(
+            "SOURCES_UNPACKED_LIMIT",
+            config.sources_unpacked_limit,
+            parse_env
+        );
+        override_with!(
+            "DEFAULT_REPORT_BRANCH",
+            config.default_report_branch,
+            string
+        );
+        override_with!("JSON_REPORTS_DIR", config.output.json_reports_dir, path);
+        override_with!("HTML_REPORTS_DIR", config.output.html_reports_dir, path);
+        override_with!("REPOSITORIES_DIR", config.output.repositories_dir, path);
+        override_with!("SOURCES_DIR", config.output.sources_dir, path);
+        override_with!(
+            "ARTIFACT_BACKEND",
+            config.output.artifact_backend,
+            |_, value: String| { value.parse::<ArtifactBackend>() }
+        );
+        override_with!(
+            "STORAGE_BACKEND",
+            config.output.storage_backend,
+            |_, value: String| { value.parse::<StorageBackend>() }
+        );
+        override_with!("SLED_DIR", config.output.sled_dir, path);
+        override_with!("SQLITE_PATH", config.output.sqlite_path, path);
+        override_with!("S3_ENDPOINT", config.s3.endpoint, string);
+        override_with!("S3_BUCKET", config.s3.bucket, string);
+        override_with!("S3_REGION", config.s3.region, string);
+        override_with!("S3_PREFIX", config.s3.prefix, string);
+        override_with!("S3_ACCESS_KEY_ID", config.s3.access_key_id, string);
+        override_with!("S3_SECRET_ACCESS_KEY", config.s3.secret_access_key, string);
+        override_with!("S3_PATH_STYLE", config.s3.path_style, parse_bool);
+        override_with!("SSH_KEY_PATH", config.ssh.key_path, path);
+        if let Some(passphrase) = env("SSH_KEY_PASSPHRASE") {
+            config.ssh.key_passphrase = Some(passphrase);
+        }
+        if let Some(deploy_keys_path) = env("SSH_DEPLOY_KEYS_PATH") {
+            config.ssh.deploy_keys_path = Some(deploy_keys_path.into());
+        }
+        override_with!("SSH_KNOWN_HOSTS_PATH", config.ssh.known_hosts_path, path);
+        if let Some(pins) = env("SSH_PINNED_FINGERPRINTS") {
+            config.ssh.pinned_fingerprints = pins
+                .split(',')
+                .map(|pin| pin.trim().to_string())
+                .filter(|pin| !pin.is_empty())
+                .collect();
+        }
+        override_with!(
+            "SSH_TRUST_ON_FIRST_USE",
+            config.ssh.trust_on_first_use,
+            parse_bool
+        );
+        override_with!("GIT_CLONE_DEPTH", config.git.clone_depth, parse_env);
+        override_with!(
+            "GIT_SPARSE_CHECKOUT",
+            config.git.sparse_checkout,
+            parse_bool
+        );
+        if let Some(roots) = env("GIT_LOCAL_ROOTS") {
+            config.git.local_roots = roots
+                .split(',')
+                .map(str::trim)
+                .filter(|root| !root.is_empty())
+                .map(PathBuf::from)
+                .collect();
+        }
+        if let Some(jwks_url) = env("GITHUB_OIDC_JWKS_URL") {
+            config.github_oidc.jwks_url = Some(jwks_url);
+        }
+        if let Some(jwks_path) = env("GITHUB_OIDC_JWKS_PATH") {
+            config.github_oidc.jwks_path = Some(jwks_path.into());
+        }
+        override_with!("GITHUB_OIDC_ISSUER", config.github_oidc.issuer, string);
+        override_with!("GITHUB_OIDC_AUDIENCE", config.github_oidc.audience, string);
+        override_with!("GITHUB_OIDC_HOST", config.github_oidc.github_host, string);
+        if let Some(repositories) = env("GITHUB_OIDC_ALLOWED_REPOSITORIES") {
+            config.github_oidc.allowed_repositories = repositories
+                .split(',')
+                .map(|repository| repository.trim().to_string())
+                .filter(|repository| !repository.is_empty())
+                .collect();
+        }
+        override_with!(
+            "VIEWER_AUTH_MODE",
+            config.viewer_auth.mode,
+            |_, value: String| { value.parse::<ViewerAuthMode>() }
+        );
+        override_with!(
+            "VIEWER_SESSION_TTL_HOURS",
+            config.viewer_auth.session_ttl_hours,
+            parse_env
+        );
+        override_with!(
+            "VIEWER_SECURE_COOKIE",
+            config.viewer_auth.secure_cookie,
+            parse_bool
+        );
+        override_with!("VIEWER_USER_HEADER", config.viewer_auth.user_header, string);
+        if let Some(groups_header) = env("VIEWER_GROUPS_HEADER") {
+            config.viewer_auth.groups_header = Some(groups_header);
+        }
+        if let Some(proxies) = env("VIEWER_TRUSTED_PROXIES") {
+            config.viewer_auth.trusted_proxies = proxies
+                .split(',')
+                .map(str::trim)
+                .filter(|proxy| !proxy.is_empty())
+                .map(|proxy| parse_env("VIEWER_TRUSTED_PROXIES", proxy.to_string()))
+                .collect::<anyhow::Result<_>>()?;
+        }
+        override_with!("OIDC_ISSUER", config.viewer_auth.oidc.issuer, string);
+        override_with!("OIDC_CLIENT_ID", config.viewer_auth.oidc.client_id, string);
+        if let Some(client_secret) = env("OIDC_CLIENT_SECRET") {
+            config.viewer_auth.oidc.client_secret = Some(client_secret);
+        }
+        override_with!(
+            "OIDC_REDIRECT_URL",
+            config.viewer_auth.oidc.redirect_url,
+            string
+        );
+        override_with!(
+            "RETENTION_KEEP_REPORTS_PER_BRANCH",
+            config.retention.keep_reports_per_branch,
+            parse_env
+        );
+        override_with!(
+            "RETENTION_BRANCH_MAX_AGE_DAYS",
+            config.retention.branch_max_age_days,
+            parse_env
+        );
+        override_with!(
+            "RETENTION_CLONE_MAX_AGE_DAYS",
+            config.retention.clone_max_age_days,
+            parse_env
+        );
+        override_with!(
+            "RETENTION_GC_INTERVAL_MINUTES",
+            config.retention.gc_interval_minutes,
+            parse_env
+        );
+        if let Some(webhooks) = env("NOTIFICATIONS_ALLOWED_WEBHOOKS") {
+            config.notifications.allowed_webhooks = webhooks
+                .split(',')
+                .map(|webhook| webhook.trim().to_string())
+                .filter(|webhook| !webhook.is_empty())
+                .collect();
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Load from the file in ENV key CONFIG_PATH, or [DEFAULT_CONFIG_PATH] if it exists
+    pub fn load() -> anyhow::Result<Self> {
+        let path = env::var("CONFIG_PATH").ok();
+        let content = match fs::read_to_string(path.as_deref().unwrap_or(DEFAULT_CONFIG_PATH)) {
+            Ok(content) => Some(content),
+            // The default file is optional, but an explicit one must exist
+            Err(error) if error.kind() == io::ErrorKind::NotFound && path.is_none() => None,
+            Err(error) => return Err(error).context("reading server configuration"),
+        };
+        Self::from_sources(content.as_deref(), |key| env::var(key).ok())
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.api_key.as_ref().is_some_and(String::is_empty) {
+            bail!("api_key can't be empty");
+        }
+        if self.port == 0 {
+            bail!("port can't be 0");
+        }
+        if self.json_limit == 0 {
+            bail!("json_limit can't be 0");
+        }
+        if self.import_limit == 0 {
+            bail!("import_limit can't be 0");
+        }
+        if self.sources_limit == 0 || self.sources_unpacked_limit == 0 {
+            bail!("sources_limit and sources_unpacked_limit can't be 0");
+        }
+        if self.default_report_branch.is_empty() {
+            bail!("default_report_branch can't be empty");
+        }
+        let output = &self.output;
+        for (name, dir) in [
+            ("json_reports_dir", &output.json_reports_dir),
+            ("html_reports_dir", &output.html_reports_dir),
+            ("repositories_dir", &output.repositories_dir),
+            ("sources_dir", &output.sources_dir),
+            ("sled_dir", &output.sled_dir),
+            ("sqlite_path", &output.sqlite_path),
+        ] {
+            if dir.as_os_str().is_empty() {
+                bail!("output.{name} can't be empty");
+            }
+        }
+        let s3 = &self.s3;
+        if output.artifact_backend == ArtifactBackend::S3 {
+            if !s3.endpoint.starts_with("https://") && !s3.endpoint.starts_with("http://") {
+                bail!("s3.endpoint must be an http(s) url");
+            }
+            if s3.bucket.is_empty()
+                || s3.access_key_id.is_empty()
+                || s3.secret_access_key.is_empty()
+            {
+                bail!("s3.bucket, s3.access_key_id and s3.secret_access_key can't be empty");
+            }
+        }
+        let oidc = &self.github_oidc;
+        if oidc.enabled() && (oidc.issuer.is_empty() || oidc.audience.is_empty()) {
+            bail!("github_oidc.issuer and github_oidc.audience can't be empty");
+        }
+        if let Some(jwks_url) = &oidc.jwks_url {
+            if !jwks_url.starts_with("https://") && !jwks_url.starts_with("http://") {
+                bail!("github_oidc.jwks_url must be an http(s) url");
+            }
+        }
+        if oidc.enabled() && oidc.allowed_repositories.is_empty() {
+            bail!("github_oidc.allowed_repositories can't be empty, any repository of the host could upload");
+        }
+        glob_set(oidc.allowed_repositories.iter().map(String::as_str))
+            .map_err(|e| anyhow::anyhow!("github_oidc.allowed_repositories: {e}"))?;
+        let viewer_auth = &self.viewer_auth;
+        if viewer_auth.session_ttl_hours <= 0 {
+            bail!("viewer_auth.session_ttl_hours must be positive");
+        }
+        if viewer_auth.mode == ViewerAuthMode::TrustedHeader {
+            if viewer_auth.user_header.is_empty() {
+                bail!("viewer_auth.user_header can't be empty");
+            }
+            // Any client could set the headers otherwise
+            if viewer_auth.trusted_proxies.is_empty() {
+                bail!("viewer_auth.trusted_proxies can't be empty in trusted_header mode");
+            }
+        }
+        let oidc = &viewer_auth.oidc;
+        if viewer_auth.mode == ViewerAuthMode::Oidc
+            && (oidc.issuer.is_empty() || oidc.client_id.is_empty() || oidc.redirect_url.is_empty())
+        {
+            bail!("viewer_auth.oidc needs an issuer, a client_id and a redirect_url");
+        }
+        for rule in &viewer_auth.access {
+            glob_set(rule.repositories.iter().map(String::as_str))
+                .map_err(|e| anyhow::anyhow!("viewer_auth.access: {e}"))?;
+        }
+        if self.retention.gc_interval_minutes == 0 {
+            bail!("retention.gc_interval_minutes can't be 0");
+        }
+        for pin in &self.ssh.pinned_fingerprints {
+            if !pin.split_once('=').is_some_and(|(host, fingerprint)| {
+                !host.is_empty() && fingerprint.starts_with("SHA256:")
+            }) {
+                bail!("invalid pinned fingerprint {pin}, expected host=SHA256:fingerprint");
+            }
+        }
+        for webhook in &self.notifications.allowed_webhooks {
+            if !url::Url::parse(webhook).is_ok_and(|url| {
+                url.has_host() && (url.scheme() == "https" || url.scheme() == "http")
+            }) {
+                bail!("notifications.allowed_webhooks: {webhook} isn't an http(s) url");
+            }
+        }
+        Ok(())
+    }
+
+    /// Pinned fingerprints as `(host, fingerprint)`
+    pub fn pinned_fingerprints(&self) -> Vec<(String, String)> {
+        self.ssh
+            .pinned_fingerprints
+            .iter()
+            .filter_map(|pin| {
+                let (host, fingerprint) = pin.split_once('=')?;
+                Some((host.trim().to_string(), fingerprint.trim().to_string()))
+            })
+            .collect()
+    }
+}
+
+/// Load and validate the server configuration, must be called once at startup
+pub fn init() -> anyhow::Result<&'static ServerConfig> {
+    let config = ServerConfig::load()?;
+    Ok(CONFIG.get_or_init(|| config))
+}
+
+/// The server configuration, loaded on first use if [init] wasn't called
+pub fn get() -> &'static ServerConfig {
+    CONFIG.get_or_init(|| ServerConfig::load().expect("valid server configuration"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn test_server_config() {
+        let no_env = |_: &str| None;
+        let config = ServerConfig::from_sources(None, no_env).unwrap();
+        assert_eq!(config.port, 8080);
+        assert_eq!(config.default_report_branch, "main");
+
+        let content = r#"
+        port = 9090
+        default_report_branch = "master"
+
+        [output]
+        sled_dir = "/data/persistance"
+
+        [ssh]
+        pinned_fingerprints = ["github.com=SHA256:abc"]
+
+        [git]
+        clone_depth = 1
+    "#;
+        let config = ServerConfig::from_sources(Some(content), no_env).unwrap();
+        assert_eq!(config.port, 9090);
+        assert_eq!(config.default_report_branch, "master");
+        assert_eq!(config.output.sled_dir, Path::new("/data/persistance"));
+        assert_eq!(
+            config.output.html_reports_dir,
+            Path::new("./output/html-reports/")
+        );
+        assert_eq!(
+            config.pinned_fingerprints(),
+            vec![("github.com".to_string(), "SHA256:abc".to_string())]
+        );
+        assert_eq!(config.git.clone_depth, 1);
+
+        // The environment takes precedence over the file
+        let env = |key: &str| match key {
+            "PORT" => Some("3000".to_string()),
+            "IMPORT_LIMIT" => Some("1024".to_string()),
+            "SOURCES_UNPACKED_LIMIT" => Some("4096".to_string()),
+            "HTML_REPORTS_DIR" => Some("/data/html".to_string()),
+            "GIT_SPARSE_CHECKOUT" => Some("true".to_string()),
+            "GIT_LOCAL_ROOTS" => Some("/srv/mirrors, /data/git".to_string()),
+            "NOTIFICATIONS_ALLOWED_WEBHOOKS" => Some("https://chat.example.com/hooks/".to_string()),
+            "STORAGE_BACKEND" => Some("sqlite".to_string()),
+            "ARTIFACT_BACKEND" => Some("s3".to_string()),
+            "S3_ENDPOINT" => Some("http://127.0.0.1:9000".to_string()),
+            "S3_BUCKET" => Some("reports".to_string()),
+            "S3_ACCESS_KEY_ID" => Some("minio".to_string()),
+            "S3_SECRET_ACCESS_KEY" => Some("minio123".to_string()),
+            "S3_PATH_STYLE" => Some("false".to_string()),
+            _ => None,
+        };
+        let config = ServerConfig::from_sources(Some(content), env).unwrap();
+        assert_eq!(config.port, 3000);
+        assert_eq!(config.import_limit, 1024);
+        assert_eq!(config.sources_unpacked_limit, 4096);
+        assert_eq!(config.output.storage_backend, StorageBackend::Sqlite);
+        assert_eq!(config.output.artifact_backend, ArtifactBackend::S3);
+        assert_eq!(config.s3.bucket, "reports");
+        assert_eq!(config.s3.region, "us-east-1");
+        assert!(!config.s3.path_style);
+        assert_eq!(config.output.html_reports_dir, Path::new("/data/html"));
+        assert!(config.git.sparse_checkout);
+        assert_eq!(config.git.clone_depth, 1);
+        assert_eq!(
+            config.git.local_roots,
+            vec![PathBuf::from("/srv/mirrors"), PathBuf::from("/data/git")]
+        );
+        assert_eq!(
+            config.notifications.allowed_webhooks,
+            ["https://chat.example.com/hooks/"]
+        );
+
+        assert!(ServerConfig::from_sources(Some("prot = 80"), no_env).is_err());
+        assert!(ServerConfig::from_sources(
+            Some("[notifications]\nallowed_webhooks = [\"chat.example.com\"]"),
+            no_env
+        )
+        .is_err());
+        assert!(ServerConfig::from_sources(Some("port = 0"), no_env).is_err());
+        assert!(ServerConfig::from_sources(None, |key: &str| {
+            (key == "STORAGE_BACKEND").then(|| "postgres".to_string())
+        })
+        .is_err());
+        // The bucket and the credentials are required with the S3 backend
+        assert!(ServerConfig::from_sources(None, |key: &str| {
+            (key == "ARTIFACT_BACKEND").then(|| "s3".to_string())
+        })
+        .is_err());
+        assert!(ServerConfig::from_sources(
+            Some("[output]\nartifact_backend = \"s3\"\n[s3]\nendpoint = \"ftp://minio\"\nbucket = \"reports\"\naccess_key_id = \"a\"\nsecret_access_key = \"b\""),
+            no_env
+        )
+        .is_err());
+        assert!(ServerConfig::from_sources(None, |key: &str| {
+            (key == "PORT").then(|| "http".to_string())
+        })
+        .is_err());
+        assert!(ServerConfig::from_sources(
+            Some("[ssh]\npinned_fingerprints = [\"github.com\"]"),
+            no_env
+        )
+        .is_err());
+        // Any repository of GitHub could upload without the allowed repositories
+        let github_oidc = |repositories: Option<&str>| {
+            let repositories = repositories.map(str::to_string);
+            ServerConfig::from_sources(
+                Some("[github_oidc]\njwks_path = \"jwks.json\""),
+                move |key| {
+                    (key == "GITHUB_OIDC_ALLOWED_REPOSITORIES")
+                        .then(|| repositories.clone())
+                        .flatten()
+                },
+            )
+        };
+        assert!(github_oidc(None).is_err());
+        assert_eq!(
+            github_oidc(Some("GreeFine/*, org/app"))
+                .unwrap()
+                .github_oidc
+                .allowed_repositories,
+            ["GreeFine/*", "org/app"]
+        );
+
+        // Any client could set the user header without the addresses of the proxies
+        let trusted_header = |proxies: Option<&str>| {
+            let proxies = proxies.map(str::to_string);
+            ServerConfig::from_sources(None, move |key: &str| match key {
+                "VIEWER_AUTH_MODE" => Some("trusted_header".to_string()),
+                "VIEWER_TRUSTED_PROXIES" => proxies.clone(),
+                _ => None,
+            })
+        };
+        assert!(trusted_header(None).is_err());
+        assert!(trusted_header(Some("proxy.internal")).is_err());
+        let config = trusted_header(Some("10.0.0.1, ::1")).unwrap();
+        assert_eq!(
+            config.viewer_auth.trusted_proxies,
+            [
+                "10.0.0.1".parse::<std::net::IpAddr>().unwrap(),
+                "::1".parse().unwrap()
+            ]
+        );
+    }
+}
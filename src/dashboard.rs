@@ -1,10 +1,34 @@
 use askama::Template;
 
-use crate::report::ReportHistory;
+use crate::{audit::AuditEntry, report::ReportHistory};
 
 #[derive(Template)]
 #[template(path = "dashboard.jinja")]
-
 pub struct DashBoardTemplate {
     pub reports: Vec<ReportHistory>,
+    /// Signed in user, with the viewer authentication
+    pub username: Option<String>,
+    /// Link to the audit log
+    pub is_admin: bool,
+    pub previous_page: Option<usize>,
+    pub next_page: Option<usize>,
+    /// Query string of the filters, kept in the links to the other pages
+    pub filters: String,
+}
+
+#[derive(Template)]
+#[template(path = "login.jinja")]
+pub struct LoginTemplate {
+    pub error: Option<String>,
+}
+
+#[derive(Template)]
+#[template(path = "audit.jinja")]
+pub struct AuditTemplate {
+    pub entries: Vec<AuditEntry>,
+    pub username: Option<String>,
+    pub previous_page: Option<usize>,
+    pub next_page: Option<usize>,
+    /// Query string of the filters, kept in the links to the other pages
+    pub filters: String,
 }
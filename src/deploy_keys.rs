@@ -0,0 +1,141 @@
+use std::{fs, path::PathBuf};
+
+use anyhow::Context;
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::config;
+
+/// An ssh private key, and the passphrase to unlock it
+#[derive(Debug, Clone, PartialEq)]
+pub struct SshKey {
+    pub path: PathBuf,
+    pub passphrase: Option<String>,
+}
+
+impl SshKey {
+    /// The global key from the `[ssh]` section of the server configuration
+    pub fn global() -> Self {
+        let ssh = &config::get().ssh;
+        Self {
+            path: ssh.key_path.clone(),
+            passphrase: ssh.key_passphrase.clone(),
+        }
+    }
+
+    /// The public key next to the private one, if it exists
+    pub fn public_key_path(&self) -> Option<PathBuf> {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".pub");
+        let path = PathBuf::from(path);
+        path.exists().then_some(path)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct DeployKeyEntry {
+    /// Regex matched against [crate::report::Request::git]
+    repository: String,
+    path: PathBuf,
+    passphrase: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct DeployKeysFile {
+    #[serde(default)]
+    keys: Vec<DeployKeyEntry>,
+}
+
+/// Registry of deploy keys, a repository url pattern maps to the key used to fetch it.
+///
+/// Loaded from the toml file in `ssh.deploy_keys_path` of the server configuration:
+/// ```toml
+/// [[keys]]
+/// repository = "^git@github.com:GreeFine/private-project(\\.git)?$"
+/// path = "/keys/private-project"
+/// passphrase = "optional"
+/// ```
+#[derive(Debug, Default)]
+pub struct DeployKeys(Vec<(Regex, SshKey)>);
+
+impl DeployKeys {
+    pub fn parse(content: &str) -> anyhow::Result<Self> {
+        let file: DeployKeysFile = toml::from_str(content)?;
+        let keys = file
+            .keys
+            .into_iter()
+            .map(|entry| {
+                let pattern = Regex::new(&entry.repository)
+                    .with_context(|| format!("invalid repository pattern {}", entry.repository))?;
+                let key = SshKey {
+                    path: entry.path,
+                    passphrase: entry.passphrase,
+                };
+                Ok((pattern, key))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self(keys))
+    }
+
+    pub fn from_config() -> anyhow::Result<Self> {
+        let Some(path) = &config::get().ssh.deploy_keys_path else {
+            return Ok(Self::default());
+        };
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("reading deploy keys {}", path.display()))?;
+        Self::parse(&content).with_context(|| format!("parsing deploy keys {}", path.display()))
+    }
+
+    /// The first key whose pattern match the repository url, or the global key
+    pub fn key_for(&self, repository_url: &str) -> SshKey {
+        self.0
+            .iter()
+            .find(|(pattern, _)| pattern.is_match(repository_url))
+            .map(|(_, key)| key.clone())
+            .unwrap_or_else(SshKey::global)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn test_deploy_keys() {
+        let deploy_keys = DeployKeys::parse(
+            r#"
+        [[keys]]
+        repository = "^git@github.com:GreeFine/private-a(\\.git)?$"
+        path = "/keys/private-a"
+        passphrase = "secret"
+
+        [[keys]]
+        repository = "github.com[:/]GreeFine/private-b"
+        path = "/keys/private-b"
+        "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            deploy_keys.key_for("git@github.com:GreeFine/private-a.git"),
+            SshKey {
+                path: "/keys/private-a".into(),
+                passphrase: Some("secret".to_string())
+            }
+        );
+        assert_eq!(
+            deploy_keys
+                .key_for("https://github.com/GreeFine/private-b.git")
+                .path,
+            Path::new("/keys/private-b")
+        );
+        assert_eq!(
+            deploy_keys.key_for("git@github.com:GreeFine/private-c.git"),
+            SshKey::global()
+        );
+
+        assert!(DeployKeys::parse("[[keys]]\nrepository = \"(\"\npath = \"/key\"").is_err());
+    }
+}
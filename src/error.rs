@@ -1,8 +1,17 @@
 use std::io;
 
-use actix_web::{http::StatusCode, ResponseError};
+use actix_multipart::MultipartError;
+use actix_web::{
+    http::{
+        header::{self, ContentType},
+        StatusCode,
+    },
+    HttpResponse, ResponseError,
+};
 use log::error;
 
+use crate::known_hosts::HostKeyError;
+
 pub type ApiResult<T> = Result<T, ApiError>;
 
 #[derive(Debug, thiserror::Error)]
@@ -21,6 +30,61 @@ pub enum ApiError {
     NoProjectFile,
     #[error("didn't succeed in finding report filepath with our local repository")]
     FailedReportFilePathReplace,
+    #[error("git host key verification failed: {0}")]
+    HostKeyVerification(#[from] HostKeyError),
+    #[error("multipart error: {0}")]
+    Multipart(String),
+    #[error("missing multipart field: {0}")]
+    MissingMultipartField(&'static str),
+    #[error("the sources archive isn't a valid tar or tar.gz")]
+    InvalidSourcesArchive,
+    #[error("the sources archive can only contain files and directories inside it: {0}")]
+    UnsafeSourcesEntry(String),
+    #[error("invalid glob pattern: {0}")]
+    InvalidGlob(String),
+    #[error("invalid .llvm-cov-host.toml: {0}")]
+    RepositoryConfig(String),
+    #[error("missing or invalid api key")]
+    Unauthorized,
+    #[error("forbidden: {0}")]
+    Forbidden(String),
+    #[error("invalid OIDC token: {0}")]
+    InvalidToken(String),
+    #[error("api key {0} not found")]
+    ApiKeyNotFound(String),
+    #[error("sign in required")]
+    LoginRequired,
+    #[error("report not found")]
+    ReportNotFound,
+    #[error("invalid user: {0}")]
+    InvalidUser(String),
+    #[error("invalid deletion: {0}")]
+    InvalidDeletion(String),
+    #[error("user {0} already exists")]
+    UserAlreadyExists(String),
+    #[error("user {0} not found")]
+    UserNotFound(String),
+    #[error("OIDC sign in failed: {0}")]
+    OidcLogin(String),
+    #[error("invalid backup archive: {0}")]
+    InvalidBackup(String),
+    #[error("the backup conflicts with the existing data: {0}")]
+    BackupConflict(String),
+    #[error("unsupported Content-Encoding {0}, use gzip or zstd")]
+    UnsupportedEncoding(String),
+    #[error("invalid upload: {0}")]
+    InvalidUpload(String),
+    #[error("the upload exceeds {0} bytes")]
+    UploadTooLarge(usize),
+    #[error("the name of the report is already used: {0}")]
+    NameConflict(String),
+}
+
+// MultipartError isn't Send, so we only keep its message
+impl From<MultipartError> for ApiError {
+    fn from(error: MultipartError) -> Self {
+        Self::Multipart(error.to_string())
+    }
 }
 
 impl ResponseError for ApiError {
@@ -31,13 +95,49 @@ impl ResponseError for ApiError {
             error!("{self:#?}");
         }
         match self {
-            Self::SerdeError(_) | Self::NoReportData | Self::NoProjectFile => {
-                StatusCode::BAD_REQUEST
-            }
+            Self::SerdeError(_)
+            | Self::NoReportData
+            | Self::NoProjectFile
+            | Self::Multipart(_)
+            | Self::MissingMultipartField(_)
+            | Self::InvalidSourcesArchive
+            | Self::UnsafeSourcesEntry(_)
+            | Self::InvalidGlob(_)
+            | Self::RepositoryConfig(_)
+            | Self::InvalidUser(_)
+            | Self::InvalidDeletion(_)
+            | Self::InvalidBackup(_)
+            | Self::InvalidUpload(_) => StatusCode::BAD_REQUEST,
             Self::IoError(_) | Self::LlvmCovPretty | Self::FailedReportFilePathReplace => {
                 StatusCode::INTERNAL_SERVER_ERROR
             }
             Self::AnyHow(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::HostKeyVerification(_) => StatusCode::BAD_GATEWAY,
+            Self::Unauthorized | Self::InvalidToken(_) | Self::OidcLogin(_) => {
+                StatusCode::UNAUTHORIZED
+            }
+            Self::Forbidden(_) => StatusCode::FORBIDDEN,
+            Self::ApiKeyNotFound(_) | Self::ReportNotFound | Self::UserNotFound(_) => {
+                StatusCode::NOT_FOUND
+            }
+            Self::UserAlreadyExists(_) | Self::BackupConflict(_) | Self::NameConflict(_) => {
+                StatusCode::CONFLICT
+            }
+            Self::LoginRequired => StatusCode::SEE_OTHER,
+            Self::UploadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::UnsupportedEncoding(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let status_code = self.status_code();
+        if let Self::LoginRequired = self {
+            return HttpResponse::build(status_code)
+                .insert_header((header::LOCATION, "/login"))
+                .finish();
         }
+        HttpResponse::build(status_code)
+            .insert_header(ContentType::plaintext())
+            .body(self.to_string())
     }
 }
@@ -0,0 +1,155 @@
+use std::path::Path;
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{ApiError, ApiResult},
+    model::{Report, Summary},
+};
+
+/// Dependencies are never part of the project coverage
+const DEFAULT_EXCLUDE: &[&str] = &["**/.cargo/registry/**", "**/.cargo/git/**"];
+
+/// Glob patterns selecting the files counted in the coverage.
+///
+/// Patterns are matched against the path relative to the repository root, like `src/**/*.rs`.
+/// Files that weren't resolved in the repository are matched with their path in the report.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct FileFilter {
+    /// Only keep the files matching one of the patterns, every file is kept when empty
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Remove the files matching one of the patterns, like `target/**` or `**/tests/**`
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+pub fn glob_set<'a>(patterns: impl IntoIterator<Item = &'a str>) -> ApiResult<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = Glob::new(pattern).map_err(|e| ApiError::InvalidGlob(e.to_string()))?;
+        builder.add(glob);
+    }
+    builder
+        .build()
+        .map_err(|e| ApiError::InvalidGlob(e.to_string()))
+}
+
+#[derive(Debug)]
+pub struct CompiledFilter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+impl FileFilter {
+    pub fn compile(&self) -> ApiResult<CompiledFilter> {
+        let include = if self.include.is_empty() {
+            None
+        } else {
+            Some(glob_set(self.include.iter().map(String::as_str))?)
+        };
+        let exclude = glob_set(
+            self.exclude
+                .iter()
+                .map(String::as_str)
+                .chain(DEFAULT_EXCLUDE.iter().copied()),
+        )?;
+        Ok(CompiledFilter { include, exclude })
+    }
+}
+
+impl CompiledFilter {
+    pub fn is_included(&self, path: &str) -> bool {
+        self.include.as_ref().is_none_or(|i| i.is_match(path)) && !self.exclude.is_match(path)
+    }
+}
+
+/// Remove the files of the report excluded by the filter, and recompute the totals from the remaining files.
+///
+/// Functions are removed when none of their files is kept.
+pub fn filter_report(
+    report: &mut Report,
+    local_repository: &Path,
+    filter: &CompiledFilter,
+) -> ApiResult<()> {
+    let local_repository = format!("{}/", local_repository.to_string_lossy());
+    let is_included = |filename: &str| {
+        filter.is_included(filename.strip_prefix(&local_repository).unwrap_or(filename))
+    };
+
+    if report.data.is_empty() {
+        return Err(ApiError::NoReportData);
+    }
+    for data in &mut report.data {
+        data.files.retain(|file| is_included(&file.filename));
+        data.functions.retain(|function| match &function.filenames {
+            Some(filenames) => filenames.iter().any(|filename| is_included(filename)),
+            None => true,
+        });
+
+        let total = Summary::total(data.files.iter().map(|file| &file.summary));
+        // The MC/DC section isn't recomputed, it is kept as exported
+        data.totals = Summary {
+            mcdc: data.totals.mcdc.take(),
+            ..total
+        };
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        repository_config::RepositoryConfig,
+        test_utils::{file_summary, llvm_cov_export},
+    };
+
+    #[test]
+    fn test_filter_report() {
+        let mut report = llvm_cov_export(serde_json::json!([{
+            "files": [
+                { "filename": "/repo/src/main.rs", "summary": file_summary(3, 4) },
+                { "filename": "/repo/src/tests.rs", "summary": file_summary(10, 10) },
+                { "filename": "/repo/target/debug/build/out/proto.rs", "summary": file_summary(0, 6) },
+                { "filename": "/home/ci/.cargo/registry/src/serde/lib.rs", "summary": file_summary(1, 10) },
+            ],
+            "functions": [
+                { "name": "main", "filenames": ["/repo/src/main.rs"] },
+                { "name": "test", "filenames": ["/repo/src/tests.rs"] },
+            ],
+            "totals": file_summary(14, 30),
+        }]));
+
+        let config = RepositoryConfig::parse(
+            r#"
+        [filter]
+        exclude = ["target/**", "**/tests.rs"]
+        "#,
+        )
+        .unwrap();
+        filter_report(
+            &mut report,
+            Path::new("/repo"),
+            &config.filter.compile().unwrap(),
+        )
+        .unwrap();
+
+        let json_report = serde_json::to_value(&report).unwrap();
+        let data = &json_report["data"][0];
+        assert_eq!(data["files"].as_array().unwrap().len(), 1);
+        assert_eq!(data["functions"].as_array().unwrap().len(), 1);
+        assert_eq!(data["totals"]["lines"]["count"], 4);
+        assert_eq!(data["totals"]["functions"]["covered"], 3);
+        assert_eq!(data["totals"]["regions"]["percent"], 75.0);
+        assert_eq!(data["totals"]["mcdc"]["count"], 0);
+
+        let filter = FileFilter {
+            include: vec!["[".to_string()],
+            exclude: vec![],
+        };
+        assert!(filter.compile().is_err());
+        assert!(RepositoryConfig::parse("[filter]\ninclude = 1").is_err());
+    }
+}
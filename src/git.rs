@@ -1,32 +1,44 @@
-use std::{env, fs, path::PathBuf, str::FromStr};
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
 
 use anyhow::Context;
 use git2::{
-    CertificateCheckStatus, Cred, CredentialType, FetchOptions, RemoteCallbacks, Repository,
+    build::CheckoutBuilder, Cred, CredentialType, FetchOptions, ObjectType, RemoteCallbacks,
+    Repository, SubmoduleUpdateOptions, Tree, TreeWalkMode, TreeWalkResult,
 };
 
-use crate::{config, report::Request};
-
-/// Get path from ENV key SSH_KEY_PATH or default to id_ed25519 in the home .ssh directory
-pub fn get_ssh_key_path() -> PathBuf {
-    PathBuf::from_str(
-        &env::var("SSH_KEY_PATH")
-            .unwrap_or_else(|_| format!("{}/.ssh/id_ed25519", env::var("HOME").unwrap())),
-    )
-    .unwrap()
-}
+use crate::{
+    config,
+    deploy_keys::{DeployKeys, SshKey},
+    error::{ApiError, ApiResult},
+    known_hosts::{ssh_port, HostKeyVerification},
+    report::{clone_name, Request},
+    repository_config::REPOSITORY_CONFIG_FILE,
+};
 
-fn create_fetch_options<'a>() -> FetchOptions<'a> {
+fn create_fetch_options<'a>(
+    url: &str,
+    ssh_key: SshKey,
+    host_key_verification: &'a HostKeyVerification,
+) -> FetchOptions<'a> {
     let mut fo = git2::FetchOptions::new();
-    let priv_key_path = get_ssh_key_path();
-    if !priv_key_path.exists() {
+    let mut callbacks = RemoteCallbacks::new();
+    let port = ssh_port(url);
+    callbacks.certificate_check(move |cert, host| {
+        host_key_verification.certificate_check(cert, host, port)
+    });
+
+    if !ssh_key.path.exists() {
+        fo.remote_callbacks(callbacks);
         return fo;
     };
 
-    let pub_key_path = priv_key_path.with_extension("pub");
+    let pub_key_path = ssh_key.public_key_path();
 
     let mut credential_tries = 0;
-    let mut callbacks = RemoteCallbacks::new();
     callbacks.credentials(move |_url, username_from_url, allowed_types| {
         if !allowed_types.contains(CredentialType::SSH_KEY) {
             return Err(git2::Error::from_str(
@@ -42,50 +54,698 @@ fn create_fetch_options<'a>() -> FetchOptions<'a> {
 
         Cred::ssh_key(
             username_from_url.unwrap(),
-            Some(&pub_key_path),
-            &priv_key_path,
-            env::var("SSH_KEY_PASSPHRASE").as_deref().ok(),
+            pub_key_path.as_deref(),
+            &ssh_key.path,
+            ssh_key.passphrase.as_deref(),
         )
     });
-    // FIXME: Proper certificate should be done.
-    callbacks.certificate_check(|_, _| Ok(CertificateCheckStatus::CertificateOk));
 
     fo.remote_callbacks(callbacks);
     fo
 }
 
+/// How much of the repository is fetched and checked out
+#[derive(Debug, Clone, Default)]
+pub struct CloneOptions {
+    /// Number of commits to fetch from the branch tip, 0 fetches the whole history.
+    ///
+    /// libgit2 doesn't support partial clones (`--filter=blob:none`), but with a depth of 1
+    /// only the blobs of the tip commit are downloaded.
+    pub depth: i32,
+    /// Only checkout the files listed in the report, and the cargo manifests
+    pub sparse_checkout: bool,
+    /// Directories of the local repositories that can be cloned, none when empty
+    pub local_roots: Vec<PathBuf>,
+    /// Where the repositories are cloned, [config::OutputConfig::repositories_dir]
+    pub repositories_dir: PathBuf,
+}
+
+impl CloneOptions {
+    /// From the `[git]` section of the server configuration, default to a full clone
+    pub fn from_config() -> Self {
+        let git = &config::get().git;
+        Self {
+            depth: git.clone_depth,
+            sparse_checkout: git.sparse_checkout,
+            local_roots: git.local_roots.clone(),
+            repositories_dir: config::get().output.repositories_dir.clone(),
+        }
+    }
+}
+
+/// Path of a local source, a `file://` url or a path, None for the remote urls
+fn local_source(git: &str) -> Option<&str> {
+    if let Some(path) = git.strip_prefix("file://") {
+        return Some(path);
+    }
+    // `scheme://` urls, and the scp-like `user@host:path` with a colon before the first slash
+    let before_slash = git.split('/').next().unwrap_or_default();
+    (!git.contains("://") && !before_slash.contains(':')).then_some(git)
+}
+
+/// Refuse the local sources outside of [CloneOptions::local_roots], so an upload can't make the
+/// server clone any repository it can read
+fn check_local_source(git: &str, local_roots: &[PathBuf]) -> ApiResult<()> {
+    let Some(path) = local_source(git) else {
+        return Ok(());
+    };
+    let allowed = fs::canonicalize(path).is_ok_and(|path| {
+        local_roots
+            .iter()
+            .filter_map(|root| fs::canonicalize(root).ok())
+            .any(|root| path.starts_with(root))
+    });
+    if allowed {
+        Ok(())
+    } else {
+        Err(ApiError::Forbidden(format!(
+            "{git} isn't in the local repository roots of the server"
+        )))
+    }
+}
+
 /// Clone the repository, or pull if it already exist, [create_fetch_options] is used to provide authentication.
-pub fn pull_or_clone(request: &Request) -> anyhow::Result<PathBuf> {
-    let repository_path =
-        PathBuf::from_str(config::REPOSITORIES_DIR)?.join(request.repository_name());
+///
+/// [Request::git] can also be a `file://` url or the path of a local (bare) repository, like a git mirror,
+/// in one of the [CloneOptions::local_roots].
+/// Submodules are initialized and updated recursively.
+/// `report_files` are the filenames of the report, used for [CloneOptions::sparse_checkout].
+///
+/// The ssh key is picked from the [DeployKeys] registry for [Request::git].
+/// The git server host key is verified with [HostKeyVerification], a failure is returned as [ApiError::HostKeyVerification].
+pub fn pull_or_clone(request: &Request, report_files: &[&str]) -> ApiResult<PathBuf> {
+    pull_or_clone_with_options(request, report_files, &CloneOptions::from_config())
+}
+
+pub fn pull_or_clone_with_options(
+    request: &Request,
+    report_files: &[&str],
+    options: &CloneOptions,
+) -> ApiResult<PathBuf> {
+    check_local_source(&request.git, &options.local_roots)?;
+    let host_key_verification = HostKeyVerification::from_config()?;
+    pull_or_clone_with(request, report_files, options, &host_key_verification)
+        .map_err(|error| api_error(error, &host_key_verification))
+}
 
-    let mut fo = create_fetch_options();
-    if repository_path.exists() && repository_path.read_dir()?.next().is_some() {
+/// The host key verification failure, if that's why the fetch failed
+fn api_error(error: anyhow::Error, host_key_verification: &HostKeyVerification) -> ApiError {
+    match host_key_verification.take_failure() {
+        Some(failure) => ApiError::HostKeyVerification(failure),
+        None => error.downcast::<ApiError>().unwrap_or_else(ApiError::from),
+    }
+}
+
+/// Fetch options of the clone of the request, with [CloneOptions::depth]
+fn clone_fetch_options<'a>(
+    request: &Request,
+    options: &CloneOptions,
+    deploy_keys: &DeployKeys,
+    host_key_verification: &'a HostKeyVerification,
+) -> FetchOptions<'a> {
+    let mut fo = create_fetch_options(
+        &request.git,
+        deploy_keys.key_for(&request.git),
+        host_key_verification,
+    );
+    fo.depth(options.depth);
+    // Tags would bring their whole history in a shallow repository
+    fo.download_tags(if options.depth > 0 {
+        git2::AutotagOption::None
+    } else {
+        git2::AutotagOption::All
+    });
+    fo
+}
+
+fn pull_or_clone_with(
+    request: &Request,
+    report_files: &[&str],
+    options: &CloneOptions,
+    host_key_verification: &HostKeyVerification,
+) -> anyhow::Result<PathBuf> {
+    let repository_path = repository_path(request, options);
+    let deploy_keys = DeployKeys::from_config()?;
+    let mut fo = clone_fetch_options(request, options, &deploy_keys, host_key_verification);
+
+    let mut checkout = CheckoutBuilder::new();
+    checkout.force();
+    if options.sparse_checkout {
+        // The files are checked out once we know which paths of the tree are in the report
+        checkout.dry_run();
+    }
+
+    let repo = if repository_path.exists() && repository_path.read_dir()?.next().is_some() {
         let repo: Repository = Repository::open(&repository_path)?;
-        let (object, reference) = repo
-            .revparse_ext(&request.branch)
-            .context("Object not found")?;
-        repo.checkout_tree(&object, None)
-            .context("Failed to checkout")?;
-        repo.set_head(reference.unwrap().name().unwrap())
-            .context("setting head")?;
-
-        let mut remote = repo
-            .find_remote("origin")
-            .context("default remote origin")?;
-        fo.download_tags(git2::AutotagOption::All);
-        remote.fetch(&[&request.branch], Some(&mut fo), None)?;
+        pull(&repo, request, &mut fo, &mut checkout)?;
+        repo
     } else {
         let mut cloner = git2::build::RepoBuilder::new();
         cloner.fetch_options(fo);
+        cloner.with_checkout(checkout);
         cloner.branch(&request.branch);
-        if let Err(error) = cloner.clone(&request.git, &repository_path) {
-            if repository_path.exists() {
-                fs::remove_dir_all(&repository_path)
-                    .context("removing git dir after failed clone")?;
+        match cloner.clone(&request.git, &repository_path) {
+            Ok(repo) => repo,
+            Err(error) => {
+                if repository_path.exists() {
+                    fs::remove_dir_all(&repository_path)
+                        .context("removing git dir after failed clone")?;
+                }
+                Err(error)?
             }
-            Err(error)?;
         }
+    };
+
+    if options.sparse_checkout {
+        let tree = repo.head()?.peel_to_tree()?;
+        let paths = sparse_checkout_paths(&tree, report_files)?;
+        // The files of the previous checkout that aren't in the report anymore
+        remove_checkout(&repository_path)?;
+        let mut checkout = CheckoutBuilder::new();
+        checkout.force();
+        for path in paths {
+            checkout.path(path);
+        }
+        repo.checkout_tree(tree.as_object(), Some(&mut checkout))
+            .context("Failed to checkout report files")?;
     }
+    update_submodules(
+        &repo,
+        report_files,
+        options,
+        &deploy_keys,
+        host_key_verification,
+    )?;
     Ok(repository_path.canonicalize()?)
 }
+
+fn repository_path(request: &Request, options: &CloneOptions) -> PathBuf {
+    options
+        .repositories_dir
+        .join(clone_name(&request.raw_repository_name()))
+}
+
+/// Remove the checked out files, but not the git directories of the repository and its submodules.
+///
+/// Symlinks are removed without following them, they could point outside of the repository.
+fn remove_checkout(directory: &Path) -> anyhow::Result<()> {
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path.file_name() == Some(".git".as_ref()) {
+            continue;
+        }
+        if !entry.file_type()?.is_dir() {
+            fs::remove_file(&path)?;
+        } else if !path.join(".git").exists() {
+            remove_checkout(&path)?;
+            if path.read_dir()?.next().is_none() {
+                fs::remove_dir(&path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Commit of the base branch the branch of the request forked from, in the repository of [pull_or_clone].
+///
+/// The base branch is fetched with [CloneOptions::depth], if the merge-base is beyond the history of
+/// the shallow repository, the history of both branches is fetched.
+/// None if the branches don't have a common commit.
+pub fn merge_base(request: &Request, base_branch: &str) -> ApiResult<Option<String>> {
+    merge_base_with_options(request, base_branch, &CloneOptions::from_config())
+}
+
+pub fn merge_base_with_options(
+    request: &Request,
+    base_branch: &str,
+    options: &CloneOptions,
+) -> ApiResult<Option<String>> {
+    let host_key_verification = HostKeyVerification::from_config()?;
+    merge_base_with(request, base_branch, options, &host_key_verification)
+        .map_err(|error| api_error(error, &host_key_verification))
+}
+
+fn merge_base_with(
+    request: &Request,
+    base_branch: &str,
+    options: &CloneOptions,
+    host_key_verification: &HostKeyVerification,
+) -> anyhow::Result<Option<String>> {
+    let repo = Repository::open(repository_path(request, options))?;
+    let deploy_keys = DeployKeys::from_config()?;
+    let mut remote = repo
+        .find_remote("origin")
+        .context("default remote origin")?;
+    let base_refspec = format!("+refs/heads/{0}:refs/remotes/origin/{0}", base_branch);
+    let mut fo = clone_fetch_options(request, options, &deploy_keys, host_key_verification);
+    remote.fetch(&[&base_refspec], Some(&mut fo), None)?;
+
+    let head = repo.head()?.peel_to_commit()?.id();
+    let base = repo
+        .revparse_single(&format!("refs/remotes/origin/{}", base_branch))
+        .context("Object not found")?
+        .peel_to_commit()?
+        .id();
+    match repo.merge_base(head, base) {
+        Err(error) if error.code() == git2::ErrorCode::NotFound && repo.is_shallow() => {
+            let refspec = format!("+refs/heads/{0}:refs/remotes/origin/{0}", request.branch);
+            let mut fo = clone_fetch_options(
+                request,
+                &CloneOptions {
+                    depth: GIT_FETCH_DEPTH_UNSHALLOW,
+                    ..options.clone()
+                },
+                &deploy_keys,
+                host_key_verification,
+            );
+            remote.fetch(&[&refspec, &base_refspec], Some(&mut fo), None)?;
+            found_merge_base(repo.merge_base(head, base))
+        }
+        result => found_merge_base(result),
+    }
+}
+
+/// `GIT_FETCH_DEPTH_UNSHALLOW` of libgit2, fetches the missing history of a shallow repository
+const GIT_FETCH_DEPTH_UNSHALLOW: i32 = i32::MAX;
+
+fn found_merge_base(result: Result<git2::Oid, git2::Error>) -> anyhow::Result<Option<String>> {
+    match result {
+        Ok(id) => Ok(Some(id.to_string())),
+        Err(error) if error.code() == git2::ErrorCode::NotFound => Ok(None),
+        Err(error) => Err(error.into()),
+    }
+}
+
+/// Init and update the submodules recursively, each one is fetched with the key matching its url.
+///
+/// With [CloneOptions::sparse_checkout] only the submodules containing report files are updated.
+fn update_submodules(
+    repo: &Repository,
+    report_files: &[&str],
+    options: &CloneOptions,
+    deploy_keys: &DeployKeys,
+    host_key_verification: &HostKeyVerification,
+) -> anyhow::Result<()> {
+    for mut submodule in repo.submodules()? {
+        let path = submodule.path().to_string_lossy().into_owned();
+        let submodule_dir = format!("/{path}/");
+        if options.sparse_checkout && !report_files.iter().any(|f| f.contains(&submodule_dir)) {
+            continue;
+        }
+
+        // The init resolves the relative urls against the remote of the repository, the resolved
+        // url is the one fetched, a relative url could reach a local repository outside of the roots
+        submodule
+            .init(true)
+            .with_context(|| format!("initializing submodule {path}"))?;
+        let name = submodule.name().unwrap_or(&path).to_string();
+        let url = repo
+            .config()?
+            .get_string(&format!("submodule.{name}.url"))
+            .with_context(|| format!("reading the url of submodule {path}"))?;
+        check_local_source(&url, &options.local_roots)?;
+        let fo = create_fetch_options(&url, deploy_keys.key_for(&url), host_key_verification);
+        let mut update_options = SubmoduleUpdateOptions::new();
+        update_options.fetch(fo);
+        submodule
+            .update(false, Some(&mut update_options))
+            .with_context(|| format!("updating submodule {path}"))?;
+
+        let submodule_repo = submodule
+            .open()
+            .with_context(|| format!("opening submodule {path}"))?;
+        update_submodules(
+            &submodule_repo,
+            report_files,
+            options,
+            deploy_keys,
+            host_key_verification,
+        )?;
+    }
+    Ok(())
+}
+
+/// Fetch the branch from origin, and checkout its tip
+fn pull(
+    repo: &Repository,
+    request: &Request,
+    fo: &mut FetchOptions,
+    checkout: &mut CheckoutBuilder,
+) -> anyhow::Result<()> {
+    let branch = &request.branch;
+    // The urls of a repository share its clone, like its https and ssh urls
+    repo.remote_set_url("origin", &request.git)?;
+    let mut remote = repo
+        .find_remote("origin")
+        .context("default remote origin")?;
+    let refspec = format!("+refs/heads/{0}:refs/remotes/origin/{0}", branch);
+    remote.fetch(&[&refspec], Some(fo), None)?;
+
+    let commit = repo
+        .revparse_single(&format!("refs/remotes/origin/{}", branch))
+        .context("Object not found")?
+        .peel_to_commit()?;
+    repo.checkout_tree(commit.as_object(), Some(checkout))
+        .context("Failed to checkout")?;
+    let reference = format!("refs/heads/{}", branch);
+    repo.reference(&reference, commit.id(), true, "pull")?;
+    repo.set_head(&reference).context("setting head")?;
+    Ok(())
+}
+
+/// Paths of the tree matching a report file, the longest matching suffix is used.
+///
+/// Cargo manifests are always included, as llvm-cov-pretty needs the workspace, and the
+/// [REPOSITORY_CONFIG_FILE] as the settings of the coverage are read from it.
+fn sparse_checkout_paths(tree: &Tree, report_files: &[&str]) -> anyhow::Result<Vec<String>> {
+    let mut tree_files = HashSet::new();
+    tree.walk(TreeWalkMode::PreOrder, |directory, entry| {
+        if entry.kind() == Some(ObjectType::Blob) {
+            tree_files.insert(format!("{directory}{}", entry.name().unwrap_or_default()));
+        }
+        TreeWalkResult::Ok
+    })?;
+
+    let mut paths: Vec<String> = tree_files
+        .iter()
+        .filter(|path| {
+            let name = path.rsplit('/').next().unwrap_or_default();
+            name == "Cargo.toml" || name == "Cargo.lock"
+        })
+        .chain(tree_files.get(REPOSITORY_CONFIG_FILE))
+        .cloned()
+        .collect();
+    for file in report_files {
+        let matching_path = file
+            .match_indices('/')
+            .map(|(idx, _)| &file[idx + 1..])
+            .find(|path| tree_files.contains(*path));
+        if let Some(path) = matching_path {
+            paths.push(path.to_string());
+        }
+    }
+    Ok(paths)
+}
+
+/// Id of the checked out commit, None if the sources aren't a git repository
+pub fn head_commit(repository_path: &Path) -> Option<String> {
+    let repo = Repository::open(repository_path).ok()?;
+    let commit = repo.head().ok()?.peel_to_commit().ok()?;
+    Some(commit.id().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{
+        report::find_matching_project_path,
+        repository_config::RepositoryConfig,
+        test_utils::{commit_entries_to_fixture, commit_to_fixture, request},
+    };
+
+    #[test]
+    fn test_git_clone_local_fixture() {
+        let fixtures = tempfile::tempdir().unwrap();
+        let repositories = tempfile::tempdir().unwrap();
+        let fixture_path = &fixtures.path().join("fixture-org/fixture-project.git");
+        let fixture = git2::Repository::init_bare(fixture_path).unwrap();
+        fixture.set_head("refs/heads/main").unwrap();
+        commit_to_fixture(
+            &fixture,
+            "main",
+            &[("Cargo.toml", "[package]"), ("src/main.rs", "fn main() {}")],
+        );
+
+        for git in [
+            format!("file://{}", fixture_path.display()),
+            fixture_path.display().to_string(),
+        ] {
+            let request = request(&git, "main");
+            assert_eq!(request.raw_repository_name(), "fixture-org/fixture-project");
+
+            // Local sources must be in one of the allowed roots
+            for local_roots in [vec![], vec![fixture_path.join("refs")]] {
+                let options = CloneOptions {
+                    local_roots,
+                    repositories_dir: repositories.path().to_path_buf(),
+                    ..Default::default()
+                };
+                assert!(matches!(
+                    pull_or_clone_with_options(&request, &[], &options),
+                    Err(ApiError::Forbidden(_))
+                ));
+            }
+
+            let options = CloneOptions {
+                local_roots: vec![fixtures.path().to_path_buf()],
+                repositories_dir: repositories.path().to_path_buf(),
+                ..Default::default()
+            };
+            let path = pull_or_clone_with_options(&request, &[], &options).unwrap();
+            assert!(path.starts_with(repositories.path().canonicalize().unwrap()));
+            assert!(path.join("src/main.rs").exists());
+            let old_path =
+                find_matching_project_path(&path, "/home/ci/project/src/main.rs").unwrap();
+            assert_eq!(old_path, "/home/ci/project");
+
+            commit_to_fixture(&fixture, "main", &[("src/lib.rs", "pub fn lib() {}")]);
+            let path = pull_or_clone_with_options(&request, &[], &options).unwrap();
+            assert!(path.join("src/lib.rs").exists());
+
+            fs::remove_dir_all(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_remove_checkout() {
+        let dir = tempfile::tempdir().unwrap();
+        let outside = dir.path().join("outside");
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(outside.join("secret"), "secret").unwrap();
+        let repository = dir.path().join("repository");
+        fs::create_dir_all(repository.join(".git")).unwrap();
+        fs::create_dir_all(repository.join("src")).unwrap();
+        fs::write(repository.join("src/lib.rs"), "pub fn lib() {}").unwrap();
+        std::os::unix::fs::symlink(&outside, repository.join("src/link")).unwrap();
+
+        remove_checkout(&repository).unwrap();
+        assert!(repository.join(".git").exists());
+        assert!(!repository.join("src").exists());
+        // The link is removed, not the directory it points to
+        assert_eq!(
+            fs::read_to_string(outside.join("secret")).unwrap(),
+            "secret"
+        );
+    }
+
+    #[test]
+    fn test_git_sparse_checkout() {
+        let fixtures = tempfile::tempdir().unwrap();
+        let repositories = tempfile::tempdir().unwrap();
+        let fixture_path = &fixtures.path().join("fixture-org/sparse-project.git");
+        let fixture = git2::Repository::init_bare(fixture_path).unwrap();
+        fixture.set_head("refs/heads/main").unwrap();
+        commit_to_fixture(
+            &fixture,
+            "main",
+            &[
+                ("Cargo.toml", "[workspace]"),
+                (".llvm-cov-host.toml", "base_branch = \"develop\""),
+                ("api/Cargo.toml", "[package]"),
+                ("api/src/main.rs", "fn main() {}"),
+                ("api/src/lib.rs", "pub fn lib() {}"),
+                ("web/index.html", "<html></html>"),
+            ],
+        );
+
+        let request = request(&format!("file://{}", fixture_path.display()), "main");
+        let options = CloneOptions {
+            depth: 0,
+            sparse_checkout: true,
+            local_roots: vec![fixtures.path().to_path_buf()],
+            repositories_dir: repositories.path().to_path_buf(),
+        };
+        let report_files = [
+            "/builds/project/api/src/main.rs",
+            "/builds/project/api/src/lib.rs",
+        ];
+
+        let path = pull_or_clone_with_options(&request, &report_files, &options).unwrap();
+        assert!(path.join("Cargo.toml").exists());
+        assert!(path.join("api/Cargo.toml").exists());
+        assert!(path.join("api/src/main.rs").exists());
+        assert!(path.join("api/src/lib.rs").exists());
+        assert!(!path.join("web/index.html").exists());
+        // The repository configuration is always checked out
+        assert_eq!(
+            RepositoryConfig::load(&path).unwrap().base_branch(),
+            "develop"
+        );
+
+        // The files of the previous checkout that aren't in the report are removed
+        commit_to_fixture(&fixture, "main", &[("api/src/main.rs", "fn main() { 1; }")]);
+        let path = pull_or_clone_with_options(&request, &report_files[..1], &options).unwrap();
+        assert_eq!(
+            fs::read_to_string(path.join("api/src/main.rs")).unwrap(),
+            "fn main() { 1; }"
+        );
+        assert!(!path.join("api/src/lib.rs").exists());
+        assert!(!path.join("web/index.html").exists());
+        assert!(path.join(".llvm-cov-host.toml").exists());
+    }
+
+    #[test]
+    fn test_git_merge_base() {
+        let fixtures = tempfile::tempdir().unwrap();
+        let repositories = tempfile::tempdir().unwrap();
+        let fixture_path = fixtures.path().join("fixture-org/merge-base-project.git");
+        let fixture = git2::Repository::init_bare(&fixture_path).unwrap();
+        fixture.set_head("refs/heads/main").unwrap();
+        commit_to_fixture(&fixture, "main", &[("src/main.rs", "fn main() {}")]);
+        let fork = commit_entries_to_fixture(&fixture, "main", &[]);
+        fixture
+            .reference("refs/heads/feature", fork, false, "fork")
+            .unwrap();
+        for i in 0..3 {
+            commit_to_fixture(
+                &fixture,
+                "main",
+                &[("src/main.rs", &format!("// main {i}"))],
+            );
+            commit_to_fixture(
+                &fixture,
+                "feature",
+                &[("src/lib.rs", &format!("// feature {i}"))],
+            );
+        }
+
+        let request = request(&format!("file://{}", fixture_path.display()), "feature");
+        let options = CloneOptions {
+            depth: 1,
+            local_roots: vec![fixtures.path().to_path_buf()],
+            repositories_dir: repositories.path().to_path_buf(),
+            ..Default::default()
+        };
+        let path = pull_or_clone_with_options(&request, &[], &options).unwrap();
+        // libgit2 doesn't fetch shallow from a local repository, the clone is cut at the tip of the
+        // branch so the merge-base isn't in its history
+        let clone = git2::Repository::open(&path).unwrap();
+        let head = clone.head().unwrap().target().unwrap();
+        fs::write(path.join(".git/shallow"), format!("{head}\n")).unwrap();
+        assert!(git2::Repository::open(&path).unwrap().is_shallow());
+        let merge_base = merge_base_with_options(&request, "main", &options).unwrap();
+        assert_eq!(merge_base, Some(fork.to_string()));
+        assert!(!git2::Repository::open(&path).unwrap().is_shallow());
+
+        // Unrelated histories
+        commit_to_fixture(&fixture, "orphan", &[("README.md", "orphan")]);
+        let merge_base = merge_base_with_options(&request, "orphan", &options).unwrap();
+        assert_eq!(merge_base, None);
+    }
+
+    #[test]
+    fn test_git_submodules() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let fixtures = temp_dir.path();
+        let repositories = tempfile::tempdir().unwrap();
+
+        let submodule =
+            git2::Repository::init_bare(fixtures.join("fixture-org/library.git")).unwrap();
+        submodule.set_head("refs/heads/main").unwrap();
+        commit_to_fixture(&submodule, "main", &[("src/lib.rs", "pub fn lib() {}")]);
+        let submodule_commit = submodule.head().unwrap().target().unwrap();
+
+        let fixture_path = fixtures.join("fixture-org/with-submodule.git");
+        let fixture = git2::Repository::init_bare(&fixture_path).unwrap();
+        fixture.set_head("refs/heads/main").unwrap();
+        let gitmodules = format!(
+            "[submodule \"vendor/library\"]\n\tpath = vendor/library\n\turl = file://{}\n",
+            fixtures.join("fixture-org/library.git").display()
+        );
+        commit_to_fixture(
+            &fixture,
+            "main",
+            &[
+                (".gitmodules", &gitmodules),
+                ("Cargo.toml", "[package]"),
+                ("src/lib.rs", "pub fn root() {}"),
+            ],
+        );
+        commit_entries_to_fixture(
+            &fixture,
+            "main",
+            &[("vendor/library", 0o160000, submodule_commit, 0)],
+        );
+
+        let request = request(&format!("file://{}", fixture_path.display()), "main");
+        let options = CloneOptions {
+            local_roots: vec![fixtures.to_path_buf()],
+            repositories_dir: repositories.path().to_path_buf(),
+            ..Default::default()
+        };
+        let path = pull_or_clone_with_options(&request, &[], &options).unwrap();
+        assert!(path.join("vendor/library/src/lib.rs").exists());
+
+        // The file of the submodule must not be matched with src/lib.rs at the root of the repository
+        let old_path =
+            find_matching_project_path(&path, "/builds/project/vendor/library/src/lib.rs").unwrap();
+        assert_eq!(old_path, "/builds/project");
+        fs::remove_dir_all(&path).unwrap();
+
+        // A relative url is resolved against the remote, then checked like the other local sources
+        let relative_path = fixtures.join("fixture-org/with-relative-submodule.git");
+        let relative = git2::Repository::init_bare(&relative_path).unwrap();
+        relative.set_head("refs/heads/main").unwrap();
+        commit_to_fixture(
+            &relative,
+            "main",
+            &[(
+                ".gitmodules",
+                "[submodule \"vendor/library\"]\n\tpath = vendor/library\n\turl = ../library.git\n",
+            )],
+        );
+        commit_entries_to_fixture(
+            &relative,
+            "main",
+            &[("vendor/library", 0o160000, submodule_commit, 0)],
+        );
+        let request = Request {
+            git: relative_path.display().to_string(),
+            ..request
+        };
+        let path = pull_or_clone_with_options(&request, &[], &options).unwrap();
+        assert!(path.join("vendor/library/src/lib.rs").exists());
+        fs::remove_dir_all(&path).unwrap();
+
+        let options = CloneOptions {
+            local_roots: vec![fixtures.join("fixture-org/with-relative-submodule.git")],
+            ..options
+        };
+        assert!(matches!(
+            pull_or_clone_with_options(&request, &[], &options),
+            Err(ApiError::Forbidden(_))
+        ));
+    }
+
+    #[test]
+    fn test_git_clone_http() {
+        dotenvy::dotenv().ok();
+
+        let repositories = tempfile::tempdir().unwrap();
+        let request = request("https://github.com/GreeFine/llvm-cov-host.git", "main");
+        let options = CloneOptions {
+            repositories_dir: repositories.path().to_path_buf(),
+            ..CloneOptions::from_config()
+        };
+
+        let path = pull_or_clone_with_options(&request, &[], &options).unwrap();
+        assert!(path.exists());
+        fs::remove_dir_all(&path).unwrap();
+
+        let path = pull_or_clone_with_options(&request, &[], &options).unwrap();
+        assert!(path.exists());
+    }
+}
@@ -0,0 +1,223 @@
+use std::sync::OnceLock;
+
+use jsonwebtoken::{decode, decode_header, Algorithm, Validation};
+use serde::Deserialize;
+
+use crate::{
+    config::{self, GithubOidcConfig},
+    error::{ApiError, ApiResult},
+    filter::glob_set,
+    jwks::{JwksCache, JwksSource},
+};
+
+/// Claims of a GitHub Actions OIDC token used to authorize an upload,
+/// see <https://docs.github.com/en/actions/deployment/security-hardening-your-deployments/about-security-hardening-with-openid-connect>
+#[derive(Debug, Clone, Deserialize)]
+pub struct GithubClaims {
+    /// `owner/project` of the repository running the workflow
+    pub repository: String,
+    #[serde(rename = "ref", default)]
+    pub git_ref: String,
+    #[serde(default)]
+    pub workflow: String,
+}
+
+impl GithubClaims {
+    /// If the git url is the repository of the token on `github_host`, over https or ssh
+    pub fn matches_git_url(&self, git: &str, github_host: &str) -> bool {
+        let git = git.trim_end_matches('/').trim_end_matches(".git");
+        let repository = &self.repository;
+        [
+            format!("https://{github_host}/{repository}"),
+            format!("git@{github_host}:{repository}"),
+            format!("ssh://git@{github_host}/{repository}"),
+        ]
+        .iter()
+        .any(|url| url.eq_ignore_ascii_case(git))
+    }
+}
+
+/// Verify GitHub Actions OIDC tokens against the keys of the issuer
+#[derive(Debug)]
+pub struct GithubOidc {
+    config: GithubOidcConfig,
+    jwks: JwksCache,
+}
+
+impl GithubOidc {
+    /// None if the configuration doesn't have a JWKS
+    pub fn new(config: GithubOidcConfig) -> Option<Self> {
+        // The local file takes precedence over the url
+        let source = match (&config.jwks_path, &config.jwks_url) {
+            (Some(path), _) => JwksSource::Path(path.clone()),
+            (None, Some(url)) => JwksSource::Url(url.clone()),
+            (None, None) => return None,
+        };
+        Some(Self {
+            config,
+            jwks: JwksCache::new(source),
+        })
+    }
+
+    /// From the `[github_oidc]` section of the server configuration, if enabled
+    pub fn global() -> Option<&'static Self> {
+        static GITHUB_OIDC: OnceLock<Option<GithubOidc>> = OnceLock::new();
+        GITHUB_OIDC
+            .get_or_init(|| Self::new(config::get().github_oidc.clone()))
+            .as_ref()
+    }
+
+    /// The repository of the token is in [GithubOidcConfig::allowed_repositories]
+    pub fn allows(&self, repository: &str) -> ApiResult<bool> {
+        let allowed = self.config.allowed_repositories.iter().map(String::as_str);
+        Ok(glob_set(allowed)?.is_match(repository))
+    }
+
+    /// Check the signature, expiration, issuer and audience of the token
+    pub fn verify(&self, token: &str) -> ApiResult<GithubClaims> {
+        let invalid = |e: jsonwebtoken::errors::Error| ApiError::InvalidToken(e.to_string());
+
+        let header = decode_header(token).map_err(invalid)?;
+        let kid = header
+            .kid
+            .ok_or_else(|| ApiError::InvalidToken("missing key id".to_string()))?;
+        let key = self.jwks.decoding_key(&kid)?;
+
+        // GitHub signs with RS256, other algorithms are refused
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.set_issuer(&[&self.config.issuer]);
+        validation.set_audience(&[&self.config.audience]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud"]);
+
+        decode::<GithubClaims>(token, &key, &validation)
+            .map(|data| data.claims)
+            .map_err(invalid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{
+        api_keys::{Permission, Principal},
+        test_utils::{request, OIDC_TEST_MODULUS, OIDC_TEST_PRIVATE_KEY},
+    };
+
+    #[test]
+    fn test_github_oidc() {
+        use base64::Engine;
+        use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+
+        let dir = tempfile::tempdir().unwrap();
+        let jwks_path = dir.path().join("jwks.json");
+        let jwks = serde_json::json!({
+            "keys": [{ "kty": "RSA", "kid": "test", "alg": "RS256", "use": "sig", "n": OIDC_TEST_MODULUS, "e": "AQAB" }]
+        });
+        fs::write(&jwks_path, jwks.to_string()).unwrap();
+
+        let github_oidc = GithubOidc::new(GithubOidcConfig {
+            jwks_path: Some(jwks_path),
+            allowed_repositories: vec!["GreeFine/llvm-cov-*".to_string()],
+            ..Default::default()
+        })
+        .unwrap();
+        let key = EncodingKey::from_rsa_pem(OIDC_TEST_PRIVATE_KEY.as_bytes()).unwrap();
+        let sign = |kid: &str, claims: serde_json::Value| {
+            let mut header = Header::new(Algorithm::RS256);
+            header.kid = Some(kid.to_string());
+            encode(&header, &claims, &key).unwrap()
+        };
+        let exp = chrono::Utc::now().timestamp() + 300;
+        let claims = |aud: &str, exp: i64| {
+            serde_json::json!({
+                "iss": "https://token.actions.githubusercontent.com",
+                "aud": aud,
+                "exp": exp,
+                "repository": "GreeFine/llvm-cov-host",
+                "ref": "refs/heads/main",
+                "workflow": "Coverage",
+            })
+        };
+
+        let verified = github_oidc
+            .verify(&sign("test", claims("llvm-cov-host", exp)))
+            .unwrap();
+        assert_eq!(verified.repository, "GreeFine/llvm-cov-host");
+        // Any repository of GitHub can mint a token for the audience
+        assert!(github_oidc.allows(&verified.repository).unwrap());
+        assert!(!github_oidc.allows("someone/else").unwrap());
+        assert!(verified.matches_git_url("git@github.com:GreeFine/llvm-cov-host.git", "github.com"));
+        assert!(verified.matches_git_url("https://github.com/greefine/llvm-cov-host", "github.com"));
+        assert!(!verified.matches_git_url("https://evil.com/GreeFine/llvm-cov-host", "github.com"));
+        assert!(!verified.matches_git_url("git@github.com:GreeFine/other.git", "github.com"));
+
+        let principal = Principal::GithubActions(verified);
+        principal
+            .authorize("GreeFine/llvm-cov-host", Permission::Upload)
+            .unwrap();
+        assert!(principal
+            .authorize("GreeFine/other", Permission::Upload)
+            .is_err());
+        assert!(principal
+            .authorize("GreeFine/llvm-cov-host", Permission::Delete)
+            .is_err());
+        assert!(principal.authorize_admin().is_err());
+
+        principal
+            .authorize_upload(&request(
+                "https://github.com/GreeFine/llvm-cov-host.git",
+                "main",
+            ))
+            .unwrap();
+        assert!(principal
+            .authorize_upload(&request("https://github.com/GreeFine/other.git", "main"))
+            .is_err());
+        // The name of a repository with a digit and a dot isn't captured from its url
+        let principal = Principal::GithubActions(GithubClaims {
+            repository: "GreeFine/llvm-cov-host2.rs".to_string(),
+            git_ref: "refs/heads/main".to_string(),
+            workflow: "Coverage".to_string(),
+        });
+        principal
+            .authorize_upload(&request(
+                "https://github.com/GreeFine/llvm-cov-host2.rs.git",
+                "main",
+            ))
+            .unwrap();
+        principal
+            .authorize_upload(&request(
+                "git@github.com:GreeFine/llvm-cov-host2.rs.git",
+                "main",
+            ))
+            .unwrap();
+        assert!(principal
+            .authorize_upload(&request(
+                "https://github.com/GreeFine/llvm-cov-host2.git",
+                "main"
+            ))
+            .is_err());
+
+        // Another audience, expired, unknown key, and tampered tokens are refused
+        assert!(github_oidc
+            .verify(&sign("test", claims("other-service", exp)))
+            .is_err());
+        assert!(github_oidc
+            .verify(&sign("test", claims("llvm-cov-host", exp - 3600)))
+            .is_err());
+        assert!(github_oidc
+            .verify(&sign("unknown", claims("llvm-cov-host", exp)))
+            .is_err());
+        let token = sign("test", claims("llvm-cov-host", exp));
+        let (payload_start, payload_end) = (token.find('.').unwrap(), token.rfind('.').unwrap());
+        let tampered = serde_json::json!({ "iss": "https://token.actions.githubusercontent.com", "aud": "llvm-cov-host", "exp": exp, "repository": "GreeFine/other" });
+        let tampered = format!(
+            "{}.{}{}",
+            &token[..payload_start],
+            base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(tampered.to_string()),
+            &token[payload_end..]
+        );
+        assert!(github_oidc.verify(&tampered).is_err());
+    }
+}
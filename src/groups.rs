@@ -0,0 +1,61 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    compare::float_precision_two,
+    error::ApiResult,
+    filter::glob_set,
+    model::{Report, Summary},
+};
+
+/// Files of the repository reported together, like the crates of a workspace
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CrateGroup {
+    pub name: String,
+    /// Glob patterns relative to the repository root, like `crates/api/**`
+    pub paths: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GroupCoverage {
+    pub name: String,
+    /// Function coverage of the group files, in percent
+    pub functions: f64,
+    /// Line coverage of the group files, in percent
+    pub lines: f64,
+}
+
+/// Coverage of each group, computed from the files summaries of the report
+pub fn group_coverage(
+    groups: &[CrateGroup],
+    report: &Report,
+    local_repository: &Path,
+) -> ApiResult<Vec<GroupCoverage>> {
+    let Some(data) = report.data.first() else {
+        return Ok(vec![]);
+    };
+    let local_repository = format!("{}/", local_repository.to_string_lossy());
+
+    groups
+        .iter()
+        .map(|group| {
+            let paths = glob_set(group.paths.iter().map(String::as_str))?;
+            let total = Summary::total(
+                data.files
+                    .iter()
+                    .filter(|file| {
+                        let filename = file.filename.as_str();
+                        paths.is_match(filename.strip_prefix(&local_repository).unwrap_or(filename))
+                    })
+                    .map(|file| &file.summary),
+            );
+            Ok(GroupCoverage {
+                name: group.name.clone(),
+                functions: float_precision_two(total.functions.percent),
+                lines: float_precision_two(total.lines.percent),
+            })
+        })
+        .collect()
+}
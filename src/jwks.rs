@@ -0,0 +1,83 @@
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use jsonwebtoken::{jwk::JwkSet, DecodingKey};
+
+use crate::error::{ApiError, ApiResult};
+
+/// The keys of the issuer are fetched again after this duration
+const JWKS_CACHE_DURATION: Duration = Duration::from_secs(60 * 60);
+/// Minimum time between two fetches triggered by an unknown key id, the keys may have been rotated
+const JWKS_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Where the keys of an issuer are read from
+#[derive(Debug, Clone)]
+pub enum JwksSource {
+    Url(String),
+    /// Local file, for tests and mock issuers
+    Path(PathBuf),
+}
+
+/// Keys of a token issuer, cached between verifications
+#[derive(Debug)]
+pub struct JwksCache {
+    source: JwksSource,
+    jwks: Mutex<Option<(Instant, JwkSet)>>,
+}
+
+impl JwksCache {
+    pub fn new(source: JwksSource) -> Self {
+        Self {
+            source,
+            jwks: Mutex::default(),
+        }
+    }
+
+    fn fetch(&self) -> anyhow::Result<JwkSet> {
+        match &self.source {
+            JwksSource::Path(path) => {
+                let content = fs::read_to_string(path)
+                    .with_context(|| format!("reading JWKS {}", path.display()))?;
+                serde_json::from_str(&content)
+                    .with_context(|| format!("parsing JWKS {}", path.display()))
+            }
+            JwksSource::Url(url) => ureq::get(url)
+                .timeout(Duration::from_secs(10))
+                .call()
+                .with_context(|| format!("fetching JWKS {url}"))?
+                .into_json()
+                .with_context(|| format!("parsing JWKS {url}")),
+        }
+    }
+
+    fn cache(&self) -> MutexGuard<'_, Option<(Instant, JwkSet)>> {
+        self.jwks.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn decoding_key(&self, kid: &str) -> ApiResult<DecodingKey> {
+        let needs_fetch = match self.cache().as_ref() {
+            None => true,
+            Some((fetched_at, jwks)) => {
+                fetched_at.elapsed() > JWKS_CACHE_DURATION
+                    || (jwks.find(kid).is_none() && fetched_at.elapsed() > JWKS_REFRESH_INTERVAL)
+            }
+        };
+        if needs_fetch {
+            // Without the lock, the other verifications keep using the cached keys meanwhile
+            let jwks = self.fetch()?;
+            *self.cache() = Some((Instant::now(), jwks));
+        }
+
+        let cache = self.cache();
+        let jwk = cache
+            .as_ref()
+            .and_then(|(_, jwks)| jwks.find(kid))
+            .ok_or_else(|| ApiError::InvalidToken(format!("unknown key id {kid}")))?;
+        DecodingKey::from_jwk(jwk).map_err(|e| ApiError::InvalidToken(e.to_string()))
+    }
+}
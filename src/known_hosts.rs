@@ -0,0 +1,404 @@
+use std::{
+    cell::RefCell,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    str::FromStr,
+};
+
+use base64::{
+    engine::general_purpose::{STANDARD, STANDARD_NO_PAD},
+    Engine,
+};
+use git2::{cert::Cert, CertificateCheckStatus};
+use hmac::{Hmac, Mac};
+use log::{info, warn};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use crate::config;
+
+#[derive(Debug, thiserror::Error)]
+pub enum HostKeyError {
+    #[error("git server {host} didn't provide its host key")]
+    MissingHostKey { host: String },
+    #[error("host {host} is not in known_hosts, key fingerprint: {fingerprint}")]
+    UnknownHost { host: String, fingerprint: String },
+    #[error("host key of {host} doesn't match the known one, key fingerprint: {fingerprint}")]
+    Mismatch { host: String, fingerprint: String },
+    #[error("host key of {host} has been revoked, key fingerprint: {fingerprint}")]
+    Revoked { host: String, fingerprint: String },
+    #[error("unable to add {host} to known_hosts: {source}")]
+    TrustOnFirstUse { host: String, source: io::Error },
+}
+
+/// OpenSSH style fingerprint of a raw host key: `SHA256:<base64 without padding>`
+pub fn fingerprint(key: &[u8]) -> String {
+    format!("SHA256:{}", STANDARD_NO_PAD.encode(Sha256::digest(key)))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum HostPattern {
+    Plain(String),
+    /// `|1|salt|hash` entries, as written by `ssh-keyscan -H` or `HashKnownHosts yes`
+    Hashed {
+        salt: Vec<u8>,
+        hash: Vec<u8>,
+    },
+}
+
+impl HostPattern {
+    /// `name` is the host as written in known_hosts, see [host_name]
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            Self::Plain(pattern) => pattern.eq_ignore_ascii_case(name),
+            Self::Hashed { salt, hash } => {
+                let Ok(mut mac) = Hmac::<Sha1>::new_from_slice(salt) else {
+                    return false;
+                };
+                mac.update(name.as_bytes());
+                mac.verify_slice(hash).is_ok()
+            }
+        }
+    }
+}
+
+/// Like OpenSSH, a server that isn't on the default port is written `[host]:port` in known_hosts,
+/// so its entries don't match the same host on another port
+fn host_name(host: &str, port: Option<u16>) -> String {
+    match port {
+        Some(port) if port != 22 => format!("[{host}]:{port}"),
+        _ => host.to_string(),
+    }
+}
+
+/// Port of an `ssh://` url, the scp-like `user@host:path` urls are always on the default port
+pub fn ssh_port(url: &str) -> Option<u16> {
+    url::Url::parse(url)
+        .ok()
+        .filter(|url| url.scheme().contains("ssh"))
+        .and_then(|url| url.port())
+}
+
+#[derive(Debug, Clone)]
+struct KnownHost {
+    patterns: Vec<HostPattern>,
+    revoked: bool,
+    key_type: String,
+    key: Vec<u8>,
+}
+
+impl FromStr for KnownHost {
+    type Err = ();
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut fields = line.split_whitespace();
+        let mut hosts = fields.next().ok_or(())?;
+        let mut revoked = false;
+        if hosts.starts_with('@') {
+            // Certificate authorities are not supported, only plain keys
+            revoked = match hosts {
+                "@revoked" => true,
+                _ => return Err(()),
+            };
+            hosts = fields.next().ok_or(())?;
+        }
+        let key_type = fields.next().ok_or(())?.to_string();
+        let key = STANDARD.decode(fields.next().ok_or(())?).map_err(|_| ())?;
+
+        let patterns = if let Some(hashed) = hosts.strip_prefix("|1|") {
+            let (salt, hash) = hashed.split_once('|').ok_or(())?;
+            vec![HostPattern::Hashed {
+                salt: STANDARD.decode(salt).map_err(|_| ())?,
+                hash: STANDARD.decode(hash).map_err(|_| ())?,
+            }]
+        } else {
+            hosts
+                .split(',')
+                // Negated and wildcard patterns are ignored, we only trust exact hosts
+                .filter(|h| !h.starts_with('!') && !h.contains(['*', '?']))
+                .map(|h| HostPattern::Plain(h.to_string()))
+                .collect()
+        };
+
+        Ok(Self {
+            patterns,
+            revoked,
+            key_type,
+            key,
+        })
+    }
+}
+
+/// Parsed content of an OpenSSH known_hosts file
+#[derive(Debug, Clone, Default)]
+pub struct KnownHosts(Vec<KnownHost>);
+
+impl FromStr for KnownHosts {
+    type Err = ();
+
+    fn from_str(content: &str) -> Result<Self, Self::Err> {
+        let hosts = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| {
+                let known_host = line.parse().ok();
+                if known_host.is_none() {
+                    warn!("ignoring unsupported known_hosts line: {line}");
+                }
+                known_host
+            })
+            .collect();
+        Ok(Self(hosts))
+    }
+}
+
+/// Host key verification used by the git remote callbacks.
+///
+/// Pinned fingerprints take precedence over the known_hosts file.
+/// A failure is kept so it can be reported instead of the generic git error.
+#[derive(Debug, Default)]
+pub struct HostKeyVerification {
+    known_hosts_path: PathBuf,
+    known_hosts: KnownHosts,
+    pinned_fingerprints: Vec<(String, String)>,
+    trust_on_first_use: bool,
+    failure: RefCell<Option<HostKeyError>>,
+}
+
+impl HostKeyVerification {
+    pub fn new(
+        known_hosts_path: PathBuf,
+        pinned_fingerprints: Vec<(String, String)>,
+        trust_on_first_use: bool,
+    ) -> io::Result<Self> {
+        let known_hosts = match fs::read_to_string(&known_hosts_path) {
+            Ok(content) => content.parse().unwrap_or_default(),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => KnownHosts::default(),
+            Err(error) => return Err(error),
+        };
+
+        Ok(Self {
+            known_hosts_path,
+            known_hosts,
+            pinned_fingerprints,
+            trust_on_first_use,
+            failure: RefCell::default(),
+        })
+    }
+
+    /// From the `[ssh]` section of the server configuration
+    pub fn from_config() -> io::Result<Self> {
+        let config = config::get();
+        Self::new(
+            config.ssh.known_hosts_path.clone(),
+            config.pinned_fingerprints(),
+            config.ssh.trust_on_first_use,
+        )
+    }
+
+    /// The error that made the last certificate check fail, if any
+    pub fn take_failure(&self) -> Option<HostKeyError> {
+        self.failure.take()
+    }
+
+    /// Callback for [git2::RemoteCallbacks::certificate_check], `port` is the one of the remote url
+    pub fn certificate_check(
+        &self,
+        cert: &Cert<'_>,
+        host: &str,
+        port: Option<u16>,
+    ) -> Result<CertificateCheckStatus, git2::Error> {
+        let Some(hostkey) = cert.as_hostkey() else {
+            // Not ssh, let libgit2 validate the x509 certificate as usual
+            return Ok(CertificateCheckStatus::CertificatePassthrough);
+        };
+        let result = match (hostkey.hostkey_type(), hostkey.hostkey()) {
+            (Some(key_type), Some(key)) => self.verify(host, port, key_type.name(), key),
+            _ => Err(HostKeyError::MissingHostKey {
+                host: host_name(host, port),
+            }),
+        };
+
+        result
+            .map(|_| CertificateCheckStatus::CertificateOk)
+            .map_err(|error| {
+                let git_error = git2::Error::from_str(&error.to_string());
+                self.failure.replace(Some(error));
+                git_error
+            })
+    }
+
+    pub fn verify(
+        &self,
+        host: &str,
+        port: Option<u16>,
+        key_type: &str,
+        key: &[u8],
+    ) -> Result<(), HostKeyError> {
+        let fingerprint = fingerprint(key);
+        let name = host_name(host, port);
+
+        let mut pins = self
+            .pinned_fingerprints
+            .iter()
+            .filter(|(pinned_host, _)| pinned_host.eq_ignore_ascii_case(&name))
+            .peekable();
+        if pins.peek().is_some() {
+            return if pins.any(|(_, pinned)| *pinned == fingerprint) {
+                Ok(())
+            } else {
+                Err(HostKeyError::Mismatch {
+                    host: name.clone(),
+                    fingerprint,
+                })
+            };
+        }
+
+        let entries: Vec<_> = self
+            .known_hosts
+            .0
+            .iter()
+            .filter(|entry| entry.patterns.iter().any(|p| p.matches(&name)))
+            .collect();
+        let same_key = |entry: &KnownHost| entry.key_type == key_type && entry.key == key;
+        if entries.iter().any(|e| e.revoked && same_key(e)) {
+            Err(HostKeyError::Revoked {
+                host: name.clone(),
+                fingerprint,
+            })
+        } else if entries.iter().any(|e| !e.revoked && same_key(e)) {
+            Ok(())
+        } else if entries.iter().any(|e| !e.revoked) {
+            Err(HostKeyError::Mismatch {
+                host: name.clone(),
+                fingerprint,
+            })
+        } else if self.trust_on_first_use {
+            self.trust(&name, key_type, key)
+                .map_err(|source| HostKeyError::TrustOnFirstUse {
+                    host: name.clone(),
+                    source,
+                })?;
+            warn!("trusting {name} on first use, key fingerprint: {fingerprint}");
+            Ok(())
+        } else {
+            Err(HostKeyError::UnknownHost {
+                host: name.clone(),
+                fingerprint,
+            })
+        }
+    }
+
+    /// Append the host key to the known_hosts file, so the next connections are verified against it
+    fn trust(&self, host: &str, key_type: &str, key: &[u8]) -> io::Result<()> {
+        if let Some(parent) = self.known_hosts_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.known_hosts_path)?;
+        writeln!(file, "{host} {key_type} {}", STANDARD.encode(key))?;
+        info!("added {host} to {}", self.known_hosts_path.display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_key_verification() {
+        let dir = tempfile::tempdir().unwrap();
+        let known_hosts_path = dir.path().join("known_hosts");
+        // key: "known-key", hashed entry salt: "salt" for host "hashed.example"
+        fs::write(
+            &known_hosts_path,
+            "# comment\n\
+             example.com,[other.example]:2222 ssh-ed25519 a25vd24ta2V5\n\
+             |1|c2FsdA==|XD6hQItdxr+adoPBY8G1NHlCXsA= ssh-ed25519 a25vd24ta2V5\n",
+        )
+        .unwrap();
+
+        let verification =
+            HostKeyVerification::new(known_hosts_path.clone(), vec![], false).unwrap();
+        assert!(verification
+            .verify("example.com", None, "ssh-ed25519", b"known-key")
+            .is_ok());
+        assert!(verification
+            .verify("other.example", Some(2222), "ssh-ed25519", b"known-key")
+            .is_ok());
+        // The entries of a port don't match another one, nor the default port
+        assert!(matches!(
+            verification.verify("other.example", None, "ssh-ed25519", b"known-key"),
+            Err(HostKeyError::UnknownHost { .. })
+        ));
+        assert!(matches!(
+            verification.verify("example.com", Some(2222), "ssh-ed25519", b"known-key"),
+            Err(HostKeyError::UnknownHost { .. })
+        ));
+        assert!(verification
+            .verify("example.com", Some(22), "ssh-ed25519", b"known-key")
+            .is_ok());
+        assert!(verification
+            .verify("hashed.example", None, "ssh-ed25519", b"known-key")
+            .is_ok());
+        assert!(matches!(
+            verification.verify("example.com", None, "ssh-ed25519", b"spoofed-key"),
+            Err(HostKeyError::Mismatch { .. })
+        ));
+        assert!(matches!(
+            verification.verify("unknown.example", None, "ssh-ed25519", b"known-key"),
+            Err(HostKeyError::UnknownHost { .. })
+        ));
+
+        let pinned = vec![("example.com".to_string(), fingerprint(b"pinned-key"))];
+        let verification =
+            HostKeyVerification::new(known_hosts_path.clone(), pinned, false).unwrap();
+        assert!(verification
+            .verify("example.com", None, "ssh-ed25519", b"pinned-key")
+            .is_ok());
+        assert!(verification
+            .verify("example.com", None, "ssh-ed25519", b"known-key")
+            .is_err());
+
+        // Pinned for a port, like the known_hosts entries
+        let pinned = vec![(
+            "[git.example.com]:2222".to_string(),
+            fingerprint(b"pinned-key"),
+        )];
+        let verification =
+            HostKeyVerification::new(known_hosts_path.clone(), pinned, false).unwrap();
+        assert!(verification
+            .verify("git.example.com", Some(2222), "ssh-ed25519", b"pinned-key")
+            .is_ok());
+        assert!(matches!(
+            verification.verify("git.example.com", None, "ssh-ed25519", b"pinned-key"),
+            Err(HostKeyError::UnknownHost { .. })
+        ));
+        assert!(matches!(
+            verification.verify("git.example.com", Some(2200), "ssh-ed25519", b"pinned-key"),
+            Err(HostKeyError::UnknownHost { .. })
+        ));
+
+        let verification =
+            HostKeyVerification::new(known_hosts_path.clone(), vec![], true).unwrap();
+        assert!(verification
+            .verify("new.example", None, "ssh-ed25519", b"new-key")
+            .is_ok());
+        let verification = HostKeyVerification::new(known_hosts_path, vec![], false).unwrap();
+        assert!(verification
+            .verify("new.example", None, "ssh-ed25519", b"new-key")
+            .is_ok());
+
+        assert_eq!(
+            ssh_port("ssh://git@example.com:2222/org/repo.git"),
+            Some(2222)
+        );
+        assert_eq!(ssh_port("ssh://git@example.com/org/repo.git"), None);
+        assert_eq!(ssh_port("git@example.com:org/repo.git"), None);
+    }
+}
@@ -1,58 +1,202 @@
 #![warn(unused_crate_dependencies)]
 #![warn(clippy::dbg_macro)]
 #![warn(missing_debug_implementations)]
-#![feature(lazy_cell)]
 
+mod api_keys;
+mod artifacts;
+mod audit;
+mod backup;
 mod compare;
+mod deploy_keys;
 mod error;
+mod filter;
 mod git;
+mod github_oidc;
+mod groups;
+mod jwks;
+mod known_hosts;
+mod migrations;
 mod model;
+mod notify;
+mod oidc_login;
+mod path_mapping;
+mod quality_gate;
+mod raw_reports;
+mod retention;
+mod s3;
+mod sled_backend;
+mod sqlite_backend;
+mod users;
 mod utils;
+mod viewer;
 
 mod config;
 mod dashboard;
 mod report;
+mod repository_config;
 mod routes;
+mod sources;
 mod storage;
 #[cfg(test)]
-mod tests;
+mod test_utils;
+
+use std::future::ready;
 
 use actix_files::Files;
 use actix_web::{
-    guard,
+    dev::Service,
     middleware::Logger,
     web::{self},
     App, HttpServer,
 };
+use futures_util::future::Either;
+
+const USAGE: &str = "unknown command, expected:
+  copy-storage <sled|sqlite> <sled|sqlite>
+  export <archive.tar.gz> [--html]
+  import <archive.tar.gz> [--on-conflict fail|skip|overwrite]";
+
+/// Storage of the configuration, after the migrations
+fn open_storage() -> anyhow::Result<storage::TypedDb> {
+    let output = &config::get().output;
+    let storage = storage::TypedDb::open(output, output.storage_backend)?;
+    migrations::run(&storage)?;
+    Ok(storage)
+}
+
+/// - `copy-storage <from> <to>`, copy the records between the `sled` and `sqlite` databases of
+///   the output configuration, the destination must be empty
+/// - `export <archive> [--html]` and `import <archive> [--on-conflict <fail|skip|overwrite>]`,
+///   see [backup]
+fn run_command(command: &str, args: Vec<String>) -> anyhow::Result<()> {
+    let config = config::get();
+    match (command, args.as_slice()) {
+        ("export", [archive, options @ ..]) if options.iter().all(|option| option == "--html") => {
+            let file = std::fs::File::create(archive)?;
+            let report = backup::export(
+                &open_storage()?,
+                &artifacts::Artifacts::open(config),
+                file,
+                !options.is_empty(),
+            )?;
+            log::info!("Wrote {archive}: {report}");
+            Ok(())
+        }
+        ("import", [archive, options @ ..]) => {
+            let on_conflict = match options {
+                [] => backup::OnConflict::default(),
+                [option, value] if option == "--on-conflict" => value.parse()?,
+                _ => anyhow::bail!(USAGE),
+            };
+            let report = backup::import(
+                &open_storage()?,
+                &artifacts::Artifacts::open(config),
+                std::path::Path::new(archive),
+                on_conflict,
+            )?;
+            log::info!("Imported {archive}: {report}");
+            Ok(())
+        }
+        ("copy-storage", [from, to]) => {
+            let source = storage::TypedDb::open(&config.output, from.parse()?)?;
+            let destination = storage::TypedDb::open(&config.output, to.parse()?)?;
+            let copied = migrations::copy(&source, &destination)?;
+            log::info!("Copied {copied} records from {from} to {to}");
+            Ok(())
+        }
+        _ => anyhow::bail!(USAGE),
+    }
+}
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     utils::init_environment();
+    let config = config::get();
 
-    let api_key: &'static str =
-        Box::leak(Box::new(std::env::var("API_KEY").expect("API_KEY in env")));
-    let report_persistance = storage::TypedDb::new(sled::open(config::SLED_DIR)?);
+    if config.api_key.is_none() {
+        log::warn!("No API_KEY configured, only the api keys stored in the database are accepted");
+    }
+    if let Some(command) = std::env::args().nth(1) {
+        if let Err(e) = run_command(&command, std::env::args().skip(2).collect()) {
+            log::error!("{command} failed: {e:#}");
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    let report_persistance = storage::TypedDb::open(&config.output, config.output.storage_backend)
+        .expect("opening the database");
+    migrations::run(&report_persistance).expect("migrating the database");
+    let artifacts = artifacts::Artifacts::open(config);
+    let migrated = report::migrate_html_layout(&report_persistance, artifacts.html.as_ref())
+        .expect("migrating the HTML reports");
+    if migrated > 0 {
+        log::info!(
+            "Moved the HTML reports of {migrated} branches to the directory of their upload"
+        );
+    }
+    let compressed = raw_reports::migrate(&report_persistance, &artifacts)
+        .expect("compressing the JSON reports");
+    if compressed > 0 {
+        log::info!("Compressed {compressed} JSON reports");
+    }
+    let renamed = report::migrate_names(&report_persistance, &artifacts, &config.output)
+        .expect("renaming the reports");
+    if renamed > 0 {
+        log::info!("Renamed the reports of {renamed} branches");
+    }
+    retention::spawn_gc(report_persistance.clone(), artifacts.clone());
 
     HttpServer::new(move || {
         App::new()
             .wrap(Logger::new(
                 r#"%{r}a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T"#,
             ))
-            .app_data(web::JsonConfig::default().limit(1024 * 1024 * 1000))
+            .app_data(web::JsonConfig::default().limit(config.json_limit))
             .app_data(web::Data::new(report_persistance.clone()))
+            .app_data(web::Data::new(artifacts.clone()))
+            .service(routes::login_form)
+            .service(routes::login)
+            .service(routes::logout)
+            .service(routes::oidc_login)
+            .service(routes::oidc_callback)
+            .service(routes::list_reports)
+            .service(routes::audit_page)
             .service(web::scope("/").service(routes::dashboard))
             .service(
                 web::scope("/report")
-                    .guard(guard::Header("x-api-key", api_key))
-                    .service(routes::new_report),
+                    .service(routes::new_report)
+                    .service(routes::new_report_with_sources)
+                    .service(routes::delete_reports),
+            )
+            .service(
+                web::scope("/admin")
+                    .service(routes::create_api_key)
+                    .service(routes::list_api_keys)
+                    .service(routes::revoke_api_key)
+                    .service(routes::create_user)
+                    .service(routes::list_users)
+                    .service(routes::delete_user)
+                    .service(routes::list_audit)
+                    .service(routes::collect_garbage)
+                    .service(routes::export_backup)
+                    .service(routes::import_backup),
+            )
+            .service(
+                web::scope("/view")
+                    .wrap_fn(|req, srv| match viewer::authorize_view(&req) {
+                        Ok(()) => Either::Left(srv.call(req)),
+                        Err(error) => Either::Right(ready(Err(error.into()))),
+                    })
+                    .service(routes::view_latest)
+                    .service(routes::view_legacy)
+                    .service(routes::view_artifact),
             )
-            .service(Files::new("/view", config::HTML_REPORTS_DIR))
             .service(
                 Files::new("/css", "templates/")
                     .path_filter(|p, _| p.extension().map(|e| e == "css") == Some(true)),
             )
     })
-    .bind(("0.0.0.0", 8080))?
+    .bind((config.bind_address.as_str(), config.port))?
     .run()
     .await
 }
@@ -0,0 +1,494 @@
+use anyhow::{anyhow, bail};
+use chrono::NaiveDateTime;
+use log::{info, warn};
+use serde::Deserialize;
+
+use crate::{
+    api_keys::{ApiKey, Permission},
+    audit::AuditEntry,
+    compare::Comparison,
+    oidc_login::OidcLoginState,
+    raw_reports::{Baseline, RawReport},
+    report::{BaseBranch, ReportHistory, ReportRepository},
+    storage::{Backend, Encoding, Record, TypedDb},
+    users::{Session, User},
+};
+
+/// Tree of the schema version of each collection, once all its records are upgraded, and of
+/// the version of each index once built
+const SCHEMA_TREE: &str = "schema";
+
+/// Marked in the [SCHEMA_TREE] once [backfill_report_repositories] ran
+const REPORT_REPOSITORIES_BACKFILL: &str = "report_repositories_backfill";
+
+/// A stored type, with the `std::any::type_name` that prefixed its keys in the default tree
+/// before each type had its own collection
+#[derive(Debug)]
+struct Collection {
+    name: &'static str,
+    version: u32,
+    /// None for the types added since
+    legacy_prefix: Option<&'static str>,
+    indexes: &'static [&'static str],
+    rebuild_indexes: fn(&TypedDb) -> anyhow::Result<usize>,
+    copy: fn(&TypedDb, &TypedDb) -> anyhow::Result<usize>,
+}
+
+impl Collection {
+    const fn of<T: Record>(legacy_prefix: &'static str) -> Self {
+        Self {
+            legacy_prefix: Some(legacy_prefix),
+            ..Self::new::<T>()
+        }
+    }
+
+    const fn new<T: Record>() -> Self {
+        Self {
+            name: T::COLLECTION,
+            version: T::VERSION,
+            legacy_prefix: None,
+            indexes: T::INDEXES,
+            rebuild_indexes: TypedDb::rebuild_indexes::<T>,
+            copy: TypedDb::copy_collection::<T>,
+        }
+    }
+}
+
+const COLLECTIONS: &[Collection] = &[
+    Collection::of::<ApiKey>("llvm_cov_host::api_keys::ApiKey"),
+    Collection::of::<AuditEntry>("llvm_cov_host::audit::AuditEntry"),
+    Collection::of::<BaseBranch>("llvm_cov_host::report::BaseBranch"),
+    Collection::of::<Baseline>("llvm_cov_host::model::Report"),
+    Collection::of::<OidcLoginState>("llvm_cov_host::oidc_login::OidcLoginState"),
+    Collection::new::<RawReport>(),
+    Collection::of::<ReportHistory>("llvm_cov_host::report::ReportHistory"),
+    Collection::of::<ReportRepository>("llvm_cov_host::report::ReportRepository"),
+    Collection::of::<Session>("llvm_cov_host::users::Session"),
+    Collection::of::<User>("llvm_cov_host::users::User"),
+];
+
+/// Upgrade of the records of a collection from the previous version to `version`
+#[derive(Debug)]
+struct Migration {
+    collection: &'static str,
+    version: u32,
+    /// From the serialized record of the previous version, in the encoding of the backend
+    upgrade: fn(Encoding, &[u8]) -> anyhow::Result<Vec<u8>>,
+}
+
+/// In the order they apply, a new version of a [Record] needs its migration here
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        collection: ReportHistory::COLLECTION,
+        version: 2,
+        upgrade: report_history_v2,
+    },
+    Migration {
+        collection: ApiKey::COLLECTION,
+        version: 2,
+        upgrade: api_key_v2,
+    },
+];
+
+/// Layout of [ReportHistory] version 1, before the commit of the upload
+#[derive(Debug, Deserialize)]
+struct ReportHistoryV1 {
+    repository_name: String,
+    branch: String,
+    name: String,
+    comparison: Comparison,
+    date: NaiveDateTime,
+}
+
+fn report_history_v2(encoding: Encoding, value: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let v1: ReportHistoryV1 = encoding.decode(value)?;
+    let v2 = ReportHistory {
+        repository_name: v1.repository_name,
+        branch: v1.branch,
+        commit: None,
+        name: v1.name,
+        comparison: v1.comparison,
+        date: v1.date,
+    };
+    encoding.encode(&v2)
+}
+
+/// Layout of [ApiKey] version 1, before the git hosts of the key
+#[derive(Debug, Deserialize)]
+struct ApiKeyV1 {
+    id: String,
+    name: String,
+    repositories: Vec<String>,
+    permissions: Vec<Permission>,
+    created_at: NaiveDateTime,
+    revoked_at: Option<NaiveDateTime>,
+    hash: Vec<u8>,
+}
+
+/// The keys keep uploading from any host, they should be replaced by keys with their git hosts
+fn api_key_v2(encoding: Encoding, value: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let v1: ApiKeyV1 = encoding.decode(value)?;
+    if v1.revoked_at.is_none() && v1.permissions.contains(&Permission::Upload) {
+        warn!(
+            "the api key {} ({}) can upload from any git host, replace it with a key limited to its git_hosts",
+            v1.name, v1.id
+        );
+    }
+    let v2 = ApiKey {
+        id: v1.id,
+        name: v1.name,
+        repositories: v1.repositories,
+        git_hosts: vec!["*".to_string()],
+        permissions: v1.permissions,
+        created_at: v1.created_at,
+        revoked_at: v1.revoked_at,
+        hash: v1.hash,
+    };
+    encoding.encode(&v2)
+}
+
+/// Tree of the sled records before each type had its own collection, empty in the other backends
+const LEGACY_TREE: &str = "__sled__default";
+
+/// Move the records of the default tree to the collection, they are the version 1 of its layout
+fn import_legacy(backend: &dyn Backend, collection: &Collection) -> anyhow::Result<usize> {
+    let Some(prefix) = collection.legacy_prefix else {
+        return Ok(0);
+    };
+    let mut imported = 0;
+    let prefix = prefix.as_bytes();
+    for res in backend.tree_scan(LEGACY_TREE, prefix, false)? {
+        let (legacy_key, value) = res?;
+        let key = std::str::from_utf8(&legacy_key[prefix.len()..])?;
+        // Copied before the removal, an interrupted import starts again from the remaining records
+        backend.put(collection.name, key, 1, &value)?;
+        backend.tree_remove(LEGACY_TREE, &legacy_key)?;
+        imported += 1;
+    }
+    Ok(imported)
+}
+
+fn upgrade(backend: &dyn Backend, migration: &Migration) -> anyhow::Result<usize> {
+    let mut upgraded = 0;
+    for res in backend.records(migration.collection, false)? {
+        let (key, (version, value)) = res?;
+        // The records of an interrupted migration are already upgraded
+        if version + 1 != migration.version {
+            continue;
+        }
+        let value = (migration.upgrade)(backend.encoding(), &value).map_err(|e| {
+            anyhow!(
+                "upgrading the {} record {key:?} to version {}: {e:#}",
+                migration.collection,
+                migration.version
+            )
+        })?;
+        backend.put(migration.collection, &key, migration.version, &value)?;
+        upgraded += 1;
+    }
+    Ok(upgraded)
+}
+
+fn stored_version(backend: &dyn Backend, name: &str) -> anyhow::Result<Option<u32>> {
+    let Some(version) = backend.tree_get(SCHEMA_TREE, name.as_bytes())? else {
+        return Ok(None);
+    };
+    Ok(Some(u32::from_be_bytes(version.as_slice().try_into()?)))
+}
+
+fn set_version(backend: &dyn Backend, name: &str, version: u32) -> anyhow::Result<()> {
+    backend.tree_insert(SCHEMA_TREE, name.as_bytes(), &version.to_be_bytes())
+}
+
+fn is_empty(backend: &dyn Backend, collection: &Collection) -> anyhow::Result<bool> {
+    Ok(backend.records(collection.name, false)?.next().is_none())
+}
+
+/// Upgrade a serialized record of `version` to the current version of its collection, for the
+/// records of a backup
+pub fn upgrade_record(
+    collection: &str,
+    version: u32,
+    encoding: Encoding,
+    mut value: Vec<u8>,
+) -> anyhow::Result<Vec<u8>> {
+    let Some(current) = COLLECTIONS.iter().find(|c| c.name == collection) else {
+        bail!("unknown collection {collection}");
+    };
+    if version > current.version {
+        bail!(
+            "the {collection} records have the schema version {version}, from a more recent llvm-cov-host, instead of {}",
+            current.version
+        );
+    }
+    let migrations = MIGRATIONS
+        .iter()
+        .filter(|migration| migration.collection == collection)
+        .filter(|migration| migration.version > version);
+    for migration in migrations {
+        value = (migration.upgrade)(encoding, &value)?;
+    }
+    Ok(value)
+}
+
+/// Upgrade the stored records to the current versions, before the server starts
+pub fn run(storage: &TypedDb) -> anyhow::Result<()> {
+    let backend = storage.backend();
+    // Checked first, so a database of a more recent version isn't partially upgraded
+    for collection in COLLECTIONS {
+        if let Some(version) = stored_version(backend, collection.name)? {
+            if version > collection.version {
+                bail!(
+                    "the {} records have the schema version {version}, from a more recent llvm-cov-host, instead of {}",
+                    collection.name,
+                    collection.version
+                );
+            }
+        }
+    }
+    for collection in COLLECTIONS {
+        let mut version = match stored_version(backend, collection.name)? {
+            Some(version) => version,
+            None => {
+                let imported = import_legacy(backend, collection)?;
+                if imported > 0 {
+                    info!("Imported {imported} {} records", collection.name);
+                }
+                // Only the import writes to a collection without a schema version
+                if is_empty(backend, collection)? {
+                    collection.version
+                } else {
+                    1
+                }
+            }
+        };
+        let current = version;
+        let migrations = MIGRATIONS
+            .iter()
+            .filter(|migration| migration.collection == collection.name)
+            .filter(|migration| migration.version > current);
+        let mut upgraded = false;
+        for migration in migrations {
+            let count = upgrade(backend, migration)?;
+            info!(
+                "Upgraded {count} {} records to version {}",
+                collection.name, migration.version
+            );
+            version = migration.version;
+            set_version(backend, collection.name, version)?;
+            upgraded = true;
+        }
+        if version != collection.version {
+            bail!(
+                "no migration of the {} records from version {version} to {}",
+                collection.name,
+                collection.version
+            );
+        }
+        set_version(backend, collection.name, version)?;
+
+        // The indexes are marked in the schema once built, a new index is built from the records,
+        // and the upgraded records are indexed again
+        let mut missing_index = upgraded;
+        for index in collection.indexes {
+            missing_index |= stored_version(backend, index)?.is_none();
+        }
+        if missing_index && !collection.indexes.is_empty() {
+            let indexed = (collection.rebuild_indexes)(storage)?;
+            info!("Indexed {indexed} {} records", collection.name);
+            for index in collection.indexes {
+                set_version(backend, index, 1)?;
+            }
+        }
+    }
+
+    if stored_version(backend, REPORT_REPOSITORIES_BACKFILL)?.is_none() {
+        let count = backfill_report_repositories(storage)?;
+        info!("Added {count} {} records", ReportRepository::COLLECTION);
+        set_version(backend, REPORT_REPOSITORIES_BACKFILL, 1)?;
+    }
+    backend.flush()?;
+    Ok(())
+}
+
+/// Add the [ReportRepository] of the branches uploaded before it existed, they are only in the
+/// history. Also used by the import of the backups written before
+pub fn backfill_report_repositories(storage: &TypedDb) -> anyhow::Result<usize> {
+    let mut added = 0;
+    for report in storage.iter::<ReportHistory>()? {
+        let report = report?;
+        if storage.get::<ReportRepository>(&report.name)?.is_none() {
+            storage.insert(
+                &report.name,
+                &ReportRepository {
+                    repository_name: report.repository_name,
+                },
+            )?;
+            added += 1;
+        }
+    }
+    Ok(added)
+}
+
+/// Copy the records to an empty database, after upgrading them, to change the storage backend
+pub fn copy(source: &TypedDb, destination: &TypedDb) -> anyhow::Result<usize> {
+    run(source)?;
+    let (from, to) = (source.backend(), destination.backend());
+    let has_schema = to.tree_scan(SCHEMA_TREE, b"", false)?.next().is_some();
+    for collection in COLLECTIONS {
+        if has_schema || !is_empty(to, collection)? {
+            bail!("the destination database isn't empty");
+        }
+    }
+    let mut copied = 0;
+    for collection in COLLECTIONS {
+        let count = (collection.copy)(source, destination)?;
+        info!("Copied {count} {} records", collection.name);
+        copied += count;
+    }
+    // The versions and the markers of the indexes, maintained by the copy
+    for res in from.tree_scan(SCHEMA_TREE, b"", false)? {
+        let (key, value) = res?;
+        to.tree_insert(SCHEMA_TREE, &key, &value)?;
+    }
+    to.advance_ids(source.generate_id()?)?;
+    to.flush()?;
+    Ok(copied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        audit::{AuditAction, AuditQuery},
+        report::report_repository,
+        sqlite_backend::SqliteBackend,
+        test_utils::{load_snapshot, memory_db},
+    };
+
+    #[test]
+    fn test_storage_snapshots() {
+        for snapshot in ["storage-legacy", "storage-v1"] {
+            let storage = load_snapshot(snapshot);
+            run(&storage).unwrap();
+            // Already migrated
+            run(&storage).unwrap();
+
+            let history: Vec<ReportHistory> = storage.get_all().unwrap();
+            assert_eq!(history.len(), 2, "{snapshot}");
+            assert_eq!(history[0].branch, "feature");
+            assert_eq!(history[0].name, "greefine-app-feature");
+            assert_eq!(history[0].comparison.base, Some(80.5));
+            assert_eq!(history[0].commit, None);
+            assert_eq!(history[1].upload_id(), "20240502T100000123456789");
+            let key = history[1].date.to_string();
+            assert!(storage.get::<ReportHistory>(&key).unwrap().is_some());
+            assert_eq!(
+                report_repository(&storage, "greefine-app-main").unwrap(),
+                Some("GreeFine/app".to_string())
+            );
+            let base_branch = storage.get::<BaseBranch>("GreeFine/app").unwrap().unwrap();
+            assert_eq!(base_branch.branch, "main");
+            let baseline = storage
+                .get::<Baseline>("greefine-app/main")
+                .unwrap()
+                .unwrap();
+            assert_eq!(baseline.data[0].files[0].filename, "/repo/src/lib.rs");
+
+            let keys = ApiKey::list(&storage).unwrap();
+            assert_eq!(keys[0].name, "ci");
+            // Stored before the Read permission was added
+            let permissions = match snapshot {
+                "storage-legacy" => vec![Permission::Upload],
+                _ => vec![Permission::Upload, Permission::Delete, Permission::Admin],
+            };
+            assert_eq!(keys[0].permissions, permissions, "{snapshot}");
+            assert_eq!(keys[0].git_hosts, vec!["*".to_string()]);
+            let user = User::authenticate(&storage, "alice", "correct horse").unwrap();
+            assert_eq!(user.unwrap().groups, vec!["dev".to_string()]);
+            let sessions: Vec<Session> = storage.get_all().unwrap();
+            assert_eq!(sessions[0].username, "alice");
+            let audit = AuditEntry::page(&storage, &AuditQuery::default()).unwrap();
+            assert_eq!(audit.entries[0].commit.as_deref(), Some("0123456789abcdef"));
+            assert_eq!(audit.entries[0].action, AuditAction::ReportUpload);
+
+            // The records moved to their collections, and were indexed
+            let legacy = storage.backend().tree_scan("__sled__default", b"", false);
+            assert!(legacy.unwrap().next().is_none(), "{snapshot}");
+            let latest = ReportHistory::latest_per_branch(&storage, None).unwrap();
+            assert_eq!(latest.len(), 2);
+        }
+
+        // A database of a more recent version isn't modified
+        let storage = load_snapshot("storage-v1");
+        let backend = storage.backend();
+        backend
+            .tree_insert("schema", b"users", &99u32.to_be_bytes())
+            .unwrap();
+        assert!(run(&storage).is_err());
+        let version = backend.tree_get("schema", b"report_history").unwrap();
+        assert_eq!(version.unwrap(), 1u32.to_be_bytes());
+    }
+
+    #[test]
+    fn test_copy_storage() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("records.sqlite");
+
+        let source = load_snapshot("storage-v1");
+        for _ in 0..100 {
+            source.generate_id().unwrap();
+        }
+        let sqlite = TypedDb::with_backend(SqliteBackend::open(&path).unwrap());
+        let copied = copy(&source, &sqlite).unwrap();
+        assert_eq!(copied, 10);
+        // Copied at the current versions, with the indexes
+        run(&sqlite).unwrap();
+        let check = |storage: &TypedDb| {
+            let history: Vec<ReportHistory> = storage.get_all().unwrap();
+            assert_eq!(history.len(), 2);
+            assert_eq!(history[0].branch, "feature");
+            let latest = ReportHistory::latest_per_branch(storage, None).unwrap();
+            assert_eq!(latest.len(), 2);
+            let base_branch = storage.get::<BaseBranch>("GreeFine/app").unwrap().unwrap();
+            assert_eq!(base_branch.branch, "main");
+            let user = User::authenticate(storage, "alice", "correct horse").unwrap();
+            assert_eq!(user.unwrap().groups, vec!["dev".to_string()]);
+            let audit = AuditEntry::page(storage, &AuditQuery::default()).unwrap();
+            assert_eq!(audit.entries[0].commit.as_deref(), Some("0123456789abcdef"));
+            // The new ids come after the ones of the source
+            assert!(storage.generate_id().unwrap() >= 100);
+        };
+        check(&sqlite);
+
+        // The records can be queried in SQL
+        let connection = rusqlite::Connection::open(&path).unwrap();
+        let mut statement = connection
+            .prepare(
+                "SELECT json_extract(value, '$.branch') FROM records
+                 WHERE collection = 'report_history' ORDER BY key",
+            )
+            .unwrap();
+        let branches: Vec<String> = statement
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(branches, ["main", "feature"]);
+
+        // Only to an empty database
+        assert!(copy(&source, &sqlite).is_err());
+
+        let sled = memory_db();
+        assert_eq!(copy(&sqlite, &sled).unwrap(), copied);
+        run(&sled).unwrap();
+        check(&sled);
+        // Without generating the ids up to it
+        let next = 1 << 40;
+        sled.backend().advance_ids(next).unwrap();
+        sled.backend().advance_ids(100).unwrap();
+        let id = sled.generate_id().unwrap();
+        assert!(id >= next);
+        assert!(sled.generate_id().unwrap() > id);
+    }
+}
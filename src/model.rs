@@ -1,4 +1,9 @@
 use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+
+/// A section of the export the server doesn't read, like the regions that are most of its size,
+/// kept as the uploaded JSON. Absent when it wasn't uploaded.
+type Opaque = Option<Box<RawValue>>;
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Branch {
@@ -44,20 +49,54 @@ pub struct Summary {
     pub instantiations: Instantiations,
     pub lines: Lines,
     pub regions: Regions,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mcdc: Opaque,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct File {
     pub filename: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub segments: Opaque,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub branches: Opaque,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mcdc_records: Opaque,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expansions: Option<Vec<Expansion>>,
     pub summary: Summary,
 }
 
+/// Code of another file expanded in a file, like a macro
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Expansion {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filenames: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_region: Opaque,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_regions: Opaque,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub branches: Opaque,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Function {
     pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub count: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub regions: Opaque,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub branches: Opaque,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mcdc_records: Opaque,
+    /// Files of the regions of the function, the function is filtered out with them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filenames: Option<Vec<String>>,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct CargoLlvmCov {
     pub manifest_path: String,
     pub version: String,
@@ -70,7 +109,7 @@ pub struct Data {
     pub totals: Summary,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct Report {
     pub cargo_llvm_cov: CargoLlvmCov,
     pub data: Vec<Data>,
@@ -78,3 +117,69 @@ pub struct Report {
     pub kind: String,
     pub version: String,
 }
+
+/// Percentage like llvm-cov computes it, 0 when there is nothing to cover
+fn percent(covered: i64, count: i64) -> f64 {
+    if count == 0 {
+        0f64
+    } else {
+        covered as f64 * 100f64 / count as f64
+    }
+}
+
+impl Summary {
+    /// Totals of the files summaries, as found in [Data::totals]
+    pub fn total<'a>(summaries: impl IntoIterator<Item = &'a Summary>) -> Self {
+        let mut total = Summary {
+            branches: Branch {
+                count: 0,
+                covered: 0,
+                notcovered: 0,
+                percent: 0f64,
+            },
+            functions: Functions {
+                count: 0,
+                covered: 0,
+                percent: 0f64,
+            },
+            instantiations: Instantiations {
+                count: 0,
+                covered: 0,
+                percent: 0f64,
+            },
+            lines: Lines {
+                count: 0,
+                covered: 0,
+                percent: 0f64,
+            },
+            regions: Regions {
+                count: 0,
+                covered: 0,
+                notcovered: 0,
+                percent: 0f64,
+            },
+            mcdc: None,
+        };
+        for summary in summaries {
+            total.branches.count += summary.branches.count;
+            total.branches.covered += summary.branches.covered;
+            total.branches.notcovered += summary.branches.notcovered;
+            total.functions.count += summary.functions.count;
+            total.functions.covered += summary.functions.covered;
+            total.instantiations.count += summary.instantiations.count;
+            total.instantiations.covered += summary.instantiations.covered;
+            total.lines.count += summary.lines.count;
+            total.lines.covered += summary.lines.covered;
+            total.regions.count += summary.regions.count;
+            total.regions.covered += summary.regions.covered;
+            total.regions.notcovered += summary.regions.notcovered;
+        }
+        total.branches.percent = percent(total.branches.covered, total.branches.count);
+        total.functions.percent = percent(total.functions.covered, total.functions.count);
+        total.instantiations.percent =
+            percent(total.instantiations.covered, total.instantiations.count);
+        total.lines.percent = percent(total.lines.covered, total.lines.count);
+        total.regions.percent = percent(total.regions.covered, total.regions.count);
+        total
+    }
+}
@@ -0,0 +1,219 @@
+use std::{
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use ureq::AgentBuilder;
+use url::Url;
+
+use crate::report::ReportResponse;
+
+/// Webhook called after a report is processed
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Notification {
+    /// Url receiving a json POST with the repository, branch and [ReportResponse]
+    pub webhook: String,
+    /// Only notify when the quality gate fails
+    #[serde(default)]
+    pub only_on_failure: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NotificationPayload<'a> {
+    pub repository: &'a str,
+    pub branch: &'a str,
+    #[serde(flatten)]
+    pub report: &'a ReportResponse,
+}
+
+impl Notification {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.webhook.starts_with("https://") || self.webhook.starts_with("http://") {
+            Ok(())
+        } else {
+            Err(format!(
+                "notification webhook {} isn't an http url",
+                self.webhook
+            ))
+        }
+    }
+}
+
+/// Whether the webhook is under one of the allowed urls: same scheme, host and port, and the
+/// path of the allowed url as prefix.
+///
+/// The webhooks are set by the repositories, they shouldn't reach the other services of the network.
+pub fn webhook_allowed(webhook: &str, allowed_webhooks: &[String]) -> bool {
+    let Ok(webhook) = Url::parse(webhook) else {
+        return false;
+    };
+    allowed_webhooks
+        .iter()
+        .filter_map(|allowed| Url::parse(allowed).ok())
+        .any(|allowed| {
+            webhook.scheme() == allowed.scheme()
+                && webhook.host() == allowed.host()
+                && webhook.port_or_known_default() == allowed.port_or_known_default()
+                && webhook.username().is_empty()
+                && (webhook.path() == allowed.path()
+                    || webhook
+                        .path()
+                        .starts_with(&format!("{}/", allowed.path().trim_end_matches('/'))))
+        })
+}
+
+/// Call the webhooks allowed by the server in a background thread, so a slow webhook doesn't delay
+/// the response of the upload.
+///
+/// A failing or refused webhook is only logged as it shouldn't fail the upload.
+pub fn notify(
+    targets: &[Notification],
+    payload: &NotificationPayload,
+    allowed_webhooks: &[String],
+) -> Option<JoinHandle<()>> {
+    let gate_failed = payload
+        .report
+        .quality_gate
+        .as_ref()
+        .is_some_and(|gate| !gate.passed);
+
+    let webhooks: Vec<String> = targets
+        .iter()
+        .filter(|target| !target.only_on_failure || gate_failed)
+        .filter(|target| {
+            let allowed = webhook_allowed(&target.webhook, allowed_webhooks);
+            if !allowed {
+                warn!(
+                    "notification webhook {} isn't allowed by the server",
+                    target.webhook
+                );
+            }
+            allowed
+        })
+        .map(|target| target.webhook.clone())
+        .collect();
+    if webhooks.is_empty() {
+        return None;
+    }
+    let body = match serde_json::to_value(payload) {
+        Ok(body) => body,
+        Err(error) => {
+            warn!("failed to serialize the notification: {error}");
+            return None;
+        }
+    };
+
+    Some(thread::spawn(move || {
+        // A redirect could lead outside of the allowed webhooks
+        let agent = AgentBuilder::new()
+            .timeout(Duration::from_secs(10))
+            .redirects(0)
+            .build();
+        for webhook in webhooks {
+            match agent.post(&webhook).send_json(&body) {
+                Ok(_) => info!("notified {webhook}"),
+                Err(error) => warn!("failed to notify {webhook}: {error}"),
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compare::Comparison;
+
+    #[test]
+    fn test_notify() {
+        use std::io::{BufRead, BufReader, Write};
+
+        let allowed = ["https://chat.example.com/hooks".to_string()];
+        assert!(webhook_allowed("https://chat.example.com/hooks", &allowed));
+        assert!(webhook_allowed(
+            "https://chat.example.com:443/hooks/coverage",
+            &allowed
+        ));
+        assert!(!webhook_allowed(
+            "https://chat.example.com/hooksy",
+            &allowed
+        ));
+        assert!(!webhook_allowed(
+            "http://chat.example.com/hooks/a",
+            &allowed
+        ));
+        assert!(!webhook_allowed(
+            "https://chat.example.com:8443/hooks/a",
+            &allowed
+        ));
+        assert!(!webhook_allowed(
+            "https://chat.example.com.evil.net/hooks/a",
+            &allowed
+        ));
+        assert!(!webhook_allowed(
+            "https://user@chat.example.com/hooks/a",
+            &allowed
+        ));
+        assert!(!webhook_allowed("http://169.254.169.254/latest", &allowed));
+        assert!(!webhook_allowed("https://chat.example.com/hooks/a", &[]));
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        let received = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let server_received = received.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request_line = String::new();
+                BufReader::new(stream.try_clone().unwrap())
+                    .read_line(&mut request_line)
+                    .unwrap();
+                server_received.lock().unwrap().push(request_line);
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                )
+                .unwrap();
+            }
+        });
+
+        let targets = [
+            Notification {
+                webhook: format!("{address}/hooks/coverage"),
+                only_on_failure: false,
+            },
+            Notification {
+                webhook: format!("{address}/internal"),
+                only_on_failure: false,
+            },
+            Notification {
+                webhook: format!("{address}/hooks/failure"),
+                only_on_failure: true,
+            },
+        ];
+        let report = ReportResponse {
+            comparison: Comparison::default(),
+            unresolved_files: vec![],
+            quality_gate: None,
+            groups: vec![],
+            merge_base: None,
+        };
+        let payload = NotificationPayload {
+            repository: "org/project",
+            branch: "main",
+            report: &report,
+        };
+        notify(&targets, &payload, &[format!("{address}/hooks/")])
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(
+            *received.lock().unwrap(),
+            ["POST /hooks/coverage HTTP/1.1\r\n"]
+        );
+        // None of the webhooks are allowed by default
+        assert!(notify(&targets, &payload, &[]).is_none());
+    }
+}
@@ -0,0 +1,562 @@
+use std::{
+    sync::{Arc, Mutex, MutexGuard, OnceLock, PoisonError},
+    time::Duration,
+};
+
+use anyhow::Context;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{NaiveDateTime, Utc};
+use jsonwebtoken::{decode, decode_header, Algorithm, Validation};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::{
+    config::{self, OidcLoginConfig, ViewerAuthMode},
+    error::{ApiError, ApiResult},
+    jwks::{JwksCache, JwksSource},
+    storage::{Record, TypedDb},
+    viewer::ViewerIdentity,
+};
+
+/// Cookie of the state of the sign in, so the callback is only completed in the browser that started it
+pub const STATE_COOKIE: &str = "llvm_cov_host_oidc_state";
+
+/// Time the user has to sign in on the provider
+pub const LOGIN_STATE_TTL_MINUTES: i64 = 10;
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Asymmetric algorithms accepted for the id tokens, the keys come from the JWKS of the provider
+const ID_TOKEN_ALGORITHMS: [Algorithm; 9] = [
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+
+/// A sign in in progress, between the redirection to the provider and the callback, keyed by its state
+#[derive(Debug, Deserialize, Serialize)]
+pub struct OidcLoginState {
+    state: String,
+    code_verifier: String,
+    nonce: String,
+    expires_at: NaiveDateTime,
+}
+
+impl Record for OidcLoginState {
+    const COLLECTION: &'static str = "oidc_login_states";
+    const VERSION: u32 = 1;
+}
+
+impl OidcLoginState {
+    /// Sign ins abandoned on the provider
+    pub fn remove_expired(storage: &TypedDb) -> ApiResult<usize> {
+        let now = Utc::now().naive_utc();
+        Ok(storage.retain::<Self>(|login| login.expires_at >= now)?)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Endpoints {
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: Option<String>,
+    jwks_uri: Option<String>,
+}
+
+#[derive(Debug)]
+struct Provider {
+    endpoints: Endpoints,
+    jwks: Option<JwksCache>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    id_token: Option<String>,
+}
+
+fn random_token() -> String {
+    URL_SAFE_NO_PAD.encode(rand::thread_rng().gen::<[u8; 32]>())
+}
+
+/// PKCE `S256` challenge of the verifier
+pub fn code_challenge(code_verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+fn login_error(message: impl ToString) -> ApiError {
+    ApiError::OidcLogin(message.to_string())
+}
+
+/// Sign in with the authorization code flow and PKCE
+#[derive(Debug)]
+pub struct OidcLogin {
+    config: OidcLoginConfig,
+    provider: Mutex<Option<Arc<Provider>>>,
+}
+
+impl OidcLogin {
+    pub fn new(config: OidcLoginConfig) -> Self {
+        Self {
+            config,
+            provider: Mutex::default(),
+        }
+    }
+
+    /// From `viewer_auth.oidc` of the server configuration, if the `oidc` mode is enabled
+    pub fn global() -> Option<&'static Self> {
+        static OIDC_LOGIN: OnceLock<Option<OidcLogin>> = OnceLock::new();
+        OIDC_LOGIN
+            .get_or_init(|| {
+                let viewer_auth = &config::get().viewer_auth;
+                (viewer_auth.mode == ViewerAuthMode::Oidc)
+                    .then(|| Self::new(viewer_auth.oidc.clone()))
+            })
+            .as_ref()
+    }
+
+    fn cached_provider(&self) -> MutexGuard<'_, Option<Arc<Provider>>> {
+        self.provider.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The endpoints of the configuration, the missing ones are discovered once
+    fn provider(&self) -> ApiResult<Arc<Provider>> {
+        if let Some(provider) = self.cached_provider().as_ref() {
+            return Ok(provider.clone());
+        }
+        // Without the lock, the other sign ins don't wait for the provider, the first discovery is kept
+        let discovered = Arc::new(self.discover()?);
+        Ok(self.cached_provider().get_or_insert(discovered).clone())
+    }
+
+    fn discover(&self) -> ApiResult<Provider> {
+        let config = &self.config;
+        let endpoints = match (&config.authorization_endpoint, &config.token_endpoint) {
+            (Some(authorization_endpoint), Some(token_endpoint)) => Endpoints {
+                authorization_endpoint: authorization_endpoint.clone(),
+                token_endpoint: token_endpoint.clone(),
+                userinfo_endpoint: config.userinfo_endpoint.clone(),
+                jwks_uri: config.jwks_uri.clone(),
+            },
+            _ => {
+                let url = format!(
+                    "{}/.well-known/openid-configuration",
+                    config.issuer.trim_end_matches('/')
+                );
+                let discovered: Endpoints = ureq::get(&url)
+                    .timeout(HTTP_TIMEOUT)
+                    .call()
+                    .with_context(|| format!("fetching {url}"))?
+                    .into_json()
+                    .with_context(|| format!("parsing {url}"))?;
+                Endpoints {
+                    authorization_endpoint: config
+                        .authorization_endpoint
+                        .clone()
+                        .unwrap_or(discovered.authorization_endpoint),
+                    token_endpoint: config
+                        .token_endpoint
+                        .clone()
+                        .unwrap_or(discovered.token_endpoint),
+                    userinfo_endpoint: config
+                        .userinfo_endpoint
+                        .clone()
+                        .or(discovered.userinfo_endpoint),
+                    jwks_uri: config.jwks_uri.clone().or(discovered.jwks_uri),
+                }
+            }
+        };
+        let jwks = endpoints
+            .jwks_uri
+            .clone()
+            .map(|uri| JwksCache::new(JwksSource::Url(uri)));
+
+        Ok(Provider { endpoints, jwks })
+    }
+
+    /// Store a new sign in, and return the url of the provider the user is redirected to, with the
+    /// state to keep in the [STATE_COOKIE] of the browser
+    pub fn start(&self, storage: &TypedDb) -> ApiResult<(String, String)> {
+        let provider = self.provider()?;
+        let now = Utc::now().naive_utc();
+        let login = OidcLoginState {
+            state: random_token(),
+            code_verifier: random_token(),
+            nonce: random_token(),
+            expires_at: now + chrono::Duration::minutes(LOGIN_STATE_TTL_MINUTES),
+        };
+        storage.insert(&login.state, &login)?;
+
+        let mut url = Url::parse(&provider.endpoints.authorization_endpoint)
+            .map_err(|e| login_error(format!("invalid authorization endpoint: {e}")))?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.config.client_id)
+            .append_pair("redirect_uri", &self.config.redirect_url)
+            .append_pair("scope", &self.config.scopes.join(" "))
+            .append_pair("state", &login.state)
+            .append_pair("nonce", &login.nonce)
+            .append_pair("code_challenge", &code_challenge(&login.code_verifier))
+            .append_pair("code_challenge_method", "S256");
+        Ok((url.into(), login.state))
+    }
+
+    /// Exchange the code of the callback, and read the user from its claims.
+    ///
+    /// `browser_state` is the [STATE_COOKIE], without it the callback of someone else's sign in
+    /// could sign the browser in as them.
+    pub fn complete(
+        &self,
+        storage: &TypedDb,
+        code: &str,
+        state: &str,
+        browser_state: Option<&str>,
+    ) -> ApiResult<ViewerIdentity> {
+        // Comparing the hashes, so the comparison time doesn't depend on the state
+        let hash = |state: &str| Sha256::digest(state.as_bytes());
+        if browser_state.map(hash) != Some(hash(state)) {
+            return Err(login_error("the sign in wasn't started by this browser"));
+        }
+        // A state can only be used once
+        let login: OidcLoginState = storage
+            .remove(state)?
+            .ok_or_else(|| login_error("unknown or already used state"))?;
+        if login.expires_at < Utc::now().naive_utc() {
+            return Err(login_error("the sign in expired"));
+        }
+        let provider = self.provider()?;
+
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.config.redirect_url),
+            ("client_id", &self.config.client_id),
+            ("code_verifier", &login.code_verifier),
+        ];
+        if let Some(client_secret) = &self.config.client_secret {
+            form.push(("client_secret", client_secret));
+        }
+        let tokens: TokenResponse = ureq::post(&provider.endpoints.token_endpoint)
+            .set("Accept", "application/json")
+            .timeout(HTTP_TIMEOUT)
+            .send_form(&form)
+            .map_err(|e| login_error(format!("token request failed: {e}")))?
+            .into_json()
+            .map_err(|e| login_error(format!("invalid token response: {e}")))?;
+
+        let mut claims = match &tokens.id_token {
+            Some(id_token) => self.verify_id_token(&provider, id_token, &login.nonce)?,
+            None => Map::new(),
+        };
+        let has_claims = claims.contains_key(&self.config.username_claim)
+            && claims.contains_key(&self.config.groups_claim);
+        match &provider.endpoints.userinfo_endpoint {
+            Some(userinfo_endpoint) if !has_claims => {
+                let userinfo: Map<String, Value> = ureq::get(userinfo_endpoint)
+                    .set("Accept", "application/json")
+                    .set("Authorization", &format!("Bearer {}", tokens.access_token))
+                    .timeout(HTTP_TIMEOUT)
+                    .call()
+                    .map_err(|e| login_error(format!("userinfo request failed: {e}")))?
+                    .into_json()
+                    .map_err(|e| login_error(format!("invalid userinfo response: {e}")))?;
+                // The claims of the id token are verified, they take precedence
+                for (claim, value) in userinfo {
+                    claims.entry(claim).or_insert(value);
+                }
+            }
+            None if tokens.id_token.is_none() => {
+                return Err(login_error(
+                    "the provider didn't return an id token, and has no userinfo endpoint",
+                ));
+            }
+            _ => {}
+        }
+
+        self.identity(&claims)
+    }
+
+    fn verify_id_token(
+        &self,
+        provider: &Provider,
+        id_token: &str,
+        nonce: &str,
+    ) -> ApiResult<Map<String, Value>> {
+        let invalid = |e: jsonwebtoken::errors::Error| ApiError::InvalidToken(e.to_string());
+
+        let header = decode_header(id_token).map_err(invalid)?;
+        if !ID_TOKEN_ALGORITHMS.contains(&header.alg) {
+            return Err(ApiError::InvalidToken(format!(
+                "unsupported algorithm {:?}",
+                header.alg
+            )));
+        }
+        let kid = header
+            .kid
+            .ok_or_else(|| ApiError::InvalidToken("missing key id".to_string()))?;
+        let jwks = provider
+            .jwks
+            .as_ref()
+            .ok_or_else(|| login_error("the provider has no jwks_uri to verify the id token"))?;
+        let key = jwks.decoding_key(&kid)?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.config.issuer]);
+        validation.set_audience(&[&self.config.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud"]);
+        let claims = decode::<Map<String, Value>>(id_token, &key, &validation)
+            .map_err(invalid)?
+            .claims;
+
+        if claims.get("nonce").and_then(Value::as_str) != Some(nonce) {
+            return Err(ApiError::InvalidToken("nonce mismatch".to_string()));
+        }
+        Ok(claims)
+    }
+
+    /// The user name and groups, from the configured claims
+    pub fn identity(&self, claims: &Map<String, Value>) -> ApiResult<ViewerIdentity> {
+        let username = match claims.get(&self.config.username_claim) {
+            Some(Value::String(username)) if !username.is_empty() => username.clone(),
+            // GitHub ids are numbers
+            Some(Value::Number(number)) => number.to_string(),
+            _ => {
+                return Err(login_error(format!(
+                    "missing {} claim",
+                    self.config.username_claim
+                )))
+            }
+        };
+        let groups = match claims.get(&self.config.groups_claim) {
+            Some(Value::Array(groups)) => groups
+                .iter()
+                .filter_map(Value::as_str)
+                .map(str::to_string)
+                .collect(),
+            Some(Value::String(group)) => vec![group.clone()],
+            _ => vec![],
+        };
+        Ok(ViewerIdentity {
+            username,
+            groups,
+            repositories: vec![],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::AccessRule,
+        test_utils::{memory_db, OIDC_TEST_MODULUS, OIDC_TEST_PRIVATE_KEY},
+        users::Session,
+    };
+
+    /// Stand-in OIDC provider serving `/jwks`, `/token` and `/userinfo`,
+    /// the token endpoint signs an id token with the claims of `id_token_claims`
+    fn serve_oidc_provider(
+        id_token_claims: std::sync::Arc<std::sync::Mutex<serde_json::Value>>,
+        token_requests: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+    ) -> String {
+        use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+        use std::io::{BufRead, BufReader, Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let key = EncodingKey::from_rsa_pem(OIDC_TEST_PRIVATE_KEY.as_bytes()).unwrap();
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+
+                let path = request_line.split(' ').nth(1).unwrap_or_default();
+                let response = match path {
+                    "/jwks" => serde_json::json!({
+                        "keys": [{ "kty": "RSA", "kid": "test", "alg": "RS256", "use": "sig", "n": OIDC_TEST_MODULUS, "e": "AQAB" }]
+                    }),
+                    "/token" => {
+                        token_requests
+                            .lock()
+                            .unwrap()
+                            .push(String::from_utf8(body).unwrap());
+                        let mut header = Header::new(Algorithm::RS256);
+                        header.kid = Some("test".to_string());
+                        let claims = id_token_claims.lock().unwrap().clone();
+                        let id_token = encode(&header, &claims, &key).unwrap();
+                        serde_json::json!({ "access_token": "access", "token_type": "Bearer", "id_token": id_token })
+                    }
+                    "/userinfo" => serde_json::json!({ "preferred_username": "ignored", "groups": ["dev"] }),
+                    _ => serde_json::json!({}),
+                }
+                .to_string();
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{response}",
+                    response.len()
+                )
+                .unwrap();
+            }
+        });
+        address
+    }
+
+    #[test]
+    fn test_oidc_login() {
+        use std::sync::{Arc, Mutex};
+
+        let storage = memory_db();
+        let id_token_claims = Arc::new(Mutex::new(serde_json::Value::Null));
+        let token_requests = Arc::new(Mutex::new(vec![]));
+        let provider = serve_oidc_provider(id_token_claims.clone(), token_requests.clone());
+
+        let oidc_login = OidcLogin::new(OidcLoginConfig {
+            issuer: "https://sso.example.com".to_string(),
+            client_id: "llvm-cov-host".to_string(),
+            redirect_url: "http://localhost:8080/oidc/callback".to_string(),
+            authorization_endpoint: Some("https://sso.example.com/authorize".to_string()),
+            token_endpoint: Some(format!("{provider}/token")),
+            userinfo_endpoint: Some(format!("{provider}/userinfo")),
+            jwks_uri: Some(format!("{provider}/jwks")),
+            ..Default::default()
+        });
+
+        let start = |storage: &TypedDb| {
+            let (url, state) = oidc_login.start(storage).unwrap();
+            let url = url::Url::parse(&url).unwrap();
+            assert_eq!(
+                url.query_pairs()
+                    .find(|(name, _)| name == "state")
+                    .unwrap()
+                    .1,
+                state
+            );
+            assert_eq!(url.path(), "/authorize");
+            url.query_pairs()
+                .map(|(name, value)| (name.into_owned(), value.into_owned()))
+                .collect::<std::collections::HashMap<_, _>>()
+        };
+        let claims = |nonce: &str, aud: &str, groups: serde_json::Value| {
+            serde_json::json!({
+                "iss": "https://sso.example.com",
+                "aud": aud,
+                "exp": chrono::Utc::now().timestamp() + 300,
+                "nonce": nonce,
+                "preferred_username": "alice",
+                "groups": groups,
+            })
+        };
+
+        let query = start(&storage);
+        assert_eq!(query["response_type"], "code");
+        assert_eq!(query["client_id"], "llvm-cov-host");
+        assert_eq!(query["code_challenge_method"], "S256");
+        *id_token_claims.lock().unwrap() =
+            claims(&query["nonce"], "llvm-cov-host", serde_json::json!(["dev"]));
+        let identity = oidc_login
+            .complete(&storage, "code", &query["state"], Some(&query["state"]))
+            .unwrap();
+        assert_eq!(identity.username, "alice");
+        assert_eq!(identity.groups, ["dev"]);
+
+        // The verifier sent to the token endpoint matches the challenge of the authorization url
+        let token_request = token_requests.lock().unwrap().last().unwrap().clone();
+        let code_verifier = url::form_urlencoded::parse(token_request.as_bytes())
+            .find(|(name, _)| name == "code_verifier")
+            .unwrap()
+            .1
+            .into_owned();
+        assert_eq!(code_challenge(&code_verifier), query["code_challenge"]);
+
+        // A state can only be used once, and unknown states are refused
+        assert!(oidc_login
+            .complete(&storage, "code", &query["state"], Some(&query["state"]))
+            .is_err());
+        assert!(oidc_login
+            .complete(&storage, "code", "unknown", Some("unknown"))
+            .is_err());
+
+        // The callback of a sign in started in another browser is refused, and its state kept
+        let query = start(&storage);
+        *id_token_claims.lock().unwrap() =
+            claims(&query["nonce"], "llvm-cov-host", serde_json::json!([]));
+        for browser_state in [None, Some("attacker")] {
+            assert!(oidc_login
+                .complete(&storage, "code", &query["state"], browser_state)
+                .is_err());
+        }
+        oidc_login
+            .complete(&storage, "code", &query["state"], Some(&query["state"]))
+            .unwrap();
+
+        // Another nonce or audience is refused
+        let query = start(&storage);
+        *id_token_claims.lock().unwrap() =
+            claims("replayed", "llvm-cov-host", serde_json::json!([]));
+        assert!(oidc_login
+            .complete(&storage, "code", &query["state"], Some(&query["state"]))
+            .is_err());
+        let query = start(&storage);
+        *id_token_claims.lock().unwrap() =
+            claims(&query["nonce"], "other-client", serde_json::json!([]));
+        assert!(oidc_login
+            .complete(&storage, "code", &query["state"], Some(&query["state"]))
+            .is_err());
+
+        // Missing groups are read from the userinfo endpoint, the id token takes precedence
+        let query = start(&storage);
+        let mut without_groups = claims(&query["nonce"], "llvm-cov-host", serde_json::json!([]));
+        without_groups.as_object_mut().unwrap().remove("groups");
+        *id_token_claims.lock().unwrap() = without_groups;
+        let identity = oidc_login
+            .complete(&storage, "code", &query["state"], Some(&query["state"]))
+            .unwrap();
+        assert_eq!(identity.username, "alice");
+        assert_eq!(identity.groups, ["dev"]);
+
+        // The groups of the claims are matched with the access rules
+        let rules = [AccessRule {
+            groups: vec!["dev".to_string()],
+            repositories: vec!["GreeFine/*".to_string()],
+            ..Default::default()
+        }];
+        assert!(identity.can_read(&rules, "GreeFine/llvm-cov-host").unwrap());
+        assert!(!identity.can_read(&rules, "other/project").unwrap());
+        let token = Session::create(
+            &storage,
+            &identity.username,
+            identity.groups,
+            chrono::Duration::hours(1),
+        )
+        .unwrap();
+        assert_eq!(
+            Session::find(&storage, &token).unwrap().unwrap().groups,
+            ["dev"]
+        );
+    }
+}
@@ -0,0 +1,257 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{model::Report, report::find_matching_project_path};
+
+/// Replace a prefix of the report filenames with a path in the repository
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PathMapping {
+    /// Prefix of the filenames in the report, like the build directory of a docker image
+    pub from: String,
+    /// Path relative to the repository root that replaces the prefix, default to the root
+    #[serde(default)]
+    pub to: String,
+}
+
+/// Resolve the report filenames to files of the local repository.
+///
+/// The explicit [PathMapping]s are tried first, the longest matching prefix wins.
+/// Otherwise the prefix is inferred with [find_matching_project_path], and reused for the next files.
+#[derive(Debug)]
+pub struct PathRemapper<'a> {
+    local_repository: &'a Path,
+    /// To check the resolved files are in the repository
+    canonical_repository: Option<PathBuf>,
+    mappings: &'a [PathMapping],
+    inferred_prefixes: Vec<String>,
+    resolved: HashMap<String, Option<String>>,
+}
+
+impl<'a> PathRemapper<'a> {
+    pub fn new(local_repository: &'a Path, mappings: &'a [PathMapping]) -> Self {
+        Self {
+            local_repository,
+            canonical_repository: local_repository.canonicalize().ok(),
+            mappings,
+            inferred_prefixes: Vec::new(),
+            resolved: HashMap::new(),
+        }
+    }
+
+    /// The local path of the file, if it exists in the repository
+    pub fn resolve(&mut self, filename: &str) -> Option<String> {
+        if let Some(resolved) = self.resolved.get(filename) {
+            return resolved.clone();
+        }
+        let resolved = self.resolve_uncached(filename);
+        self.resolved.insert(filename.to_string(), resolved.clone());
+        resolved
+    }
+
+    fn resolve_uncached(&mut self, filename: &str) -> Option<String> {
+        let mut mappings: Vec<_> = self
+            .mappings
+            .iter()
+            .filter_map(|m| Some((m, strip_path_prefix(filename, &m.from)?)))
+            .collect();
+        mappings.sort_by_key(|(m, _)| std::cmp::Reverse(m.from.len()));
+        let explicit = mappings
+            .into_iter()
+            .find_map(|(mapping, relative)| self.existing_path(&mapping.to, relative));
+        if explicit.is_some() {
+            return explicit;
+        }
+
+        let inferred = self
+            .inferred_prefixes
+            .iter()
+            .find_map(|prefix| self.existing_path("", strip_path_prefix(filename, prefix)?));
+        if inferred.is_some() {
+            return inferred;
+        }
+
+        let prefix = find_matching_project_path(self.local_repository, filename).ok()?;
+        self.inferred_prefixes.push(prefix.to_string());
+        self.existing_path("", strip_path_prefix(filename, prefix)?)
+    }
+
+    /// `repository_directory` comes from the repository config and `relative` from the report,
+    /// the path is refused if they lead outside of the repository, with `..` or a symlink
+    fn existing_path(&self, repository_directory: &str, relative: &str) -> Option<String> {
+        let path = self
+            .local_repository
+            .join(repository_directory.trim_start_matches('/'))
+            .join(relative);
+        let canonical = path.canonicalize().ok()?;
+        let inside = self
+            .canonical_repository
+            .as_ref()
+            .is_some_and(|repository| canonical.starts_with(repository));
+        (inside && canonical.is_file()).then(|| path.to_string_lossy().into_owned())
+    }
+}
+
+/// The rest of the path after the prefix, the prefix must end on a path component
+fn strip_path_prefix<'a>(filename: &'a str, prefix: &str) -> Option<&'a str> {
+    let rest = filename.strip_prefix(prefix)?;
+    if prefix.ends_with('/') || rest.starts_with('/') {
+        Some(rest.trim_start_matches('/'))
+    } else {
+        None
+    }
+}
+
+/// Call `f` on every filename field of a llvm-cov json export:
+/// `data[].files[].filename`, `data[].files[].expansions[].filenames[]` and `data[].functions[].filenames[]`
+fn for_each_filename(report: &mut Report, mut f: impl FnMut(&mut String)) {
+    for data in &mut report.data {
+        for file in &mut data.files {
+            f(&mut file.filename);
+            for expansion in file.expansions.iter_mut().flatten() {
+                expansion.filenames.iter_mut().flatten().for_each(&mut f);
+            }
+        }
+        for function in &mut data.functions {
+            function.filenames.iter_mut().flatten().for_each(&mut f);
+        }
+    }
+}
+
+/// Rewrite the filenames of the report with their path in the local repository.
+///
+/// Only the filename fields are modified, dependencies are left untouched.
+/// Returns the project files that couldn't be resolved.
+pub fn remap_filenames(
+    report: &mut Report,
+    local_repository: &Path,
+    mappings: &[PathMapping],
+) -> Vec<String> {
+    let mut remapper = PathRemapper::new(local_repository, mappings);
+    let mut unresolved = BTreeSet::new();
+
+    for_each_filename(report, |filename| {
+        // we filter out any file containing the path "/.cargo/registry" to avoid dependency files
+        if filename.contains("/.cargo/registry") {
+            return;
+        }
+        match remapper.resolve(filename) {
+            Some(local_path) => *filename = local_path,
+            None => {
+                unresolved.insert(filename.clone());
+            }
+        }
+    });
+    unresolved.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::test_utils::{file_summary, llvm_cov_export};
+
+    #[test]
+    fn test_remap_filenames() {
+        let dir = tempfile::tempdir().unwrap();
+        let local_repository = dir.path();
+        fs::create_dir_all(local_repository.join("api/src")).unwrap();
+        fs::create_dir_all(local_repository.join("generated")).unwrap();
+        fs::write(local_repository.join("api/src/main.rs"), "").unwrap();
+        fs::write(local_repository.join("api/src/lib.rs"), "").unwrap();
+        fs::write(local_repository.join("generated/proto.rs"), "").unwrap();
+
+        let mut report = llvm_cov_export(serde_json::json!([{
+            "files": [
+                { "filename": "/app/api/src/main.rs", "summary": file_summary(0, 1), "expansions": [
+                    { "filenames": ["/app/api/src/main.rs", "/home/ci/project/api/src/lib.rs"] }
+                ] },
+                { "filename": "/home/ci/project/api/src/lib.rs", "summary": file_summary(0, 1) },
+                { "filename": "/out/proto.rs", "summary": file_summary(0, 1) },
+                { "filename": "/app/api/src/deleted.rs", "summary": file_summary(0, 1) },
+                { "filename": "/home/ci/.cargo/registry/src/serde/lib.rs", "summary": file_summary(0, 1) },
+            ],
+            "functions": [
+                { "name": "_RNvCs/app/api/src/main.rs", "filenames": ["/app/api/src/main.rs"] },
+            ],
+            "totals": file_summary(0, 5),
+        }]));
+        let mappings = vec![PathMapping {
+            from: "/out".to_string(),
+            to: "generated".to_string(),
+        }];
+        let unresolved = remap_filenames(&mut report, local_repository, &mappings);
+        assert_eq!(unresolved, vec!["/app/api/src/deleted.rs"]);
+        let json_report = serde_json::to_value(&report).unwrap();
+
+        let local = |path: &str| local_repository.join(path).to_string_lossy().into_owned();
+        let data = &json_report["data"][0];
+        assert_eq!(data["files"][0]["filename"], local("api/src/main.rs"));
+        assert_eq!(
+            data["files"][0]["expansions"][0]["filenames"],
+            serde_json::json!([local("api/src/main.rs"), local("api/src/lib.rs")])
+        );
+        assert_eq!(data["files"][1]["filename"], local("api/src/lib.rs"));
+        assert_eq!(data["files"][2]["filename"], local("generated/proto.rs"));
+        assert_eq!(
+            data["files"][4]["filename"],
+            "/home/ci/.cargo/registry/src/serde/lib.rs"
+        );
+        assert_eq!(data["functions"][0]["name"], "_RNvCs/app/api/src/main.rs");
+        assert_eq!(
+            data["functions"][0]["filenames"][0],
+            local("api/src/main.rs")
+        );
+    }
+
+    #[test]
+    fn test_remap_filenames_outside_repository() {
+        let dir = tempfile::tempdir().unwrap();
+        let local_repository = dir.path().join("repository");
+        fs::create_dir_all(local_repository.join("src")).unwrap();
+        fs::write(local_repository.join("src/main.rs"), "").unwrap();
+        fs::write(dir.path().join("secret.rs"), "").unwrap();
+        std::os::unix::fs::symlink(
+            dir.path().join("secret.rs"),
+            local_repository.join("src/link.rs"),
+        )
+        .unwrap();
+
+        let files = |filenames: &[&str]| {
+            let files: Vec<_> = filenames
+                .iter()
+                .map(|filename| {
+                    serde_json::json!({ "filename": filename, "summary": file_summary(0, 1) })
+                })
+                .collect();
+            llvm_cov_export(serde_json::json!([{
+                "files": files,
+                "functions": [],
+                "totals": file_summary(0, 1),
+            }]))
+        };
+        // With `..` in the mapping of the repository config, or in the report
+        let mappings = vec![PathMapping {
+            from: "/out".to_string(),
+            to: "..".to_string(),
+        }];
+        let mut report = files(&[
+            "/out/secret.rs",
+            "/app/src/main.rs",
+            "/app/src/../../secret.rs",
+        ]);
+        let unresolved = remap_filenames(&mut report, &local_repository, &mappings);
+        assert_eq!(
+            unresolved,
+            vec!["/app/src/../../secret.rs", "/out/secret.rs"]
+        );
+
+        let mut report = files(&["/app/src/main.rs", "/app/src/link.rs"]);
+        let unresolved = remap_filenames(&mut report, &local_repository, &[]);
+        assert_eq!(unresolved, vec!["/app/src/link.rs"]);
+    }
+}
@@ -0,0 +1,62 @@
+use serde::{Deserialize, Serialize};
+
+use crate::compare::Comparison;
+
+/// Thresholds a report must meet, on the function coverage like [Comparison]
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QualityGate {
+    /// Minimum coverage of the report, in percent
+    pub min_coverage: Option<f64>,
+    /// Maximum decrease of the coverage compared to the base branch, in percent
+    pub max_decrease: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GateResult {
+    pub passed: bool,
+    pub failures: Vec<String>,
+}
+
+impl QualityGate {
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, threshold) in [
+            ("min_coverage", self.min_coverage),
+            ("max_decrease", self.max_decrease),
+        ] {
+            if threshold.is_some_and(|t| !(0f64..=100f64).contains(&t)) {
+                return Err(format!("quality_gate.{name} must be between 0 and 100"));
+            }
+        }
+        Ok(())
+    }
+
+    /// The result of the gate, or None if no threshold is configured
+    pub fn evaluate(&self, comparison: &Comparison) -> Option<GateResult> {
+        if self.min_coverage.is_none() && self.max_decrease.is_none() {
+            return None;
+        }
+
+        let mut failures = Vec::new();
+        if let Some(min_coverage) = self.min_coverage {
+            if comparison.new < min_coverage {
+                failures.push(format!(
+                    "coverage {:.2}% is below the minimum of {min_coverage:.2}%",
+                    comparison.new
+                ));
+            }
+        }
+        if let (Some(max_decrease), Some(diff)) = (self.max_decrease, comparison.diff) {
+            if -diff > max_decrease {
+                failures.push(format!(
+                    "coverage decreased by {:.2}%, more than the maximum of {max_decrease:.2}%",
+                    -diff
+                ));
+            }
+        }
+        Some(GateResult {
+            passed: failures.is_empty(),
+            failures,
+        })
+    }
+}
@@ -0,0 +1,322 @@
+use std::{
+    collections::BTreeSet,
+    fs::File,
+    io::{self, BufReader},
+    path::Path,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use anyhow::anyhow;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    artifacts::{ArtifactStore, Artifacts},
+    model::{Branch, CargoLlvmCov, Functions, Instantiations, Lines, Regions, Report},
+    report::{branch_name, legacy_branch_name, url_safe_repository_name, ReportRepository},
+    storage::{Record, TypedDb},
+};
+
+/// Compression level of the blobs, the default of zstd
+const ZSTD_LEVEL: i32 = 3;
+
+/// Held while the blobs are linked or released, so a blob isn't removed while another branch
+/// links it
+static BLOBS_LOCK: Mutex<()> = Mutex::new(());
+
+fn blobs_guard() -> MutexGuard<'static, ()> {
+    BLOBS_LOCK.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The JSON report of the last upload of a branch, keyed by [crate::report::Request::unique_name].
+///
+/// The reports are zstd compressed blobs of the JSON artifact store, keyed by the SHA-256 of
+/// their content, so the identical uploads of the branches share a blob.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RawReport {
+    /// Hex SHA-256 of the uncompressed report
+    pub digest: String,
+    /// Of the uncompressed report
+    pub size: u64,
+}
+
+impl Record for RawReport {
+    const COLLECTION: &'static str = "raw_reports";
+    const VERSION: u32 = 1;
+}
+
+/// The last report of the base branch, keyed by `{repository_name}/{branch}`, before the reports
+/// were compressed, moved by [migrate].
+///
+/// The layout of [Report] at the time, the records of sled being bincode.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Baseline {
+    pub cargo_llvm_cov: CargoLlvmCov,
+    pub data: Vec<BaselineData>,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub version: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BaselineData {
+    pub files: Vec<BaselineFile>,
+    pub functions: Vec<BaselineFunction>,
+    pub totals: BaselineSummary,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BaselineFile {
+    pub filename: String,
+    pub summary: BaselineSummary,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BaselineFunction {
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BaselineSummary {
+    pub branches: Branch,
+    pub functions: Functions,
+    pub instantiations: Instantiations,
+    pub lines: Lines,
+    pub regions: Regions,
+}
+
+impl Record for Baseline {
+    const COLLECTION: &'static str = "baselines";
+    const VERSION: u32 = 1;
+}
+
+fn blob_key(digest: &str) -> String {
+    format!("sha256/{digest}.zst")
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Point the branch to the blob, stored with `put` unless it exists, and release the blob of
+/// its previous report
+fn link(
+    storage: &TypedDb,
+    json: &dyn ArtifactStore,
+    name: &str,
+    raw: RawReport,
+    put: impl FnOnce(&str) -> anyhow::Result<()>,
+) -> anyhow::Result<RawReport> {
+    let _blobs = blobs_guard();
+    let key = blob_key(&raw.digest);
+    if !json.exists(&key)? {
+        put(&key)?;
+    }
+    let previous = storage.get::<RawReport>(name)?;
+    storage.insert(name, &raw)?;
+    if let Some(previous) = previous.filter(|previous| previous.digest != raw.digest) {
+        release(storage, json, &previous.digest)?;
+    }
+    Ok(raw)
+}
+
+/// Remove the blob if no branch points to it anymore, and return its size
+fn release(
+    storage: &TypedDb,
+    json: &dyn ArtifactStore,
+    digest: &str,
+) -> anyhow::Result<Option<u64>> {
+    for raw in storage.iter::<RawReport>()? {
+        if raw?.digest == digest {
+            return Ok(None);
+        }
+    }
+    json.delete(&blob_key(digest))
+}
+
+/// Store the report of the branch from a local file, compressed next to it
+pub fn store_file(
+    storage: &TypedDb,
+    json: &dyn ArtifactStore,
+    name: &str,
+    path: &Path,
+) -> anyhow::Result<RawReport> {
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut BufReader::new(File::open(path)?), &mut hasher)?;
+    let raw = RawReport {
+        digest: hex(&hasher.finalize()),
+        size,
+    };
+    link(storage, json, name, raw, |key| {
+        let compressed = path.with_extension("json.zst");
+        zstd::stream::copy_encode(
+            BufReader::new(File::open(path)?),
+            File::create(&compressed)?,
+            ZSTD_LEVEL,
+        )?;
+        json.put_file(key, &compressed)
+    })
+}
+
+/// Store the report of the branch
+pub fn store(
+    storage: &TypedDb,
+    json: &dyn ArtifactStore,
+    name: &str,
+    content: &[u8],
+) -> anyhow::Result<RawReport> {
+    let raw = RawReport {
+        digest: hex(&Sha256::digest(content)),
+        size: content.len() as u64,
+    };
+    link(storage, json, name, raw, |key| {
+        json.put(key, &zstd::encode_all(content, ZSTD_LEVEL)?)
+    })
+}
+
+/// The uncompressed report of the branch
+pub fn load(
+    storage: &TypedDb,
+    json: &dyn ArtifactStore,
+    name: &str,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    let Some(raw) = storage.get::<RawReport>(name)? else {
+        return Ok(None);
+    };
+    let blob = json
+        .get(&blob_key(&raw.digest))?
+        .ok_or_else(|| anyhow!("the report blob {} of {name} is missing", raw.digest))?;
+    Ok(Some(zstd::decode_all(blob.as_slice())?))
+}
+
+/// The report of the last upload of the branch of the repository, by its [crate::report::Request::raw_repository_name]
+pub fn load_report(
+    storage: &TypedDb,
+    json: &dyn ArtifactStore,
+    raw_repository_name: &str,
+    branch: &str,
+) -> anyhow::Result<Option<Report>> {
+    let Some(content) = load(storage, json, &branch_name(raw_repository_name, branch))? else {
+        return Ok(None);
+    };
+    Ok(Some(serde_json::from_slice(&content)?))
+}
+
+/// Remove the report of the branch, and return the size of its blob if no other branch shares it
+pub fn remove(
+    storage: &TypedDb,
+    json: &dyn ArtifactStore,
+    name: &str,
+) -> anyhow::Result<Option<u64>> {
+    let _blobs = blobs_guard();
+    match storage.remove::<RawReport>(name)? {
+        Some(raw) => release(storage, json, &raw.digest),
+        None => Ok(None),
+    }
+}
+
+/// Move the report of the branch `name` to `to`, unless `to` has one
+pub fn rename(
+    storage: &TypedDb,
+    json: &dyn ArtifactStore,
+    name: &str,
+    to: &str,
+) -> anyhow::Result<()> {
+    let Some(raw) = storage.get::<RawReport>(name)? else {
+        return Ok(());
+    };
+    if storage.get::<RawReport>(to)?.is_none() {
+        storage.insert(to, &raw)?;
+    }
+    remove(storage, json, name)?;
+    Ok(())
+}
+
+/// Move the reports written before they were compressed: the JSON file of each branch, and the
+/// baselines of the base branches that were stored in the database
+pub fn migrate(storage: &TypedDb, artifacts: &Artifacts) -> anyhow::Result<usize> {
+    let json = artifacts.json.as_ref();
+    let mut migrated = 0;
+    for res in storage.entries::<ReportRepository>(false)? {
+        let (name, _) = res?;
+        if let Some(content) = json.get(&name)? {
+            store(storage, json, &name, &content)?;
+            json.delete(&name)?;
+            migrated += 1;
+        }
+    }
+    // Keyed by `{repository_name}/{branch}`, the report of the last upload of the base branch.
+    // The versions with a single repository keyed it by the branch only
+    let mut repositories = BTreeSet::new();
+    for res in storage.iter::<ReportRepository>()? {
+        repositories.insert(url_safe_repository_name(&res?.repository_name));
+    }
+    for res in storage.entries::<Baseline>(false)? {
+        let (key, baseline) = res?;
+        let (repository_name, branch) = match key.split_once('/') {
+            Some((repository_name, branch)) => (repository_name, branch),
+            None if repositories.len() == 1 => (repositories.first().unwrap().as_str(), &*key),
+            None => {
+                warn!(
+                    "Kept the baseline of the branch {key}, it can't be attributed to one of the {} repositories",
+                    repositories.len()
+                );
+                continue;
+            }
+        };
+        // Moved to the name of the branch by [crate::report::migrate_names]
+        let name = legacy_branch_name(repository_name, branch);
+        if storage.get::<RawReport>(&name)?.is_none() {
+            store(storage, json, &name, &serde_json::to_vec(&baseline)?)?;
+            migrated += 1;
+        } else {
+            warn!("Dropped the baseline {key}, the branch {name} has a more recent report");
+        }
+        storage.remove::<Baseline>(&key)?;
+    }
+    Ok(migrated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::OutputConfig, migrations, test_utils::load_snapshot};
+
+    #[test]
+    fn test_migrate_legacy_baseline() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        let output = OutputConfig {
+            json_reports_dir: dir.join("json"),
+            html_reports_dir: dir.join("html"),
+            ..OutputConfig::default()
+        };
+        let artifacts = Artifacts::local(&output);
+        let json = artifacts.json.as_ref();
+        let storage = load_snapshot("storage-v1");
+        migrations::run(&storage).unwrap();
+        // Keyed by the branch only, by the versions with a single repository
+        let baseline = storage.remove::<Baseline>("greefine-app/main").unwrap();
+        storage.insert("main", &baseline.unwrap()).unwrap();
+
+        assert_eq!(migrate(&storage, &artifacts).unwrap(), 1);
+        let report = load(&storage, json, "greefine-app-main").unwrap();
+        let report: Report = serde_json::from_slice(&report.unwrap()).unwrap();
+        assert_eq!(report.data[0].files[0].filename, "/repo/src/lib.rs");
+        assert!(storage.iter::<Baseline>().unwrap().next().is_none());
+
+        // Kept when there are several repositories
+        let baseline = load_snapshot("storage-v1")
+            .get::<Baseline>("greefine-app/main")
+            .unwrap()
+            .unwrap();
+        storage.insert("develop", &baseline).unwrap();
+        let repository = ReportRepository {
+            repository_name: "GreeFine/other".to_string(),
+        };
+        storage.insert("greefine-other-main", &repository).unwrap();
+        assert_eq!(migrate(&storage, &artifacts).unwrap(), 0);
+        assert!(storage.get::<Baseline>("develop").unwrap().is_some());
+    }
+}
@@ -1,18 +1,32 @@
 use std::{
-    fs,
-    io::Write,
-    path::{Path, PathBuf},
+    cmp::Reverse,
+    collections::HashSet,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read},
+    mem,
+    path::Path,
     str::FromStr,
 };
 
+use actix_web::http::header::{self, HeaderMap};
 use chrono::NaiveDateTime;
+use flate2::read::MultiGzDecoder;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
 use crate::{
+    artifacts::{ArtifactStore, Artifacts},
     compare::Comparison,
-    config,
+    config::{self, OutputConfig},
     error::{ApiError, ApiResult},
+    filter::{filter_report, FileFilter},
+    groups::GroupCoverage,
     model::Report,
+    path_mapping::{remap_filenames, PathMapping},
+    quality_gate::GateResult,
+    raw_reports,
+    repository_config::RepositoryConfig,
+    storage::{Record, TypedDb},
     utils,
 };
 
@@ -21,11 +35,98 @@ pub struct Request {
     /// Url of the git repository associated to this report.
     ///
     /// We need to clone the repository so we can package the sources files into the export.
+    /// Local repositories are supported with a `file://` url or a path.
     pub git: String,
     /// Branch of git repository associated to this report.
     pub branch: String,
+    /// Commit the coverage ran on, recorded in the audit log, the checked out commit if not set
+    #[serde(default)]
+    pub commit: Option<String>,
     /// The report generated when running `cargo llvm-cov --json`
-    pub json_report: serde_json::Value,
+    pub json_report: Report,
+    /// Prefixes of the report filenames to replace, otherwise they are inferred per file
+    #[serde(default)]
+    pub path_mappings: Vec<PathMapping>,
+    /// Files counted in the coverage, takes precedence over the filter of the repository configuration
+    #[serde(default)]
+    pub filter: Option<FileFilter>,
+}
+
+/// `Content-Encoding` of an upload, compressing the report saves most of its size
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UploadEncoding {
+    #[default]
+    Identity,
+    Gzip,
+    Zstd,
+}
+
+impl FromStr for UploadEncoding {
+    type Err = ApiError;
+
+    fn from_str(encoding: &str) -> Result<Self, Self::Err> {
+        match encoding.trim().to_ascii_lowercase().as_str() {
+            "identity" => Ok(Self::Identity),
+            "gzip" | "x-gzip" => Ok(Self::Gzip),
+            "zstd" => Ok(Self::Zstd),
+            _ => Err(ApiError::UnsupportedEncoding(encoding.to_string())),
+        }
+    }
+}
+
+impl UploadEncoding {
+    pub fn from_headers(headers: &HeaderMap) -> ApiResult<Self> {
+        match headers.get(header::CONTENT_ENCODING) {
+            Some(value) => value
+                .to_str()
+                .map_err(|_| ApiError::UnsupportedEncoding(format!("{value:?}")))?
+                .parse(),
+            None => Ok(Self::Identity),
+        }
+    }
+}
+
+/// Fails the reads past `remaining` bytes, so a compressed upload can't expand past the limit
+struct LimitedReader<R> {
+    inner: R,
+    remaining: u64,
+    exceeded: bool,
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        match self.remaining.checked_sub(read as u64) {
+            Some(remaining) => self.remaining = remaining,
+            None => {
+                self.exceeded = true;
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "the report is too large",
+                ));
+            }
+        }
+        Ok(read)
+    }
+}
+
+/// Response to a new report
+#[derive(Debug, Serialize)]
+pub struct ReportResponse {
+    #[serde(flatten)]
+    pub comparison: Comparison,
+    /// Files of the report that weren't found in the repository, they are missing from the HTML report
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unresolved_files: Vec<String>,
+    /// Result of the quality gate of the repository configuration, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quality_gate: Option<GateResult>,
+    /// Coverage of the groups of the repository configuration
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<GroupCoverage>,
+    /// Commit of the base branch the branch forked from, to diff against, for the cloned repositories
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub merge_base: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -36,26 +137,345 @@ pub struct ReportHistory {
     pub repository_name: String,
     /// Branch of git repository associated to this report.
     pub branch: String,
+    /// Commit of the upload, the checked out commit if it wasn't sent
+    pub commit: Option<String>,
     pub name: String,
     pub comparison: Comparison,
     pub date: NaiveDateTime,
 }
 
+const HISTORY_BY_DATE: &str = "report_history_by_date";
+const HISTORY_BY_REPOSITORY: &str = "report_history_by_repository";
+const HISTORY_BY_BRANCH: &str = "report_history_by_branch";
+const TIME_KEY_LEN: usize = 12;
+const MAX_PAGE_SIZE: usize = 500;
+
+/// Bytes sorted like the dates, the seconds with their sign bit flipped, then the nanoseconds
+fn time_key(date: NaiveDateTime) -> [u8; TIME_KEY_LEN] {
+    let utc = date.and_utc();
+    let seconds = (utc.timestamp() as u64) ^ (1 << 63);
+    let mut key = [0; TIME_KEY_LEN];
+    key[..8].copy_from_slice(&seconds.to_be_bytes());
+    key[8..].copy_from_slice(&utc.timestamp_subsec_nanos().to_be_bytes());
+    key
+}
+
+/// Prefix of the index keys of the repository, the names don't contain a NUL byte
+fn repository_prefix(repository_name: &str) -> Vec<u8> {
+    [repository_name.as_bytes(), b"\0"].concat()
+}
+
+fn branch_prefix(repository_name: &str, branch: &str) -> Vec<u8> {
+    [repository_name.as_bytes(), b"\0", branch.as_bytes(), b"\0"].concat()
+}
+
+/// Keyed by the date of the upload, and indexed by date, by repository, and by branch
+impl Record for ReportHistory {
+    const COLLECTION: &'static str = "report_history";
+    const VERSION: u32 = 2;
+    const INDEXES: &'static [&'static str] =
+        &[HISTORY_BY_DATE, HISTORY_BY_REPOSITORY, HISTORY_BY_BRANCH];
+
+    fn index_keys(&self, _key: &str) -> Vec<Vec<u8>> {
+        let time = time_key(self.date);
+        vec![
+            time.to_vec(),
+            [repository_prefix(&self.repository_name).as_slice(), &time].concat(),
+            [
+                branch_prefix(&self.repository_name, &self.branch).as_slice(),
+                &time,
+            ]
+            .concat(),
+        ]
+    }
+}
+
+/// Directory of the upload in the HTML reports of its branch, derived from the date of its history
+pub fn upload_id(date: NaiveDateTime) -> String {
+    date.format("%Y%m%dT%H%M%S%9f").to_string()
+}
+
+/// Query of `GET /reports` and of the dashboard, the uploads are the most recent first
+#[derive(Debug, Default, Deserialize)]
+pub struct HistoryQuery {
+    pub repository: Option<String>,
+    pub branch: Option<String>,
+    /// Only the most recent upload of each branch
+    #[serde(default)]
+    pub latest: bool,
+    /// Starting at 1
+    pub page: Option<usize>,
+    pub per_page: Option<usize>,
+}
+
+impl HistoryQuery {
+    /// The filters and page size, as a query string for the links to the other pages
+    pub fn filters(&self) -> String {
+        let mut query = url::form_urlencoded::Serializer::new(String::new());
+        if let Some(per_page) = self.per_page {
+            query.append_pair("per_page", &per_page.to_string());
+        }
+        if let Some(repository) = &self.repository {
+            query.append_pair("repository", repository);
+        }
+        if let Some(branch) = &self.branch {
+            query.append_pair("branch", branch);
+        }
+        if self.latest {
+            query.append_pair("latest", "true");
+        }
+        query.finish()
+    }
+}
+
+type History = Box<dyn Iterator<Item = ApiResult<ReportHistory>>>;
+
+impl ReportHistory {
+    pub fn upload_id(&self) -> String {
+        upload_id(self.date)
+    }
+
+    fn scan(storage: &TypedDb, index: &str, prefix: &[u8]) -> ApiResult<History> {
+        let history = storage.scan_index(index, prefix, true)?;
+        Ok(Box::new(history.map(|report| Ok(report?))))
+    }
+
+    /// Every upload, the most recent first
+    pub fn recent(storage: &TypedDb) -> ApiResult<History> {
+        Self::scan(storage, HISTORY_BY_DATE, &[])
+    }
+
+    pub fn of_repository(storage: &TypedDb, repository_name: &str) -> ApiResult<History> {
+        Self::scan(
+            storage,
+            HISTORY_BY_REPOSITORY,
+            &repository_prefix(repository_name),
+        )
+    }
+
+    pub fn of_branch(storage: &TypedDb, repository_name: &str, branch: &str) -> ApiResult<History> {
+        Self::scan(
+            storage,
+            HISTORY_BY_BRANCH,
+            &branch_prefix(repository_name, branch),
+        )
+    }
+
+    /// The uploads of each branch after each other, the most recent first
+    pub fn by_branch(storage: &TypedDb, repository_name: Option<&str>) -> ApiResult<History> {
+        let prefix = repository_name.map(repository_prefix).unwrap_or_default();
+        Self::scan(storage, HISTORY_BY_BRANCH, &prefix)
+    }
+
+    /// The most recent upload of each branch, of the repository or of all of them
+    pub fn latest_per_branch(
+        storage: &TypedDb,
+        repository_name: Option<&str>,
+    ) -> ApiResult<Vec<Self>> {
+        let prefix = repository_name.map(repository_prefix).unwrap_or_default();
+        let mut latest = storage.last_per_group(HISTORY_BY_BRANCH, &prefix, |key| {
+            &key[..key.len() - TIME_KEY_LEN]
+        })?;
+        latest.sort_by_key(|report: &Self| Reverse(report.date));
+        Ok(latest)
+    }
+
+    /// The uploads matching the filters of the query, without its pagination
+    pub fn query(storage: &TypedDb, query: &HistoryQuery) -> ApiResult<History> {
+        let branch = query.branch.clone();
+        let history = match (&query.repository, &query.branch) {
+            _ if query.latest => {
+                let latest = Self::latest_per_branch(storage, query.repository.as_deref())?;
+                Box::new(latest.into_iter().map(Ok))
+            }
+            (Some(repository), Some(branch)) => {
+                return Self::of_branch(storage, repository, branch)
+            }
+            (Some(repository), None) => return Self::of_repository(storage, repository),
+            (None, _) => Self::recent(storage)?,
+        };
+        Ok(Box::new(history.filter(move |report| {
+            report.as_ref().map_or(true, |report| {
+                branch.as_ref().is_none_or(|b| &report.branch == b)
+            })
+        })))
+    }
+}
+
+/// A page of the uploads of the query that are `visible`, and the number of the next page.
+/// Without a page size, all of them.
+pub fn history_page(
+    storage: &TypedDb,
+    query: &HistoryQuery,
+    default_per_page: Option<usize>,
+    visible: impl Fn(&ReportHistory) -> ApiResult<bool>,
+) -> ApiResult<(Vec<ReportHistory>, Option<usize>)> {
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query
+        .per_page
+        .or(default_per_page)
+        .map_or(usize::MAX, |per_page| per_page.clamp(1, MAX_PAGE_SIZE));
+
+    let mut skipped = 0;
+    let mut reports = vec![];
+    for report in ReportHistory::query(storage, query)? {
+        let report = report?;
+        if !visible(&report)? {
+            continue;
+        }
+        if skipped < (page - 1).saturating_mul(per_page) {
+            skipped += 1;
+            continue;
+        }
+        reports.push(report);
+        // One more upload than the page, to know if there is a next one
+        if reports.len() > per_page {
+            break;
+        }
+    }
+    let next_page = (reports.len() > per_page).then(|| {
+        reports.truncate(per_page);
+        page + 1
+    });
+    Ok((reports, next_page))
+}
+
+/// The most recent upload of the branch named `name`
+pub fn latest_upload(storage: &TypedDb, name: &str) -> ApiResult<Option<ReportHistory>> {
+    let Some(repository_name) = report_repository(storage, name)? else {
+        return Ok(None);
+    };
+    let latest = ReportHistory::latest_per_branch(storage, Some(&repository_name))?;
+    Ok(latest.into_iter().find(|report| report.name == name))
+}
+
+/// Move the HTML reports written before each upload had its own directory to the one of their latest upload,
+/// the previous uploads were overwritten by it
+pub fn migrate_html_layout(storage: &TypedDb, html: &dyn ArtifactStore) -> ApiResult<usize> {
+    let mut migrated = 0;
+    for ReportHistory { name, date, .. } in ReportHistory::latest_per_branch(storage, None)? {
+        if html.get(&format!("{name}/index.html"))?.is_none() {
+            continue;
+        }
+        let upload_dir = format!("{name}/{}/", upload_id(date));
+        for (key, _) in html.list(&name)? {
+            if !key.starts_with(&upload_dir) {
+                let path = &key[name.len() + 1..];
+                html.rename(&key, &format!("{upload_dir}{path}"))?;
+            }
+        }
+        migrated += 1;
+    }
+    Ok(migrated)
+}
+
+/// Repository of an HTML report, keyed by [Request::unique_name], to check the read access of `/view`
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ReportRepository {
+    pub repository_name: String,
+}
+
+impl Record for ReportRepository {
+    const COLLECTION: &'static str = "report_repositories";
+    const VERSION: u32 = 1;
+}
+
+/// Base branch of the repository configuration, keyed by [Request::raw_repository_name], see [record_base_branch].
+/// Its history is always kept by the retention policy
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BaseBranch {
+    pub branch: String,
+}
+
+impl Record for BaseBranch {
+    const COLLECTION: &'static str = "base_branches";
+    const VERSION: u32 = 1;
+}
+
+/// The recorded [BaseBranch] of the repository, or else the one of the configuration of the upload
+pub fn base_branch(
+    storage: &TypedDb,
+    repository_name: &str,
+    repository_config: &RepositoryConfig,
+) -> ApiResult<String> {
+    Ok(storage
+        .get::<BaseBranch>(repository_name)?
+        .map_or_else(|| repository_config.base_branch().to_string(), |b| b.branch))
+}
+
+/// Record the base branch of the repository configuration, only from an upload of the base branch itself.
+///
+/// Any branch can have its own `.llvm-cov-host.toml`, another branch must not change the base of
+/// the comparisons, nor the branch kept by the retention policy.
+pub fn record_base_branch(
+    storage: &TypedDb,
+    request: &Request,
+    repository_config: &RepositoryConfig,
+) -> ApiResult<()> {
+    let repository_name = request.raw_repository_name();
+    if request.branch == base_branch(storage, &repository_name, repository_config)? {
+        storage.insert(
+            &repository_name,
+            &BaseBranch {
+                branch: repository_config.base_branch().to_string(),
+            },
+        )?;
+    }
+    Ok(())
+}
+
+/// The `owner/project` name of the repository of the HTML report.
+///
+/// The reports sent before the [ReportRepository] existed are added by [crate::migrations].
+pub fn report_repository(storage: &TypedDb, name: &str) -> ApiResult<Option<String>> {
+    Ok(storage
+        .get::<ReportRepository>(name)?
+        .map(|report| report.repository_name))
+}
+
 impl Request {
-    /// try to extract the project name from the git path, or return an url safe version of the git address
-    pub fn repository_name(&self) -> String {
-        let capture = config::REPOSITORY_REGEX.captures(&self.git);
-        if let Some(name) = capture.map(|c| c.name("name").unwrap().as_str()) {
-            name.replace('/', "-").to_lowercase()
-        } else {
-            utils::url_safe_string(&self.git)
+    /// Parse an upload written to `path`, decompressing it on the fly.
+    ///
+    /// The report is read once into its model, the upload is never held in memory as a whole.
+    pub fn from_upload(path: &Path, encoding: UploadEncoding, limit: usize) -> ApiResult<Self> {
+        let file = BufReader::new(File::open(path)?);
+        let inner: Box<dyn Read> = match encoding {
+            UploadEncoding::Identity => Box::new(file),
+            UploadEncoding::Gzip => Box::new(MultiGzDecoder::new(file)),
+            UploadEncoding::Zstd => Box::new(zstd::Decoder::with_buffer(file)?),
+        };
+        let mut reader = LimitedReader {
+            inner,
+            remaining: limit as u64,
+            exceeded: false,
+        };
+        match serde_json::from_reader(BufReader::new(&mut reader)) {
+            Err(_) if reader.exceeded => Err(ApiError::UploadTooLarge(limit)),
+            result => Ok(result?),
+        }
+    }
+
+    /// the `owner/project` part of a remote url, or the two last components of a local path
+    fn captured_name(&self) -> Option<&str> {
+        config::REPOSITORY_REGEX
+            .captures(&self.git)
+            .or_else(|| config::LOCAL_REPOSITORY_REGEX.captures(&self.git))
+            .map(|c| c.name("name").unwrap().as_str())
+    }
+
+    /// Host of the remote [Request::git] in lowercase, None for the local sources
+    pub fn git_host(&self) -> Option<String> {
+        if let Ok(url) = url::Url::parse(&self.git) {
+            return url.host_str().map(str::to_lowercase);
         }
+        // The scp-like `user@host:path`, with a colon before the first slash
+        let (before, _) = self.git.split_once(':')?;
+        (!before.contains('/')).then(|| before.rsplit('@').next().unwrap().to_lowercase())
     }
 
-    /// try to extract the project name from the git path
+    /// try to extract the project name from the git path, or return an url safe version of the git address
     pub fn raw_repository_name(&self) -> String {
-        let capture = config::REPOSITORY_REGEX.captures(&self.git);
-        if let Some(name) = capture.map(|c| c.name("name").unwrap().as_str()) {
+        if let Some(name) = self.captured_name() {
             name.to_string()
         } else {
             utils::url_safe_string(&self.git)
@@ -64,11 +484,126 @@ impl Request {
 
     /// Based on the git url and the branch name
     pub fn unique_name(&self) -> String {
-        let mut result = self.repository_name();
-        result.push('-');
-        result.push_str(&utils::url_safe_string(&self.branch));
-        result
+        branch_name(&self.raw_repository_name(), &self.branch)
+    }
+}
+
+/// `owner-project` in lowercase, from the [Request::raw_repository_name]
+pub fn url_safe_repository_name(raw_repository_name: &str) -> String {
+    raw_repository_name.replace('/', "-").to_lowercase()
+}
+
+/// Hex of the first bytes of the SHA-256 of the parts
+fn name_digest(parts: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part.as_bytes());
+        // The parts don't contain a NUL byte
+        hasher.update([0]);
     }
+    hasher.finalize()[..6]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// [Request::unique_name] of the branch of a repository, from its [Request::raw_repository_name].
+///
+/// The url safe names aren't unique, `org/repo` on `a-main` and `org/repo-a` on `main` read the same,
+/// the digest of the repository and the branch tells them apart.
+pub fn branch_name(raw_repository_name: &str, branch: &str) -> String {
+    format!(
+        "{}-{}-{}",
+        url_safe_repository_name(raw_repository_name),
+        utils::url_safe_string(branch),
+        name_digest(&[raw_repository_name, branch])
+    )
+}
+
+/// Name of the branches before [branch_name], from the url safe name of the repository
+pub fn legacy_branch_name(repository_name: &str, branch: &str) -> String {
+    format!("{repository_name}-{}", utils::url_safe_string(branch))
+}
+
+/// Directory of the clone of the repository, from its [Request::raw_repository_name]
+pub fn clone_name(raw_repository_name: &str) -> String {
+    format!(
+        "{}-{}",
+        url_safe_repository_name(raw_repository_name),
+        name_digest(&[raw_repository_name])
+    )
+}
+
+/// Refuse an upload whose [Request::unique_name] belongs to another branch, it would replace its reports
+pub fn check_unique_name(storage: &TypedDb, request: &Request) -> ApiResult<()> {
+    let name = request.unique_name();
+    let owner = match latest_upload(storage, &name)? {
+        Some(report) => Some((report.repository_name, report.branch)),
+        None => report_repository(storage, &name)?
+            .map(|repository_name| (repository_name, request.branch.clone())),
+    };
+    match owner {
+        Some((repository_name, branch))
+            if repository_name != request.raw_repository_name() || branch != request.branch =>
+        {
+            Err(ApiError::NameConflict(format!(
+                "{name} is the branch {branch} of {repository_name}"
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Move the branches named before [branch_name] to their name: the history, the JSON and HTML reports,
+/// the sources, and the clone of the repository
+pub fn migrate_names(
+    storage: &TypedDb,
+    artifacts: &Artifacts,
+    output: &OutputConfig,
+) -> ApiResult<usize> {
+    let history: Vec<ReportHistory> =
+        ReportHistory::by_branch(storage, None)?.collect::<ApiResult<_>>()?;
+    let mut migrated = HashSet::new();
+    for mut entry in history {
+        let name = branch_name(&entry.repository_name, &entry.branch);
+        if entry.name == name {
+            continue;
+        }
+        let legacy = mem::replace(&mut entry.name, name.clone());
+        // The entries are renamed last, so an interrupted migration moves the rest on the next start
+        if migrated.insert(name.clone()) {
+            raw_reports::rename(storage, artifacts.json.as_ref(), &legacy, &name)?;
+            storage.remove::<ReportRepository>(&legacy)?;
+            storage.insert(
+                &name,
+                &ReportRepository {
+                    repository_name: entry.repository_name.clone(),
+                },
+            )?;
+            let clones = &output.repositories_dir;
+            for (from, to) in [
+                (
+                    output.sources_dir.join(&legacy),
+                    output.sources_dir.join(&name),
+                ),
+                (
+                    clones.join(url_safe_repository_name(&entry.repository_name)),
+                    clones.join(clone_name(&entry.repository_name)),
+                ),
+            ] {
+                if from.exists() && !to.exists() {
+                    fs::rename(from, to)?;
+                }
+            }
+            for (key, _) in artifacts.html.list(&legacy)? {
+                artifacts
+                    .html
+                    .rename(&key, &format!("{name}/{}", &key[legacy.len() + 1..]))?;
+            }
+        }
+        storage.insert(&entry.date.to_string(), &entry)?;
+    }
+    Ok(migrated.len())
 }
 
 pub fn find_matching_project_path<'a>(
@@ -76,13 +611,13 @@ pub fn find_matching_project_path<'a>(
     remote_filepath: &'a str,
 ) -> ApiResult<&'a str> {
     let separators_positions: Vec<_> = remote_filepath
-        .chars()
-        .enumerate()
-        .filter_map(|(idx, e)| (e == '/').then_some(idx))
+        .match_indices('/')
+        .map(|(idx, _)| idx)
         .collect();
     // Try to get an existing path from joining our local repository path with the one sent by the user
-    // Start from the end of the filepath and for each subsequent tries take one more ancestors of the filepath
-    let matching_project_path = separators_positions.iter().rev().find_map(|&sep| {
+    // Start from the longest suffix of the filepath, so a file in a submodule isn't matched
+    // with a file of the same name at the root of the repository (`src/lib.rs` for example)
+    let matching_project_path = separators_positions.iter().find_map(|&sep| {
         let path = &remote_filepath[sep + 1..];
         local_repository
             .join(path)
@@ -93,47 +628,545 @@ pub fn find_matching_project_path<'a>(
     matching_project_path.ok_or(ApiError::FailedReportFilePathReplace)
 }
 
-/// Modify the report sources paths, with the path to the locally clone repository
+/// Save the report to `json_path`, with its sources paths modified to the locally cloned repository,
+/// and only the files selected by the filter.
 ///
-/// In the case of a project that use workspaces, we need to find the root path first.
-fn raw_report_with_local_repository(
-    request: &Request,
-    report: &Report,
+/// Returns the project files that couldn't be resolved in the repository.
+pub fn save_json(
+    report: &mut Report,
     local_repository: &Path,
-) -> ApiResult<String> {
-    let report_data = report.data.first().ok_or(ApiError::NoReportData)?;
-    // try to get a file cited in the report
-    // we filter out any file containing the path "/.cargo/registry" to avoid dependency files
-    let any_project_file_path = &report_data
-        .files
-        .iter()
-        .find(|f| !f.filename.contains("/.cargo/registry"))
-        .ok_or(ApiError::NoProjectFile)?
-        .filename;
-    let old_file_path = find_matching_project_path(local_repository, any_project_file_path)?;
+    path_mappings: &[PathMapping],
+    filter: &FileFilter,
+    json_path: &Path,
+) -> ApiResult<Vec<String>> {
+    if report.data.first().is_none_or(|data| data.files.is_empty()) {
+        return Err(ApiError::NoReportData);
+    }
+    let unresolved_files = remap_filenames(report, local_repository, path_mappings);
+    filter_report(report, local_repository, &filter.compile()?)?;
 
-    Ok(request
-        .json_report
-        .to_string()
-        .replace(old_file_path, local_repository.to_str().unwrap()))
-}
+    let local_repository = local_repository.to_string_lossy();
+    let files = &report.data[0].files;
+    if files.is_empty() {
+        return Err(ApiError::NoProjectFile);
+    }
+    if !files
+        .iter()
+        .any(|file| file.filename.starts_with(local_repository.as_ref()))
+    {
+        return Err(ApiError::FailedReportFilePathReplace);
+    }
+    let unresolved_files = unresolved_files
+        .into_iter()
+        .filter(|filename| files.iter().any(|file| file.filename == *filename))
+        .collect();
 
-pub fn save_json(
-    request: &Request,
-    report: &Report,
-    local_repository: &Path,
-) -> anyhow::Result<String> {
-    let fixed_report = raw_report_with_local_repository(request, report, local_repository)?;
-    let json_path = PathBuf::from_str(config::JSON_REPORTS_DIR)
-        .unwrap()
-        .canonicalize()
-        .unwrap()
-        .join(request.unique_name());
-    let mut file = fs::OpenOptions::new()
+    let file = fs::OpenOptions::new()
         .write(true)
         .create(true)
         .truncate(true)
-        .open(json_path.clone())?;
-    file.write_all(fixed_report.as_bytes())?;
-    Ok(json_path.to_string_lossy().into_owned())
+        .open(json_path)?;
+    serde_json::to_writer(BufWriter::new(file), report)?;
+    Ok(unresolved_files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        artifacts::LocalStore,
+        raw_reports::RawReport,
+        test_utils::{self, file_summary, memory_db, request, sqlite_storage},
+    };
+
+    #[test]
+    fn test_record_base_branch() {
+        let storage = memory_db();
+        let request = |branch: &str| test_utils::request("https://github.com/GreeFine/app", branch);
+        let default = RepositoryConfig::default();
+        let feature = RepositoryConfig::parse("base_branch = \"feature\"").unwrap();
+        let develop = RepositoryConfig::parse("base_branch = \"develop\"").unwrap();
+
+        // A branch that isn't the base can't change it
+        record_base_branch(&storage, &request("feature"), &default).unwrap();
+        assert!(storage.get::<BaseBranch>("GreeFine/app").unwrap().is_none());
+        record_base_branch(&storage, &request("main"), &default).unwrap();
+        record_base_branch(&storage, &request("feature"), &feature).unwrap();
+        assert_eq!(
+            base_branch(&storage, "GreeFine/app", &feature).unwrap(),
+            "main"
+        );
+
+        // The base branch moves with its own configuration
+        record_base_branch(&storage, &request("main"), &develop).unwrap();
+        assert_eq!(
+            base_branch(&storage, "GreeFine/app", &default).unwrap(),
+            "develop"
+        );
+    }
+
+    #[test]
+    fn test_migrate_names() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        let output = OutputConfig {
+            json_reports_dir: dir.join("json"),
+            html_reports_dir: dir.join("html"),
+            repositories_dir: dir.join("repositories"),
+            sources_dir: dir.join("sources"),
+            sled_dir: dir.join("sled"),
+            ..OutputConfig::default()
+        };
+        let storage = memory_db();
+        let artifacts = Artifacts::local(&output);
+        let json = artifacts.json.as_ref();
+        let now = chrono::Utc::now().naive_utc();
+
+        // The names of the previous version
+        let legacy = "greefine-app-main";
+        let dates = [now - chrono::Duration::days(1), now];
+        for date in dates {
+            let history = ReportHistory {
+                repository_name: "GreeFine/app".to_string(),
+                branch: "main".to_string(),
+                commit: None,
+                name: legacy.to_string(),
+                comparison: Comparison::default(),
+                date,
+            };
+            storage.insert(&date.to_string(), &history).unwrap();
+            let page = format!("{legacy}/{}/index.html", upload_id(date));
+            artifacts.html.put(&page, b"html").unwrap();
+        }
+        let repository = ReportRepository {
+            repository_name: "GreeFine/app".to_string(),
+        };
+        storage.insert(legacy, &repository).unwrap();
+        raw_reports::store(&storage, json, legacy, b"{}").unwrap();
+        fs::create_dir_all(output.sources_dir.join(legacy)).unwrap();
+        fs::create_dir_all(output.repositories_dir.join("greefine-app")).unwrap();
+
+        assert_eq!(migrate_names(&storage, &artifacts, &output).unwrap(), 1);
+        let name = branch_name("GreeFine/app", "main");
+        let history: Vec<ReportHistory> = storage.get_all().unwrap();
+        assert!(history.iter().all(|entry| entry.name == name));
+        assert_eq!(
+            report_repository(&storage, &name).unwrap().as_deref(),
+            Some("GreeFine/app")
+        );
+        assert!(report_repository(&storage, legacy).unwrap().is_none());
+        assert_eq!(
+            raw_reports::load(&storage, json, &name).unwrap().as_deref(),
+            Some(&b"{}"[..])
+        );
+        assert!(storage.get::<RawReport>(legacy).unwrap().is_none());
+        for date in dates {
+            let page = format!("{name}/{}/index.html", upload_id(date));
+            assert!(artifacts.html.exists(&page).unwrap());
+        }
+        assert!(artifacts.html.list(legacy).unwrap().is_empty());
+        assert!(output.sources_dir.join(&name).exists());
+        assert!(output
+            .repositories_dir
+            .join(clone_name("GreeFine/app"))
+            .exists());
+        // Already migrated
+        assert_eq!(migrate_names(&storage, &artifacts, &output).unwrap(), 0);
+
+        // A name is only used by its repository and branch
+        assert!(check_unique_name(
+            &storage,
+            &request("https://github.com/GreeFine/app", "main")
+        )
+        .is_ok());
+        assert!(
+            check_unique_name(&storage, &request("https://github.com/GreeFine/app", "dev")).is_ok()
+        );
+        let other = request("https://github.com/GreeFine/other", "main");
+        storage.insert(&other.unique_name(), &repository).unwrap();
+        assert!(matches!(
+            check_unique_name(&storage, &other),
+            Err(ApiError::NameConflict(_))
+        ));
+    }
+
+    #[test]
+    fn test_html_layout() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        let storage = memory_db();
+        let now = chrono::Utc::now().naive_utc();
+        for (name, minutes_ago) in [("app-main", 2), ("app-main", 1), ("app-feature", 3)] {
+            let date = now - chrono::Duration::minutes(minutes_ago);
+            let history = ReportHistory {
+                repository_name: "GreeFine/app".to_string(),
+                branch: name.trim_start_matches("app-").to_string(),
+                commit: None,
+                name: name.to_string(),
+                comparison: Comparison::default(),
+                date,
+            };
+            storage.insert(&date.to_string(), &history).unwrap();
+            let repository = ReportRepository {
+                repository_name: "GreeFine/app".to_string(),
+            };
+            storage.insert(name, &repository).unwrap();
+        }
+        let latest = latest_upload(&storage, "app-main").unwrap().unwrap();
+        assert_eq!(latest.date, now - chrono::Duration::minutes(1));
+        assert!(latest_upload(&storage, "app-fix").unwrap().is_none());
+        let id = latest.upload_id();
+        assert!(id.chars().all(|c| c.is_ascii_alphanumeric()), "{id}");
+
+        // A report written before the uploads had their own directory
+        let legacy = dir.join("app-main");
+        fs::create_dir_all(legacy.join("src")).unwrap();
+        fs::write(legacy.join("index.html"), "html").unwrap();
+        fs::write(legacy.join("src/lib.rs.html"), "html").unwrap();
+        // Already in the directory of its upload
+        let feature = dir.join("app-feature").join("20240101T000000000000000");
+        fs::create_dir_all(&feature).unwrap();
+        fs::write(feature.join("index.html"), "html").unwrap();
+
+        let html = LocalStore::new(dir);
+        assert_eq!(migrate_html_layout(&storage, &html).unwrap(), 1);
+        assert!(!legacy.join("index.html").exists());
+        assert!(!legacy.join("src").exists());
+        assert!(legacy.join(&id).join("index.html").exists());
+        assert!(legacy.join(&id).join("src/lib.rs.html").exists());
+        assert!(feature.join("index.html").exists());
+        assert_eq!(migrate_html_layout(&storage, &html).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_history_indexes() {
+        check_history_indexes(memory_db());
+        check_history_indexes(sqlite_storage());
+    }
+
+    fn check_history_indexes(storage: TypedDb) {
+        let start = chrono::NaiveDate::from_ymd_opt(1969, 12, 31)
+            .unwrap()
+            .and_hms_nano_opt(23, 59, 59, 500_000_000)
+            .unwrap();
+        let upload = |repository_name: &str, branch: &str, millis: i64| {
+            let date = start + chrono::Duration::milliseconds(millis);
+            let history = ReportHistory {
+                repository_name: repository_name.to_string(),
+                branch: branch.to_string(),
+                commit: None,
+                name: format!("{}-{branch}", repository_name.replace('/', "-")),
+                comparison: Comparison::default(),
+                date,
+            };
+            storage.insert(&date.to_string(), &history).unwrap();
+            let repository = ReportRepository {
+                repository_name: history.repository_name.clone(),
+            };
+            storage.insert(&history.name, &repository).unwrap();
+            history
+        };
+        // Before and after 1970, with fractions of a second of different lengths
+        upload("GreeFine/app", "main", 0);
+        upload("GreeFine/app", "feature", 500);
+        upload("GreeFine/app", "main", 1_000);
+        upload("GreeFine/app", "main", 1_250);
+        let removed = upload("GreeFine/app", "feature", 2_000);
+        upload("GreeFine/other", "main", 3_000);
+        storage
+            .remove::<ReportHistory>(&removed.date.to_string())
+            .unwrap();
+        let collect = |history: Box<dyn Iterator<Item = _>>| -> Vec<(String, i64)> {
+            history
+                .map(|report: Result<ReportHistory, ApiError>| {
+                    let report = report.unwrap();
+                    let millis = (report.date - start).num_milliseconds();
+                    (report.branch, millis)
+                })
+                .collect()
+        };
+
+        assert_eq!(
+            collect(ReportHistory::recent(&storage).unwrap()),
+            [
+                ("main".to_string(), 3_000),
+                ("main".to_string(), 1_250),
+                ("main".to_string(), 1_000),
+                ("feature".to_string(), 500),
+                ("main".to_string(), 0),
+            ]
+        );
+        assert_eq!(
+            collect(ReportHistory::of_branch(&storage, "GreeFine/app", "main").unwrap()),
+            [
+                ("main".to_string(), 1_250),
+                ("main".to_string(), 1_000),
+                ("main".to_string(), 0)
+            ]
+        );
+        assert_eq!(
+            ReportHistory::of_repository(&storage, "GreeFine/app")
+                .unwrap()
+                .count(),
+            4
+        );
+        // A prefix of another repository name
+        assert_eq!(
+            ReportHistory::of_repository(&storage, "GreeFine/ap")
+                .unwrap()
+                .count(),
+            0
+        );
+        let latest = ReportHistory::latest_per_branch(&storage, None).unwrap();
+        let latest: Vec<_> = latest
+            .iter()
+            .map(|report| (report.repository_name.as_str(), report.branch.as_str()))
+            .collect();
+        assert_eq!(
+            latest,
+            [
+                ("GreeFine/other", "main"),
+                ("GreeFine/app", "main"),
+                ("GreeFine/app", "feature")
+            ]
+        );
+        assert_eq!(
+            ReportHistory::latest_per_branch(&storage, Some("GreeFine/app"))
+                .unwrap()
+                .len(),
+            2
+        );
+        assert_eq!(
+            latest_upload(&storage, "GreeFine-app-main")
+                .unwrap()
+                .map(|report| report.date),
+            Some(start + chrono::Duration::milliseconds(1_250))
+        );
+
+        // Pages of the readable uploads
+        let query = HistoryQuery {
+            per_page: Some(2),
+            ..Default::default()
+        };
+        let readable = |report: &ReportHistory| Ok(report.repository_name == "GreeFine/app");
+        let (page, next_page) = history_page(&storage, &query, None, readable).unwrap();
+        assert_eq!(page.len(), 2);
+        assert_eq!(page[0].date, start + chrono::Duration::milliseconds(1_250));
+        assert_eq!(next_page, Some(2));
+        let query = HistoryQuery {
+            page: Some(2),
+            ..query
+        };
+        let (page, next_page) = history_page(&storage, &query, None, readable).unwrap();
+        assert_eq!((page.len(), next_page), (2, None));
+        let query = HistoryQuery {
+            branch: Some("feature".to_string()),
+            ..Default::default()
+        };
+        let (page, _) = history_page(&storage, &query, Some(50), readable).unwrap();
+        assert_eq!(page.len(), 1);
+        let query = HistoryQuery {
+            latest: true,
+            ..Default::default()
+        };
+        let (page, _) = history_page(&storage, &query, None, |_| Ok(true)).unwrap();
+        assert_eq!(page.len(), 3);
+
+        // Concurrent uploads and removals keep the indexes consistent with the records
+        let threads: Vec<_> = (0..4)
+            .map(|thread| {
+                let storage = storage.clone();
+                std::thread::spawn(move || {
+                    for i in 0..50 {
+                        let date = start + chrono::Duration::seconds(10 + thread * 100 + i);
+                        let history = ReportHistory {
+                            repository_name: format!("GreeFine/concurrent-{}", i % 3),
+                            branch: format!("branch-{}", i % 5),
+                            commit: None,
+                            name: format!("concurrent-{i}"),
+                            comparison: Comparison::default(),
+                            date,
+                        };
+                        storage.insert(&date.to_string(), &history).unwrap();
+                        if i % 2 == 0 {
+                            storage.remove::<ReportHistory>(&date.to_string()).unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let records = storage.get_all::<ReportHistory>().unwrap().len();
+        assert_eq!(records, 5 + 4 * 25);
+        assert_eq!(ReportHistory::recent(&storage).unwrap().count(), records);
+        assert_eq!(
+            ReportHistory::by_branch(&storage, None).unwrap().count(),
+            records
+        );
+        assert_eq!(storage.rebuild_indexes::<ReportHistory>().unwrap(), records);
+    }
+
+    #[test]
+    fn test_get_names() {
+        let request =
+            test_utils::request("https://github.com/GreeFine/llvm-cov-host", "main/aqwqe/2");
+        assert_eq!(
+            request.unique_name(),
+            "greefine-llvm-cov-host-main-aqwqe-2-b31639e26561"
+        );
+        assert_eq!(request.raw_repository_name(), "GreeFine/llvm-cov-host");
+
+        let request =
+            test_utils::request("weird://github.com/GreeFine/llvm-cov-host", "main/aqwqe/2");
+        assert_eq!(
+            request.unique_name(),
+            "weird-github-com-greefine-llvm-cov-host-main-aqwqe-2-31ff7d6764cb"
+        );
+        assert_eq!(
+            request.raw_repository_name(),
+            "weird-github-com-greefine-llvm-cov-host"
+        );
+
+        // The url safe names read the same, but the branches have their own name
+        let name = |git: &str, branch: &str| test_utils::request(git, branch).unique_name();
+        let names = [
+            name("https://github.com/org/repo", "a-main"),
+            name("https://github.com/org/repo-a", "main"),
+            name("https://github.com/org/repo", "a/main"),
+            name("https://github.com/org-repo/a", "main"),
+        ];
+        assert!(names[0].starts_with("org-repo-a-main-"));
+        for (i, name) in names.iter().enumerate() {
+            assert!(!names[i + 1..].contains(name), "{name}");
+        }
+
+        for git in [
+            "file:///srv/mirrors/GreeFine/llvm-cov-host.git",
+            "/srv/mirrors/GreeFine/llvm-cov-host.git/",
+            "../mirrors/GreeFine/llvm-cov-host",
+        ] {
+            let request = test_utils::request(git, "main");
+            assert_eq!(request.raw_repository_name(), "GreeFine/llvm-cov-host");
+        }
+    }
+
+    #[test]
+    fn test_raw_report_with_local_repository() {
+        use std::fs::File;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let local_repository = temp_dir.path();
+        let dir = local_repository.join("api/src");
+        fs::create_dir_all(&dir).unwrap();
+        File::create(dir.join("compare.rs")).unwrap();
+
+        let remote_filepath = "/home/greefine/Projects/llvm-cov-host/api/src/compare.rs";
+        let old_path = find_matching_project_path(local_repository, remote_filepath).unwrap();
+
+        assert_eq!(old_path, "/home/greefine/Projects/llvm-cov-host");
+    }
+
+    #[test]
+    fn test_upload_encodings() {
+        use std::io::Write;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        let local_repository = dir.join("repository");
+        fs::create_dir_all(local_repository.join("src")).unwrap();
+        fs::write(local_repository.join("src/main.rs"), "fn main() {}").unwrap();
+
+        let segments = serde_json::json!([
+            [1, 11, 1, true, true, false],
+            [1, 13, 0, false, false, false]
+        ]);
+        let regions = serde_json::json!([[1, 11, 1, 13, 1, 0, 0, 0]]);
+        let request = serde_json::json!({
+            "git": "https://github.com/GreeFine/app",
+            "branch": "main",
+            "json_report": {
+                "cargo_llvm_cov": { "manifest_path": "/builds/app/Cargo.toml", "version": "0.5.0" },
+                "data": [{
+                    "files": [{
+                        "filename": "/builds/app/src/main.rs",
+                        "segments": segments,
+                        "branches": [],
+                        "expansions": [],
+                        "summary": file_summary(1, 1),
+                    }],
+                    "functions": [{
+                        "name": "main",
+                        "count": 1,
+                        "regions": regions,
+                        "branches": [],
+                        "filenames": ["/builds/app/src/main.rs"],
+                    }],
+                    "totals": file_summary(1, 1),
+                }],
+                "type": "llvm.coverage.json.export",
+                "version": "2.0.1",
+            },
+        });
+        let content = serde_json::to_vec(&request).unwrap();
+        let plain = dir.join("request.json");
+        fs::write(&plain, &content).unwrap();
+        let gzip = dir.join("request.json.gz");
+        let mut encoder = flate2::write::GzEncoder::new(
+            fs::File::create(&gzip).unwrap(),
+            flate2::Compression::default(),
+        );
+        encoder.write_all(&content).unwrap();
+        encoder.finish().unwrap();
+        let zstd = dir.join("request.json.zst");
+        fs::write(&zstd, zstd::encode_all(content.as_slice(), 3).unwrap()).unwrap();
+
+        for (path, encoding) in [(&plain, "identity"), (&gzip, "x-gzip"), (&zstd, "zstd")] {
+            let encoding: UploadEncoding = encoding.parse().unwrap();
+            let mut request = Request::from_upload(path, encoding, content.len()).unwrap();
+            assert_eq!(request.unique_name(), "greefine-app-main-9f9ff1a210c4");
+
+            // The sections the server doesn't read are saved as uploaded
+            let json_path = dir.join("report.json");
+            let unresolved = save_json(
+                &mut request.json_report,
+                &local_repository,
+                &[],
+                &FileFilter::default(),
+                &json_path,
+            )
+            .unwrap();
+            assert!(unresolved.is_empty());
+            let saved: serde_json::Value =
+                serde_json::from_slice(&fs::read(&json_path).unwrap()).unwrap();
+            let data = &saved["data"][0];
+            assert_eq!(
+                data["files"][0]["filename"],
+                local_repository
+                    .join("src/main.rs")
+                    .to_string_lossy()
+                    .as_ref()
+            );
+            assert_eq!(data["files"][0]["segments"], segments);
+            assert_eq!(data["files"][0]["expansions"], serde_json::json!([]));
+            assert_eq!(data["functions"][0]["regions"], regions);
+            assert_eq!(data["functions"][0]["count"], 1);
+            assert!(data["files"][0].get("mcdc_records").is_none());
+
+            // Decompressed past the limit
+            assert!(matches!(
+                Request::from_upload(path, encoding, content.len() - 1),
+                Err(ApiError::UploadTooLarge(_))
+            ));
+        }
+        assert!(matches!(
+            "br".parse::<UploadEncoding>(),
+            Err(ApiError::UnsupportedEncoding(_))
+        ));
+        assert!(matches!(
+            Request::from_upload(&gzip, UploadEncoding::Identity, content.len()),
+            Err(ApiError::SerdeError(_))
+        ));
+    }
 }
@@ -0,0 +1,157 @@
+use std::{fs, io, path::Path};
+
+use serde::Deserialize;
+
+use crate::{
+    config,
+    error::{ApiError, ApiResult},
+    filter::{glob_set, FileFilter},
+    groups::CrateGroup,
+    notify::Notification,
+    quality_gate::QualityGate,
+};
+
+/// Configuration file read from the root of the repository
+pub const REPOSITORY_CONFIG_FILE: &str = ".llvm-cov-host.toml";
+
+/// Coverage settings managed by each repository, in [REPOSITORY_CONFIG_FILE]
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RepositoryConfig {
+    /// Branch the other branches are compared to, default to `default_report_branch` of the server configuration
+    base_branch: Option<String>,
+    #[serde(default)]
+    pub quality_gate: QualityGate,
+    /// Files counted in the coverage
+    #[serde(default)]
+    pub filter: FileFilter,
+    /// Coverage reported per group of files
+    #[serde(default)]
+    pub groups: Vec<CrateGroup>,
+    #[serde(default)]
+    pub notifications: Vec<Notification>,
+}
+
+impl RepositoryConfig {
+    pub fn parse(content: &str) -> ApiResult<Self> {
+        let repository_config: Self =
+            toml::from_str(content).map_err(|e| ApiError::RepositoryConfig(e.to_string()))?;
+        repository_config
+            .validate()
+            .map_err(ApiError::RepositoryConfig)?;
+        Ok(repository_config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.base_branch.as_ref().is_some_and(|b| b.is_empty()) {
+            return Err("base_branch can't be empty".to_string());
+        }
+        self.quality_gate.validate()?;
+        self.filter.compile().map_err(|e| format!("filter: {e}"))?;
+        for group in &self.groups {
+            if group.name.is_empty() {
+                return Err("groups need a name".to_string());
+            }
+            glob_set(group.paths.iter().map(String::as_str))
+                .map_err(|e| format!("group {}: {e}", group.name))?;
+        }
+        for notification in &self.notifications {
+            notification.validate()?;
+        }
+        Ok(())
+    }
+
+    pub fn base_branch(&self) -> &str {
+        self.base_branch
+            .as_deref()
+            .unwrap_or(&config::get().default_report_branch)
+    }
+
+    /// Read the configuration of the repository, the default one is used if the file doesn't exist
+    pub fn load(repository_path: &Path) -> ApiResult<Self> {
+        match fs::read_to_string(repository_path.join(REPOSITORY_CONFIG_FILE)) {
+            Ok(content) => Self::parse(&content),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(error) => Err(error.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::{
+        compare::Comparison,
+        groups::group_coverage,
+        test_utils::{file_summary, llvm_cov_export},
+    };
+
+    #[test]
+    fn test_repository_config() {
+        let config = RepositoryConfig::parse(
+            r#"
+        base_branch = "develop"
+
+        [quality_gate]
+        min_coverage = 50
+        max_decrease = 1.5
+
+        [[groups]]
+        name = "api"
+        paths = ["api/**"]
+
+        [[groups]]
+        name = "web"
+        paths = ["web/**"]
+
+        [[notifications]]
+        webhook = "http://localhost:9000/coverage"
+        only_on_failure = true
+        "#,
+        )
+        .unwrap();
+        assert_eq!(config.base_branch(), "develop");
+        assert_eq!(RepositoryConfig::default().base_branch(), "main");
+
+        let gate = |new, diff| {
+            config.quality_gate.evaluate(&Comparison {
+                base: None,
+                new,
+                diff,
+            })
+        };
+        assert!(gate(60.0, Some(-1.0)).unwrap().passed);
+        assert!(gate(60.0, None).unwrap().passed);
+        assert_eq!(gate(40.0, Some(-2.0)).unwrap().failures.len(), 2);
+        assert!(RepositoryConfig::default()
+            .quality_gate
+            .evaluate(&Comparison::default())
+            .is_none());
+
+        let report = llvm_cov_export(serde_json::json!([{
+            "files": [
+                { "filename": "/repo/api/src/main.rs", "summary": file_summary(1, 4) },
+                { "filename": "/repo/api/src/lib.rs", "summary": file_summary(3, 4) },
+                { "filename": "/repo/web/src/main.rs", "summary": file_summary(1, 1) },
+            ],
+            "functions": [],
+            "totals": file_summary(5, 9),
+        }]));
+        let groups = group_coverage(&config.groups, &report, Path::new("/repo")).unwrap();
+        assert_eq!(groups[0].name, "api");
+        assert_eq!(groups[0].functions, 50.0);
+        assert_eq!(groups[1].lines, 100.0);
+
+        for invalid in [
+            "unknown = true",
+            "base_branch = \"\"",
+            "[quality_gate]\nmin_coverage = 101",
+            "[[groups]]\nname = \"api\"\npaths = [\"[\"]",
+            "[[notifications]]\nwebhook = \"ftp://example.com\"",
+        ] {
+            assert!(RepositoryConfig::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,605 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    sync::{PoisonError, RwLock, RwLockReadGuard},
+    thread,
+    time::Duration,
+};
+
+use chrono::{NaiveDateTime, Utc};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    artifacts::Artifacts,
+    audit::{Actor, AuditAction, AuditEntry},
+    config::{self, OutputConfig, RetentionConfig},
+    error::{ApiError, ApiResult},
+    oidc_login::OidcLoginState,
+    raw_reports,
+    report::{clone_name, BaseBranch, ReportHistory, ReportRepository},
+    storage::TypedDb,
+    users::Session,
+    utils::remove_path,
+};
+
+/// Held by the uploads while they write to the output directories, the garbage collection waits for them
+static OUTPUT_LOCK: RwLock<()> = RwLock::new(());
+
+pub fn output_guard() -> RwLockReadGuard<'static, ()> {
+    OUTPUT_LOCK.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// What a garbage collection or a deletion removed
+#[derive(Debug, Default, Serialize)]
+pub struct RemovalReport {
+    /// Entries of the branches history, including the ones of the removed branches
+    pub removed_reports: usize,
+    pub removed_branches: usize,
+    /// Clones and uploaded sources
+    pub removed_clones: usize,
+    /// Expired sessions and OIDC sign ins
+    pub removed_sessions: usize,
+    /// Size of the removed files
+    pub freed_bytes: u64,
+}
+
+impl RemovalReport {
+    fn is_empty(&self) -> bool {
+        self.removed_reports == 0
+            && self.removed_branches == 0
+            && self.removed_clones == 0
+            && self.removed_sessions == 0
+    }
+}
+
+impl Display for RemovalReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "removed {} reports, {} branches, {} clones, {} sessions, freed {:.1} MiB",
+            self.removed_reports,
+            self.removed_branches,
+            self.removed_clones,
+            self.removed_sessions,
+            self.freed_bytes as f64 / (1024.0 * 1024.0)
+        )
+    }
+}
+
+/// Uploads of a branch, the most recent first
+#[derive(Debug)]
+struct BranchHistory {
+    repository_name: String,
+    branch: String,
+    /// [crate::report::Request::unique_name], the name of its HTML, JSON and sources
+    name: String,
+    reports: Vec<ReportHistory>,
+}
+
+impl BranchHistory {
+    fn last_upload(&self) -> NaiveDateTime {
+        self.reports[0].date
+    }
+}
+
+/// The history grouped by branch, of the repository or of all of them
+fn branch_histories(
+    storage: &TypedDb,
+    repository_name: Option<&str>,
+) -> ApiResult<Vec<BranchHistory>> {
+    let mut branches: Vec<BranchHistory> = vec![];
+    for entry in ReportHistory::by_branch(storage, repository_name)? {
+        let entry = entry?;
+        match branches.last_mut() {
+            Some(branch)
+                if branch.repository_name == entry.repository_name
+                    && branch.branch == entry.branch =>
+            {
+                branch.reports.push(entry)
+            }
+            _ => branches.push(BranchHistory {
+                repository_name: entry.repository_name.clone(),
+                branch: entry.branch.clone(),
+                name: entry.name.clone(),
+                reports: vec![entry],
+            }),
+        }
+    }
+    Ok(branches)
+}
+
+/// Remove the entries of the history, and the HTML reports of their uploads
+fn remove_history(
+    storage: &TypedDb,
+    artifacts: &Artifacts,
+    reports: &[ReportHistory],
+    removal: &mut RemovalReport,
+) -> ApiResult<()> {
+    for entry in reports {
+        let upload_dir = format!("{}/{}", entry.name, entry.upload_id());
+        removal.freed_bytes += artifacts.html.delete_dir(&upload_dir)?.unwrap_or_default();
+        // The key of the history is the date of the upload
+        storage.remove::<ReportHistory>(&entry.date.to_string())?;
+    }
+    removal.removed_reports += reports.len();
+    Ok(())
+}
+
+/// Remove the history, JSON, HTML, sources and baseline of the branch
+fn remove_branch(
+    storage: &TypedDb,
+    artifacts: &Artifacts,
+    output: &OutputConfig,
+    branch: &BranchHistory,
+    removal: &mut RemovalReport,
+) -> ApiResult<()> {
+    let freed = [
+        raw_reports::remove(storage, artifacts.json.as_ref(), &branch.name)?,
+        artifacts.html.delete_dir(&branch.name)?,
+        remove_path(&output.sources_dir.join(&branch.name))?,
+    ];
+    removal.freed_bytes += freed.into_iter().flatten().sum::<u64>();
+    storage.remove::<ReportRepository>(&branch.name)?;
+    remove_history(storage, artifacts, &branch.reports, removal)?;
+    removal.removed_branches += 1;
+    Ok(())
+}
+
+fn remove_clone(
+    output: &OutputConfig,
+    repository_name: &str,
+    removal: &mut RemovalReport,
+) -> ApiResult<()> {
+    let clone = output.repositories_dir.join(clone_name(repository_name));
+    if let Some(size) = remove_path(&clone)? {
+        removal.freed_bytes += size;
+        removal.removed_clones += 1;
+    }
+    Ok(())
+}
+
+/// Apply the retention policy, the history of the base branches is always kept
+pub fn collect_garbage(
+    storage: &TypedDb,
+    artifacts: &Artifacts,
+    retention: &RetentionConfig,
+    output: &OutputConfig,
+    now: NaiveDateTime,
+) -> ApiResult<RemovalReport> {
+    let _output = OUTPUT_LOCK.write().unwrap_or_else(PoisonError::into_inner);
+    let mut removal = RemovalReport::default();
+    let expired = |days: u32, date: NaiveDateTime| {
+        days > 0 && date < now - chrono::Duration::days(days.into())
+    };
+
+    let mut last_uploads: HashMap<String, NaiveDateTime> = HashMap::new();
+    for branch in branch_histories(storage, None)? {
+        let base_branch = storage
+            .get::<BaseBranch>(&branch.repository_name)?
+            .map_or_else(|| config::get().default_report_branch.clone(), |b| b.branch);
+        let is_base = branch.branch == base_branch;
+
+        if !is_base && expired(retention.branch_max_age_days, branch.last_upload()) {
+            info!(
+                "Removing the branch {} of {}, last upload {}",
+                branch.branch,
+                branch.repository_name,
+                branch.last_upload()
+            );
+            remove_branch(storage, artifacts, output, &branch, &mut removal)?;
+            continue;
+        }
+
+        let last_upload = last_uploads
+            .entry(branch.repository_name.clone())
+            .or_insert(branch.last_upload());
+        *last_upload = (*last_upload).max(branch.last_upload());
+        if expired(retention.clone_max_age_days, branch.last_upload()) {
+            let sources = output.sources_dir.join(&branch.name);
+            if let Some(size) = remove_path(&sources)? {
+                removal.freed_bytes += size;
+                removal.removed_clones += 1;
+            }
+        }
+        let keep = retention.keep_reports_per_branch;
+        if !is_base && keep > 0 && branch.reports.len() > keep {
+            remove_history(storage, artifacts, &branch.reports[keep..], &mut removal)?;
+        }
+    }
+
+    for (repository_name, last_upload) in last_uploads {
+        if expired(retention.clone_max_age_days, last_upload) {
+            info!("Removing the clone of {repository_name}, last upload {last_upload}");
+            remove_clone(output, &repository_name, &mut removal)?;
+        }
+    }
+
+    removal.removed_sessions =
+        Session::remove_expired(storage)? + OidcLoginState::remove_expired(storage)?;
+    Ok(removal)
+}
+
+/// Query of `DELETE /report`, a whole repository without a branch, a single report with a date
+#[derive(Debug, Deserialize)]
+pub struct Deletion {
+    /// `owner/project` name of the repository
+    pub repository: String,
+    pub branch: Option<String>,
+    /// Date of the report, as listed by `GET /reports`
+    pub date: Option<NaiveDateTime>,
+}
+
+/// Remove the reports of the deletion, fails with [ApiError::ReportNotFound] if there are none
+pub fn delete_reports(
+    storage: &TypedDb,
+    artifacts: &Artifacts,
+    output: &OutputConfig,
+    deletion: &Deletion,
+) -> ApiResult<RemovalReport> {
+    let _output = OUTPUT_LOCK.write().unwrap_or_else(PoisonError::into_inner);
+    let mut removal = RemovalReport::default();
+
+    let branches: Vec<BranchHistory> = branch_histories(storage, Some(&deletion.repository))?
+        .into_iter()
+        .filter(|branch| {
+            deletion
+                .branch
+                .as_ref()
+                .is_none_or(|name| &branch.branch == name)
+        })
+        .collect();
+    if branches.is_empty() {
+        return Err(ApiError::ReportNotFound);
+    }
+
+    match (&deletion.branch, deletion.date) {
+        (None, Some(_)) => {
+            return Err(ApiError::InvalidDeletion(
+                "a report is deleted with its branch and date".to_string(),
+            ))
+        }
+        (Some(_), Some(date)) => {
+            let branch = &branches[0];
+            let report = branch
+                .reports
+                .iter()
+                .position(|report| report.date == date)
+                .ok_or(ApiError::ReportNotFound)?;
+            // The JSON is the one of the branch, it is removed with its last report
+            if branch.reports.len() == 1 {
+                remove_branch(storage, artifacts, output, branch, &mut removal)?;
+            } else {
+                let reports = &branch.reports[report..=report];
+                remove_history(storage, artifacts, reports, &mut removal)?;
+            }
+        }
+        (Some(_), None) => {
+            for branch in &branches {
+                remove_branch(storage, artifacts, output, branch, &mut removal)?;
+            }
+        }
+        (None, None) => {
+            for branch in &branches {
+                remove_branch(storage, artifacts, output, branch, &mut removal)?;
+            }
+            remove_clone(output, &deletion.repository, &mut removal)?;
+            storage.remove::<BaseBranch>(&deletion.repository)?;
+        }
+    }
+    Ok(removal)
+}
+
+/// Run [collect_garbage] with the server configuration, and record it in the audit log
+pub fn run(storage: &TypedDb, artifacts: &Artifacts, actor: &Actor) -> ApiResult<RemovalReport> {
+    let config = config::get();
+    let result = collect_garbage(
+        storage,
+        artifacts,
+        &config.retention,
+        &config.output,
+        Utc::now().naive_utc(),
+    );
+    match &result {
+        Ok(report) if report.is_empty() => return result,
+        Ok(report) => info!("Garbage collection: {report}"),
+        Err(e) => error!("Garbage collection failed: {e:#}"),
+    }
+    AuditEntry {
+        details: result.as_ref().ok().map(RemovalReport::to_string),
+        ..AuditEntry::new(actor, AuditAction::GarbageCollection)
+    }
+    .record_result(storage, &result);
+    result
+}
+
+/// Collect the garbage at startup, then every `retention.gc_interval_minutes`
+pub fn spawn_gc(storage: TypedDb, artifacts: Artifacts) {
+    let interval = Duration::from_secs(config::get().retention.gc_interval_minutes * 60);
+    let actor = Actor {
+        name: "retention policy".to_string(),
+        client_ip: None,
+    };
+    thread::spawn(move || loop {
+        // Errors are logged, the next run tries again
+        let _ = run(&storage, &artifacts, &actor);
+        thread::sleep(interval);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{
+        compare::Comparison,
+        raw_reports::RawReport,
+        report::{branch_name, report_repository, upload_id},
+        test_utils::{file_summary, llvm_cov_export, memory_db},
+    };
+
+    #[test]
+    fn test_retention() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        let output = OutputConfig {
+            json_reports_dir: dir.join("json"),
+            html_reports_dir: dir.join("html"),
+            repositories_dir: dir.join("repositories"),
+            sources_dir: dir.join("sources"),
+            sled_dir: dir.join("sled"),
+            ..OutputConfig::default()
+        };
+        let storage = memory_db();
+        let artifacts = Artifacts::local(&output);
+        let now = chrono::Utc::now().naive_utc();
+
+        let upload = |repository_name: &str, branch: &str, days_ago: i64| {
+            let name = branch_name(repository_name, branch);
+            let date = now - chrono::Duration::days(days_ago);
+            storage
+                .insert(
+                    &date.to_string(),
+                    &ReportHistory {
+                        repository_name: repository_name.to_string(),
+                        branch: branch.to_string(),
+                        commit: None,
+                        name: name.clone(),
+                        comparison: Comparison::default(),
+                        date,
+                    },
+                )
+                .unwrap();
+            storage
+                .insert(
+                    &name,
+                    &ReportRepository {
+                        repository_name: repository_name.to_string(),
+                    },
+                )
+                .unwrap();
+            let upload_dir = output.html_reports_dir.join(&name).join(upload_id(date));
+            fs::create_dir_all(&upload_dir).unwrap();
+            fs::write(upload_dir.join("index.html"), "html").unwrap();
+            let content = format!("{{\"name\": \"{name}\"}}");
+            raw_reports::store(&storage, artifacts.json.as_ref(), &name, content.as_bytes())
+                .unwrap();
+        };
+        for repository_name in ["GreeFine/app", "GreeFine/old"] {
+            storage
+                .insert(
+                    repository_name,
+                    &BaseBranch {
+                        branch: "main".to_string(),
+                    },
+                )
+                .unwrap();
+        }
+        for days_ago in [100, 101, 102] {
+            upload("GreeFine/app", "main", days_ago);
+        }
+        for days_ago in [1, 2, 3] {
+            upload("GreeFine/app", "feature", days_ago);
+        }
+        upload("GreeFine/app", "stale", 40);
+        upload("GreeFine/old", "main", 10);
+        for repository_name in ["GreeFine/app", "GreeFine/old"] {
+            let clone = output.repositories_dir.join(clone_name(repository_name));
+            fs::create_dir_all(&clone).unwrap();
+            fs::write(clone.join("lib.rs"), "12345").unwrap();
+        }
+        let stale = branch_name("GreeFine/app", "stale");
+        let feature = branch_name("GreeFine/app", "feature");
+        fs::create_dir_all(output.sources_dir.join(&stale)).unwrap();
+        Session::create(&storage, "alice", vec![], chrono::Duration::hours(-1)).unwrap();
+        let session = Session::create(&storage, "bob", vec![], chrono::Duration::hours(1)).unwrap();
+
+        let retention = RetentionConfig {
+            keep_reports_per_branch: 2,
+            branch_max_age_days: 30,
+            clone_max_age_days: 7,
+            ..Default::default()
+        };
+        let stale_raw = storage.get::<RawReport>(&stale).unwrap().unwrap();
+        let stale_blob = format!("sha256/{}.zst", stale_raw.digest);
+        let (_, stale_blob_size) = artifacts
+            .json
+            .list("sha256")
+            .unwrap()
+            .into_iter()
+            .find(|(key, _)| *key == stale_blob)
+            .unwrap();
+        let report = collect_garbage(&storage, &artifacts, &retention, &output, now).unwrap();
+        assert_eq!(report.removed_branches, 1);
+        // The oldest upload of feature, and the one of stale
+        assert_eq!(report.removed_reports, 2);
+        assert_eq!(report.removed_clones, 1);
+        assert_eq!(report.removed_sessions, 1);
+        // The clone, the HTML and JSON of stale, and the HTML of the oldest upload of feature
+        assert_eq!(report.freed_bytes, 5 + 4 + stale_blob_size + 4);
+
+        let history: Vec<ReportHistory> = storage.get_all().unwrap();
+        let count = |repository_name: &str, branch: &str| {
+            let name = branch_name(repository_name, branch);
+            history.iter().filter(|r| r.name == name).count()
+        };
+        // The history of the base branches is always kept
+        assert_eq!(count("GreeFine/app", "main"), 3);
+        assert_eq!(count("GreeFine/old", "main"), 1);
+        assert_eq!(count("GreeFine/app", "feature"), 2);
+        assert_eq!(count("GreeFine/app", "stale"), 0);
+        assert!(report_repository(&storage, &stale).unwrap().is_none());
+        assert!(!output.html_reports_dir.join(&stale).exists());
+        assert!(storage.get::<RawReport>(&stale).unwrap().is_none());
+        assert!(!artifacts.json.exists(&stale_blob).unwrap());
+        assert!(
+            raw_reports::load(&storage, artifacts.json.as_ref(), &feature)
+                .unwrap()
+                .is_some()
+        );
+        assert!(!output.sources_dir.join(&stale).exists());
+        assert!(output.html_reports_dir.join(&feature).exists());
+        assert!(output
+            .repositories_dir
+            .join(clone_name("GreeFine/app"))
+            .exists());
+        assert!(!output
+            .repositories_dir
+            .join(clone_name("GreeFine/old"))
+            .exists());
+        assert!(Session::find(&storage, &session).unwrap().is_some());
+
+        // Nothing left to remove
+        let report = collect_garbage(&storage, &artifacts, &retention, &output, now).unwrap();
+        assert_eq!(report.removed_reports + report.removed_branches, 0);
+    }
+
+    #[test]
+    fn test_delete_reports() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        let output = OutputConfig {
+            json_reports_dir: dir.join("json"),
+            html_reports_dir: dir.join("html"),
+            repositories_dir: dir.join("repositories"),
+            sources_dir: dir.join("sources"),
+            sled_dir: dir.join("sled"),
+            ..OutputConfig::default()
+        };
+        let storage = memory_db();
+        let now = chrono::Utc::now().naive_utc();
+        let baseline = llvm_cov_export(serde_json::json!([{
+            "files": [], "functions": [], "totals": file_summary(1, 2)
+        }]));
+        let baseline = serde_json::to_vec(&baseline).unwrap();
+        let artifacts = Artifacts::local(&output);
+        let json = artifacts.json.as_ref();
+
+        let upload = |repository_name: &str, branch: &str, minutes_ago: i64| {
+            let name = branch_name(repository_name, branch);
+            let date = now - chrono::Duration::minutes(minutes_ago);
+            let history = ReportHistory {
+                repository_name: repository_name.to_string(),
+                branch: branch.to_string(),
+                commit: None,
+                name: name.clone(),
+                comparison: Comparison::default(),
+                date,
+            };
+            storage.insert(&date.to_string(), &history).unwrap();
+            let repository = ReportRepository {
+                repository_name: repository_name.to_string(),
+            };
+            storage.insert(&name, &repository).unwrap();
+            // The same report for every branch, they share its blob
+            raw_reports::store(&storage, json, &name, &baseline).unwrap();
+            fs::create_dir_all(output.html_reports_dir.join(&name).join(upload_id(date))).unwrap();
+            fs::create_dir_all(output.repositories_dir.join(clone_name(repository_name))).unwrap();
+            date
+        };
+        upload("GreeFine/app", "main", 5);
+        let first = upload("GreeFine/app", "feature", 4);
+        let second = upload("GreeFine/app", "feature", 3);
+        upload("GreeFine/app", "fix", 2);
+        upload("GreeFine/other", "main", 1);
+        let delete = |repository: &str, branch: Option<&str>, date| {
+            let deletion = Deletion {
+                repository: repository.to_string(),
+                branch: branch.map(str::to_string),
+                date,
+            };
+            delete_reports(&storage, &artifacts, &output, &deletion)
+        };
+        let history = |repository_name: &str, branch: &str| {
+            let name = branch_name(repository_name, branch);
+            let history: Vec<ReportHistory> = storage.get_all().unwrap();
+            history.iter().filter(|r| r.name == name).count()
+        };
+        let feature = branch_name("GreeFine/app", "feature");
+
+        // A report of the branch, the JSON is kept for the other one
+        let removal = delete("GreeFine/app", Some("feature"), Some(second)).unwrap();
+        assert_eq!((removal.removed_reports, removal.removed_branches), (1, 0));
+        assert_eq!(history("GreeFine/app", "feature"), 1);
+        assert!(raw_reports::load(&storage, json, &feature)
+            .unwrap()
+            .is_some());
+        let feature_dir = output.html_reports_dir.join(&feature);
+        assert!(!feature_dir.join(upload_id(second)).exists());
+        assert!(feature_dir.join(upload_id(first)).exists());
+        assert!(matches!(
+            delete("GreeFine/app", Some("feature"), Some(second)),
+            Err(ApiError::ReportNotFound)
+        ));
+        // The last report removes the branch
+        let removal = delete("GreeFine/app", Some("feature"), Some(first)).unwrap();
+        assert_eq!((removal.removed_reports, removal.removed_branches), (1, 1));
+        assert!(storage.get::<RawReport>(&feature).unwrap().is_none());
+        assert!(!output.html_reports_dir.join(&feature).exists());
+        // Not freed, the other branches share the blob
+        assert_eq!(removal.freed_bytes, 0);
+
+        // A whole branch
+        let removal = delete("GreeFine/app", Some("fix"), None).unwrap();
+        assert_eq!(removal.removed_branches, 1);
+        assert!(
+            report_repository(&storage, &branch_name("GreeFine/app", "fix"))
+                .unwrap()
+                .is_none()
+        );
+        assert!(matches!(
+            delete("GreeFine/app", None, Some(first)),
+            Err(ApiError::InvalidDeletion(_))
+        ));
+
+        // The repository, with its clone, the other repositories are kept
+        let removal = delete("GreeFine/app", None, None).unwrap();
+        assert_eq!((removal.removed_branches, removal.removed_clones), (1, 1));
+        assert_eq!(history("GreeFine/app", "main"), 0);
+        assert!(
+            raw_reports::load_report(&storage, json, "GreeFine/app", "main")
+                .unwrap()
+                .is_none()
+        );
+        assert!(!output
+            .repositories_dir
+            .join(clone_name("GreeFine/app"))
+            .exists());
+        assert!(matches!(
+            delete("GreeFine/app", None, None),
+            Err(ApiError::ReportNotFound)
+        ));
+        assert_eq!(history("GreeFine/other", "main"), 1);
+        assert!(output
+            .repositories_dir
+            .join(clone_name("GreeFine/other"))
+            .exists());
+        assert!(
+            raw_reports::load_report(&storage, json, "GreeFine/other", "main")
+                .unwrap()
+                .is_some()
+        );
+        assert_eq!(json.list("sha256").unwrap().len(), 1);
+    }
+}
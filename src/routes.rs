@@ -1,37 +1,238 @@
-use std::{path::PathBuf, process::Command, str::FromStr};
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    process::Command,
+};
 
-use actix_web::{get, http::StatusCode, put, web, HttpResponse, Responder};
+use actix_files::NamedFile;
+use actix_multipart::Multipart;
+use actix_web::{
+    cookie::{time::Duration as CookieDuration, Cookie, SameSite},
+    delete,
+    error::PayloadError,
+    get,
+    http::{header, StatusCode},
+    post, put,
+    web::{self, Bytes},
+    HttpRequest, HttpResponse, Responder,
+};
 use askama::Template;
-use chrono::Utc;
-use log::{error, info};
+use chrono::{Duration, Utc};
+use futures_util::{Stream, StreamExt};
+use log::{error, info, warn};
+use serde::Deserialize;
 
 use crate::{
+    api_keys::{ApiKey, NewApiKey, Permission, Principal},
+    artifacts::{self, Artifacts},
+    audit::{Actor, AuditAction, AuditEntry, AuditPage, AuditQuery},
+    backup::{self, ExportQuery, ImportQuery},
     compare, config,
-    dashboard::DashBoardTemplate,
-    error::ApiError,
+    config::ViewerAuthMode,
+    dashboard::{AuditTemplate, DashBoardTemplate, LoginTemplate},
+    error::{ApiError, ApiResult},
     git,
-    model::Report,
-    report::{self, ReportHistory, Request},
+    groups::group_coverage,
+    notify::{notify, NotificationPayload},
+    oidc_login::{OidcLogin, LOGIN_STATE_TTL_MINUTES, STATE_COOKIE as OIDC_STATE_COOKIE},
+    raw_reports,
+    report::{
+        self, HistoryQuery, ReportHistory, ReportRepository, ReportResponse, Request,
+        UploadEncoding,
+    },
+    repository_config::RepositoryConfig,
+    retention::{self, Deletion},
+    sources::SourcesUpload,
     storage::TypedDb,
+    users::{NewUser, Session, User},
+    utils::TempDir,
+    viewer::{Viewer, SESSION_COOKIE},
 };
 
+/// Write the body of an upload to `path` as it is received, it can be larger than the memory.
+/// The upload is aborted once it exceeds `limit` bytes
+pub async fn save_upload(
+    mut payload: impl Stream<Item = Result<Bytes, PayloadError>> + Unpin,
+    path: &Path,
+    limit: usize,
+) -> ApiResult<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    let mut size = 0;
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|e| ApiError::InvalidUpload(e.to_string()))?;
+        size += chunk.len();
+        if size > limit {
+            return Err(ApiError::UploadTooLarge(limit));
+        }
+        file.write_all(&chunk)?;
+    }
+    file.flush()?;
+    Ok(())
+}
+
+/// The [Request] json, optionally compressed with gzip or zstd and its `Content-Encoding`
 #[put("")]
 pub async fn new_report(
     storage: web::Data<TypedDb>,
-    request: web::Json<Request>,
+    artifacts: web::Data<Artifacts>,
+    principal: Principal,
+    req: HttpRequest,
+    payload: web::Payload,
 ) -> impl Responder {
+    let encoding = UploadEncoding::from_headers(req.headers())?;
+    let work_dir = TempDir::new(&format!(
+        "upload-{}",
+        report::upload_id(Utc::now().naive_utc())
+    ))?;
+    let path = work_dir.path().join("request.json");
+    save_upload(payload, &path, config::get().json_limit).await?;
+    let mut request = Request::from_upload(&path, encoding, config::get().json_limit)?;
+    drop(work_dir);
+
     info!("Request git: {}, branch: {}", request.git, request.branch);
-    let report: Report = serde_json::from_value(request.json_report.clone())?;
+    let mut audit = AuditEntry::upload(&Actor::new(&req, &principal), &request);
+    let result =
+        clone_and_generate_report(&storage, &artifacts, &principal, &mut request, &mut audit);
+    audit.record_result(&storage, &result);
+
+    Ok::<_, ApiError>(serde_json::to_string(&result?))
+}
+
+fn clone_and_generate_report(
+    storage: &TypedDb,
+    artifacts: &Artifacts,
+    principal: &Principal,
+    request: &mut Request,
+    audit: &mut AuditEntry,
+) -> ApiResult<ReportResponse> {
+    principal.authorize_upload(request)?;
+    report::check_unique_name(storage, request)?;
+    let _output = retention::output_guard();
+
+    let report_files = request.json_report.data.first().map_or(vec![], |data| {
+        data.files.iter().map(|f| f.filename.as_str()).collect()
+    });
+    let repository_path = git::pull_or_clone(request, &report_files)?;
+    let mut response = generate_report(storage, artifacts, request, &repository_path, audit)?;
+    let base_branch = report::base_branch(
+        storage,
+        &request.raw_repository_name(),
+        &RepositoryConfig::load(&repository_path)?,
+    )?;
+    if request.branch != base_branch {
+        // The coverage is stored already, the upload doesn't fail without the merge-base
+        response.merge_base = git::merge_base(request, &base_branch).unwrap_or_else(|error| {
+            warn!(
+                "Request git: {}, branch: {}: merge-base with {base_branch}: {error}",
+                request.git, request.branch
+            );
+            None
+        });
+    }
+    Ok(response)
+}
 
-    let repository_path = git::pull_or_clone(&request)?;
-    let json_path = report::save_json(&request, &report, &repository_path)?;
+/// Same as [new_report], but the sources are sent with the report instead of cloning the repository
+#[put("/sources")]
+pub async fn new_report_with_sources(
+    storage: web::Data<TypedDb>,
+    artifacts: web::Data<Artifacts>,
+    principal: Principal,
+    req: HttpRequest,
+    payload: Multipart,
+) -> impl Responder {
+    let mut upload = SourcesUpload::from_multipart(payload).await?;
+    info!(
+        "Request with sources git: {}, branch: {}",
+        upload.request.git, upload.request.branch
+    );
+    let mut audit = AuditEntry::upload(&Actor::new(&req, &principal), &upload.request);
+    let result = match principal
+        .authorize_upload(&upload.request)
+        .and_then(|()| report::check_unique_name(&storage, &upload.request))
+    {
+        Ok(()) => upload.receive_sources().await.and_then(|()| {
+            let _output = retention::output_guard();
+            let sources_path = upload.extract()?;
+            generate_report(
+                &storage,
+                &artifacts,
+                &mut upload.request,
+                &sources_path,
+                &mut audit,
+            )
+        }),
+        Err(error) => Err(error),
+    };
+    audit.record_result(&storage, &result);
 
-    // Safety: the str is pre-defined
-    let output_path = PathBuf::from_str(config::HTML_REPORTS_DIR)
-        .unwrap()
-        .canonicalize()
-        .unwrap()
-        .join(request.unique_name());
+    Ok::<_, ApiError>(serde_json::to_string(&result?))
+}
+
+/// Delete a report with `date`, a branch with `branch`, or else the whole repository
+#[delete("")]
+pub async fn delete_reports(
+    storage: web::Data<TypedDb>,
+    artifacts: web::Data<Artifacts>,
+    principal: Principal,
+    req: HttpRequest,
+    deletion: web::Query<Deletion>,
+) -> impl Responder {
+    principal.authorize(&deletion.repository, Permission::Delete)?;
+    let result = retention::delete_reports(&storage, &artifacts, &config::get().output, &deletion);
+    AuditEntry {
+        repository: Some(deletion.repository.clone()),
+        branch: deletion.branch.clone(),
+        details: match (&result, deletion.date) {
+            (Ok(removal), Some(date)) => Some(format!("report of {date}, {removal}")),
+            (Ok(removal), None) => Some(removal.to_string()),
+            (Err(_), _) => None,
+        },
+        ..AuditEntry::new(&Actor::new(&req, &principal), AuditAction::ReportDeletion)
+    }
+    .record_result(&storage, &result);
+
+    Ok::<_, ApiError>(HttpResponse::Ok().json(result?))
+}
+
+/// Generate the HTML report from the project sources, and compare it with the base branch
+///
+/// The `audit` entry of the upload is completed with the commit and the coverage.
+fn generate_report(
+    storage: &TypedDb,
+    artifacts: &Artifacts,
+    request: &mut Request,
+    repository_path: &Path,
+    audit: &mut AuditEntry,
+) -> ApiResult<ReportResponse> {
+    let now = Utc::now().naive_utc();
+    if audit.commit.is_none() {
+        audit.commit = git::head_commit(repository_path);
+    }
+    let repository_config = RepositoryConfig::load(repository_path)?;
+    let base_branch =
+        report::base_branch(storage, &request.raw_repository_name(), &repository_config)?;
+    let filter = request.filter.as_ref().unwrap_or(&repository_config.filter);
+    // Written locally for llvm-cov-pretty, then stored with the artifacts
+    let upload_id = report::upload_id(now);
+    let work_dir = TempDir::new(&format!("{}-{upload_id}", request.unique_name()))?;
+    let json_path = work_dir.path().join("report.json");
+    let output_path = work_dir.path().join("html");
+    let unresolved_files = report::save_json(
+        &mut request.json_report,
+        repository_path,
+        &request.path_mappings,
+        filter,
+        &json_path,
+    )?;
+    let report = &request.json_report;
+    if !unresolved_files.is_empty() {
+        warn!(
+            "Request git: {}, branch: {}: unresolved files: {:?}",
+            request.git, request.branch, unresolved_files
+        );
+    }
 
     let command = Command::new("llvm-cov-pretty")
         .current_dir(repository_path)
@@ -40,7 +241,7 @@ pub async fn new_report(
             output_path.to_str().unwrap(),
             "--manifest-path",
             "./Cargo.toml",
-            &json_path,
+            json_path.to_str().unwrap(),
         ])
         .spawn()?
         .wait_with_output()?;
@@ -51,18 +252,53 @@ pub async fn new_report(
         );
         return Err(ApiError::LlvmCovPretty);
     }
-
-    let comparison =
-        compare::default_branch(&storage, &report, &request.branch).map_err(ApiError::from)?;
+    let comparison = compare::base_branch(
+        storage,
+        artifacts.json.as_ref(),
+        report,
+        &request.raw_repository_name(),
+        &base_branch,
+    )
+    .map_err(ApiError::from)?;
+    raw_reports::store_file(
+        storage,
+        artifacts.json.as_ref(),
+        &request.unique_name(),
+        &json_path,
+    )?;
+    // Each upload has its own directory, so the history keeps the reports of the previous uploads
+    artifacts.html.put_dir(
+        &format!("{}/{upload_id}", request.unique_name()),
+        &output_path,
+    )?;
     info!(
         "Request git: {}, branch: {}: comparison: {:?}",
         request.git, request.branch, comparison
     );
-    let now = Utc::now().naive_utc();
+    audit.details = Some(format!("coverage {comparison}"));
+    if request.branch == base_branch {
+        let previous = comparison
+            .base
+            .map_or("none".to_string(), |base| format!("{base:.1}%"));
+        AuditEntry {
+            action: AuditAction::BaselineChange,
+            details: Some(format!("baseline {previous} -> {:.1}%", comparison.new)),
+            ..audit.clone()
+        }
+        .record(storage);
+    }
+    storage.insert(
+        &request.unique_name(),
+        &ReportRepository {
+            repository_name: request.raw_repository_name(),
+        },
+    )?;
+    report::record_base_branch(storage, request, &repository_config)?;
     storage.insert(
         &now.to_string(),
         &ReportHistory {
             branch: request.branch.clone(),
+            commit: audit.commit.clone(),
             repository_name: request.raw_repository_name(),
             name: request.unique_name(),
             comparison: comparison.clone(),
@@ -70,13 +306,117 @@ pub async fn new_report(
         },
     )?;
 
-    Ok(serde_json::to_string(&comparison))
+    let response = ReportResponse {
+        quality_gate: repository_config.quality_gate.evaluate(&comparison),
+        groups: group_coverage(&repository_config.groups, report, repository_path)?,
+        comparison,
+        unresolved_files,
+        merge_base: None,
+    };
+    notify(
+        &repository_config.notifications,
+        &NotificationPayload {
+            repository: &request.raw_repository_name(),
+            branch: &request.branch,
+            report: &response,
+        },
+        &config::get().notifications.allowed_webhooks,
+    );
+
+    Ok(response)
+}
+
+/// Redirect `/view/{name}/latest/...` to the most recent upload of the branch
+#[get("/{name}/latest{tail:.*}")]
+pub async fn view_latest(
+    storage: web::Data<TypedDb>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (name, tail) = path.into_inner();
+    let tail = match tail.as_str() {
+        "" | "/" => "/index.html",
+        tail if tail.starts_with('/') => tail,
+        _ => return Err(ApiError::ReportNotFound),
+    };
+    let latest = report::latest_upload(&storage, &name)?.ok_or(ApiError::ReportNotFound)?;
+
+    Ok::<_, ApiError>(
+        HttpResponse::Found()
+            .insert_header((
+                header::LOCATION,
+                format!("/view/{name}/{}{tail}", latest.upload_id()),
+            ))
+            .finish(),
+    )
+}
+
+/// The reports were at `/view/{name}/index.html` before each upload had its own directory
+#[get("/{name}/index.html")]
+pub async fn view_legacy(name: web::Path<String>) -> impl Responder {
+    HttpResponse::Found()
+        .insert_header((header::LOCATION, format!("/view/{name}/latest/index.html")))
+        .finish()
+}
+
+/// Files of the HTML reports, from the artifact store, `index.html` for a directory
+#[get("/{path:.*}")]
+pub async fn view_artifact(
+    artifacts: web::Data<Artifacts>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let mut key = path.into_inner();
+    if key.is_empty() || key.ends_with('/') {
+        key.push_str("index.html");
+    }
+    if !artifacts::is_valid_key(&key) {
+        return Err(ApiError::ReportNotFound);
+    }
+    let content = artifacts.html.get(&key)?.ok_or(ApiError::ReportNotFound)?;
+    let content_type = mime_guess::from_path(&key).first_or_octet_stream();
+
+    Ok::<_, ApiError>(
+        HttpResponse::Ok()
+            .content_type(content_type.essence_str())
+            .body(content),
+    )
+}
+
+/// Uploads of the dashboard page
+const DASHBOARD_PAGE_SIZE: usize = 50;
+
+/// Reports of the repositories the viewer can read, and the number of the next page
+fn readable_reports(
+    storage: &TypedDb,
+    viewer: &Viewer,
+    query: &HistoryQuery,
+    per_page: Option<usize>,
+) -> ApiResult<(Vec<ReportHistory>, Option<usize>)> {
+    if let Some(repository) = &query.repository {
+        if !viewer.can_read(repository)? {
+            return Ok((vec![], None));
+        }
+    }
+    report::history_page(storage, query, per_page, |report| {
+        viewer.can_read(&report.repository_name)
+    })
 }
 
 #[get("")]
-pub async fn dashboard(storage: web::Data<TypedDb>) -> impl Responder {
-    let reports: Vec<ReportHistory> = storage.get_all().map_err(ApiError::from)?;
-    let page = DashBoardTemplate { reports };
+pub async fn dashboard(
+    storage: web::Data<TypedDb>,
+    viewer: Viewer,
+    query: web::Query<HistoryQuery>,
+) -> impl Responder {
+    let (reports, next_page) =
+        readable_reports(&storage, &viewer, &query, Some(DASHBOARD_PAGE_SIZE))?;
+    let page = DashBoardTemplate {
+        reports,
+        username: viewer.username().map(str::to_string),
+        is_admin: viewer.is_admin(),
+        previous_page: query.page.filter(|page| *page > 1).map(|page| page - 1),
+        next_page,
+        filters: query.filters(),
+    };
 
     Ok::<HttpResponse, ApiError>(
         HttpResponse::build(StatusCode::OK)
@@ -84,3 +424,429 @@ pub async fn dashboard(storage: web::Data<TypedDb>) -> impl Responder {
             .body(page.render().unwrap()),
     )
 }
+
+/// Same reports as the dashboard, as json, all of them without a `page` or `per_page`
+#[get("/reports")]
+pub async fn list_reports(
+    storage: web::Data<TypedDb>,
+    viewer: Viewer,
+    query: web::Query<HistoryQuery>,
+) -> impl Responder {
+    let (reports, _) = readable_reports(&storage, &viewer, &query, None)?;
+
+    Ok::<_, ApiError>(HttpResponse::Ok().json(reports))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginForm {
+    username: String,
+    password: String,
+}
+
+fn login_page(status: StatusCode, error: Option<&str>) -> HttpResponse {
+    let page = LoginTemplate {
+        error: error.map(str::to_string),
+    };
+    HttpResponse::build(status)
+        .content_type("text/html; charset=utf-8")
+        .body(page.render().unwrap())
+}
+
+#[get("/login")]
+pub async fn login_form() -> impl Responder {
+    match config::get().viewer_auth.mode {
+        ViewerAuthMode::Local => login_page(StatusCode::OK, None),
+        ViewerAuthMode::Oidc => HttpResponse::SeeOther()
+            .insert_header((header::LOCATION, "/oidc/login"))
+            .finish(),
+        ViewerAuthMode::None | ViewerAuthMode::TrustedHeader => HttpResponse::NotFound().finish(),
+    }
+}
+
+/// Store a new session, and redirect to the dashboard with its cookie
+fn signed_in(storage: &TypedDb, username: &str, groups: Vec<String>) -> ApiResult<HttpResponse> {
+    let viewer_auth = &config::get().viewer_auth;
+    let ttl = Duration::hours(viewer_auth.session_ttl_hours);
+    let token = Session::create(storage, username, groups, ttl)?;
+    let cookie = Cookie::build(SESSION_COOKIE, token)
+        .path("/")
+        .http_only(true)
+        .secure(viewer_auth.secure_cookie)
+        .same_site(SameSite::Lax)
+        .max_age(CookieDuration::hours(viewer_auth.session_ttl_hours))
+        .finish();
+    info!("{username} signed in");
+
+    Ok(HttpResponse::SeeOther()
+        .insert_header((header::LOCATION, "/"))
+        .cookie(cookie)
+        .finish())
+}
+
+#[post("/login")]
+pub async fn login(storage: web::Data<TypedDb>, form: web::Form<LoginForm>) -> impl Responder {
+    if config::get().viewer_auth.mode != ViewerAuthMode::Local {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+    let Some(user) = User::authenticate(&storage, &form.username, &form.password)? else {
+        warn!("Failed sign in of {}", form.username);
+        return Ok(login_page(
+            StatusCode::UNAUTHORIZED,
+            Some("Invalid username or password"),
+        ));
+    };
+
+    signed_in(&storage, &user.username, vec![])
+}
+
+/// Redirect to the provider of the `oidc` viewer authentication
+#[get("/oidc/login")]
+pub async fn oidc_login(storage: web::Data<TypedDb>) -> impl Responder {
+    let Some(provider) = OidcLogin::global() else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+    let (url, state) = provider.start(&storage)?;
+    let cookie = Cookie::build(OIDC_STATE_COOKIE, state)
+        .path("/oidc")
+        .http_only(true)
+        .secure(config::get().viewer_auth.secure_cookie)
+        .same_site(SameSite::Lax)
+        .max_age(CookieDuration::minutes(LOGIN_STATE_TTL_MINUTES))
+        .finish();
+
+    Ok::<_, ApiError>(
+        HttpResponse::SeeOther()
+            .insert_header((header::LOCATION, url))
+            .cookie(cookie)
+            .finish(),
+    )
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OidcCallback {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+/// Redirection of the provider once the user signed in
+#[get("/oidc/callback")]
+pub async fn oidc_callback(
+    storage: web::Data<TypedDb>,
+    req: HttpRequest,
+    query: web::Query<OidcCallback>,
+) -> impl Responder {
+    let Some(provider) = OidcLogin::global() else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+    let (code, state) = match &query.into_inner() {
+        OidcCallback {
+            error: Some(error),
+            error_description,
+            ..
+        } => {
+            let description = error_description.as_deref().unwrap_or_default();
+            return Err(ApiError::OidcLogin(format!("{error} {description}")));
+        }
+        OidcCallback {
+            code: Some(code),
+            state: Some(state),
+            ..
+        } => (code.clone(), state.clone()),
+        _ => return Err(ApiError::OidcLogin("missing code or state".to_string())),
+    };
+    let browser_state = req.cookie(OIDC_STATE_COOKIE);
+    let identity = provider.complete(
+        &storage,
+        &code,
+        &state,
+        browser_state.as_ref().map(|cookie| cookie.value()),
+    )?;
+
+    let mut removal = Cookie::build(OIDC_STATE_COOKIE, "").path("/oidc").finish();
+    removal.make_removal();
+    let mut response = signed_in(&storage, &identity.username, identity.groups)?;
+    response.add_cookie(&removal).map_err(anyhow::Error::from)?;
+    Ok(response)
+}
+
+#[post("/logout")]
+pub async fn logout(storage: web::Data<TypedDb>, req: HttpRequest) -> impl Responder {
+    if let Some(cookie) = req.cookie(SESSION_COOKIE) {
+        Session::delete(&storage, cookie.value())?;
+    }
+    let mut removal = Cookie::build(SESSION_COOKIE, "").path("/").finish();
+    removal.make_removal();
+
+    Ok::<_, ApiError>(
+        HttpResponse::SeeOther()
+            .insert_header((header::LOCATION, "/login"))
+            .cookie(removal)
+            .finish(),
+    )
+}
+
+/// Create an api key, the token is only returned in this response
+#[post("/api-keys")]
+pub async fn create_api_key(
+    storage: web::Data<TypedDb>,
+    principal: Principal,
+    req: HttpRequest,
+    new_key: web::Json<NewApiKey>,
+) -> impl Responder {
+    let new_key = new_key.into_inner();
+    let audit = AuditEntry {
+        details: Some(format!(
+            "{}: {:?} on {:?}",
+            new_key.name, new_key.permissions, new_key.repositories
+        )),
+        ..AuditEntry::new(&Actor::new(&req, &principal), AuditAction::ApiKeyCreation)
+    };
+    let result = principal
+        .authorize_admin()
+        .and_then(|()| ApiKey::create(&storage, new_key));
+    AuditEntry {
+        target: result.as_ref().ok().map(|(key, _)| key.id.clone()),
+        ..audit
+    }
+    .record_result(&storage, &result);
+    let (key, token) = result?;
+    info!("Created api key {} ({})", key.id, key.name);
+
+    Ok::<_, ApiError>(
+        HttpResponse::Created().json(serde_json::json!({ "key": key.info(), "token": token })),
+    )
+}
+
+#[get("/api-keys")]
+pub async fn list_api_keys(storage: web::Data<TypedDb>, principal: Principal) -> impl Responder {
+    principal.authorize_admin()?;
+    let keys = ApiKey::list(&storage)?;
+
+    Ok::<_, ApiError>(HttpResponse::Ok().json(keys.iter().map(ApiKey::info).collect::<Vec<_>>()))
+}
+
+#[delete("/api-keys/{id}")]
+pub async fn revoke_api_key(
+    storage: web::Data<TypedDb>,
+    principal: Principal,
+    req: HttpRequest,
+    id: web::Path<String>,
+) -> impl Responder {
+    let result = principal
+        .authorize_admin()
+        .and_then(|()| ApiKey::revoke(&storage, &id));
+    AuditEntry {
+        target: Some(id.into_inner()),
+        ..AuditEntry::new(&Actor::new(&req, &principal), AuditAction::ApiKeyRevocation)
+    }
+    .record_result(&storage, &result);
+    let key = result?;
+    info!("Revoked api key {} ({})", key.id, key.name);
+
+    Ok::<_, ApiError>(HttpResponse::Ok().json(key.info()))
+}
+
+/// Create a user of the `local` viewer authentication
+#[post("/users")]
+pub async fn create_user(
+    storage: web::Data<TypedDb>,
+    principal: Principal,
+    req: HttpRequest,
+    new_user: web::Json<NewUser>,
+) -> impl Responder {
+    let new_user = new_user.into_inner();
+    let audit = AuditEntry {
+        target: Some(new_user.username.clone()),
+        details: Some(format!(
+            "groups {:?}, repositories {:?}",
+            new_user.groups, new_user.repositories
+        )),
+        ..AuditEntry::new(&Actor::new(&req, &principal), AuditAction::UserCreation)
+    };
+    let result = principal
+        .authorize_admin()
+        .and_then(|()| User::create(&storage, new_user));
+    audit.record_result(&storage, &result);
+    let user = result?;
+    info!("Created user {}", user.username);
+
+    Ok::<_, ApiError>(HttpResponse::Created().json(user.info()))
+}
+
+#[get("/users")]
+pub async fn list_users(storage: web::Data<TypedDb>, principal: Principal) -> impl Responder {
+    principal.authorize_admin()?;
+    let users = User::list(&storage)?;
+
+    Ok::<_, ApiError>(HttpResponse::Ok().json(users.iter().map(User::info).collect::<Vec<_>>()))
+}
+
+#[delete("/users/{username}")]
+pub async fn delete_user(
+    storage: web::Data<TypedDb>,
+    principal: Principal,
+    req: HttpRequest,
+    username: web::Path<String>,
+) -> impl Responder {
+    let result = principal
+        .authorize_admin()
+        .and_then(|()| User::delete(&storage, &username));
+    AuditEntry {
+        target: Some(username.into_inner()),
+        ..AuditEntry::new(&Actor::new(&req, &principal), AuditAction::UserDeletion)
+    }
+    .record_result(&storage, &result);
+    let user = result?;
+    info!("Deleted user {}", user.username);
+
+    Ok::<_, ApiError>(HttpResponse::Ok().json(user.info()))
+}
+
+/// Apply the retention policy now, instead of waiting for the background garbage collection
+#[post("/gc")]
+pub async fn collect_garbage(
+    storage: web::Data<TypedDb>,
+    artifacts: web::Data<Artifacts>,
+    principal: Principal,
+    req: HttpRequest,
+) -> impl Responder {
+    principal.authorize_admin()?;
+    let report = retention::run(&storage, &artifacts, &Actor::new(&req, &principal))?;
+
+    Ok::<_, ApiError>(HttpResponse::Ok().json(report))
+}
+
+/// A tar.gz archive of the records and of the reports, see [backup::export]
+#[get("/export")]
+pub async fn export_backup(
+    storage: web::Data<TypedDb>,
+    artifacts: web::Data<Artifacts>,
+    principal: Principal,
+    req: HttpRequest,
+    query: web::Query<ExportQuery>,
+) -> impl Responder {
+    principal.authorize_admin()?;
+    let now = Utc::now().naive_utc();
+    let work_dir = TempDir::new(&format!("backup-{}", report::upload_id(now)))?;
+    let path = work_dir.path().join("backup.tar.gz");
+    let result = File::create(&path)
+        .map_err(ApiError::from)
+        .and_then(|file| Ok(backup::export(&storage, &artifacts, file, query.html)?));
+    AuditEntry {
+        details: result.as_ref().ok().map(ToString::to_string),
+        ..AuditEntry::new(&Actor::new(&req, &principal), AuditAction::Export)
+    }
+    .record_result(&storage, &result);
+    info!("Backup: {}", result?);
+    // The response reads the open file, removed with the temporary directory
+    let file = NamedFile::open(&path)?.set_content_disposition(header::ContentDisposition {
+        disposition: header::DispositionType::Attachment,
+        parameters: vec![header::DispositionParam::Filename(format!(
+            "llvm-cov-host-{}.tar.gz",
+            now.format("%Y%m%dT%H%M%S")
+        ))],
+    });
+
+    Ok::<_, ApiError>(file.into_response(&req))
+}
+
+/// Restore an archive of `GET /admin/export`, sent as the body of the request
+#[post("/import")]
+pub async fn import_backup(
+    storage: web::Data<TypedDb>,
+    artifacts: web::Data<Artifacts>,
+    principal: Principal,
+    req: HttpRequest,
+    query: web::Query<ImportQuery>,
+    payload: web::Payload,
+) -> impl Responder {
+    principal.authorize_admin()?;
+    let work_dir = TempDir::new(&format!(
+        "import-{}",
+        report::upload_id(Utc::now().naive_utc())
+    ))?;
+    let path = work_dir.path().join("backup.tar.gz");
+    save_upload(payload, &path, config::get().import_limit).await?;
+    let result = backup::import(&storage, &artifacts, &path, query.on_conflict);
+    AuditEntry {
+        details: result.as_ref().ok().map(ToString::to_string),
+        ..AuditEntry::new(&Actor::new(&req, &principal), AuditAction::Import)
+    }
+    .record_result(&storage, &result);
+    let report = result?;
+    info!("Import: {report}");
+
+    Ok::<_, ApiError>(HttpResponse::Ok().json(report))
+}
+
+/// The audit log, most recent first
+#[get("/audit")]
+pub async fn list_audit(
+    storage: web::Data<TypedDb>,
+    principal: Principal,
+    query: web::Query<AuditQuery>,
+) -> impl Responder {
+    principal.authorize_admin()?;
+    let page = AuditEntry::page(&storage, &query)?;
+
+    Ok::<_, ApiError>(HttpResponse::Ok().json(page))
+}
+
+/// Dashboard page of the audit log, for the admins of the viewer authentication
+#[get("/audit")]
+pub async fn audit_page(
+    storage: web::Data<TypedDb>,
+    viewer: Viewer,
+    query: web::Query<AuditQuery>,
+) -> impl Responder {
+    viewer.authorize_admin()?;
+    let AuditPage {
+        entries, next_page, ..
+    } = AuditEntry::page(&storage, &query)?;
+    let page = AuditTemplate {
+        entries,
+        username: viewer.username().map(str::to_string),
+        previous_page: query.page.filter(|page| *page > 1).map(|page| page - 1),
+        next_page,
+        filters: query.filters(),
+    };
+
+    Ok::<HttpResponse, ApiError>(
+        HttpResponse::build(StatusCode::OK)
+            .content_type("text/html; charset=utf-8")
+            .body(page.render().unwrap()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn test_save_upload() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        let path = dir.join("backup.tar.gz");
+        let chunks = |count: usize| {
+            let chunks = (0..count).map(|_| Ok(actix_web::web::Bytes::from_static(b"1234")));
+            futures_util::stream::iter(chunks.collect::<Vec<_>>())
+        };
+
+        actix_web::rt::System::new().block_on(async {
+            save_upload(chunks(2), &path, 8).await.unwrap();
+            assert_eq!(fs::read(&path).unwrap(), b"12341234");
+
+            // The stream isn't read past the limit
+            let mut stream = chunks(4);
+            assert!(matches!(
+                save_upload(&mut stream, &path, 8).await,
+                Err(ApiError::UploadTooLarge(8))
+            ));
+            assert_eq!(futures_util::StreamExt::count(stream).await, 1);
+        });
+    }
+}
@@ -0,0 +1,196 @@
+use anyhow::bail;
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionError},
+    Db, IVec, Transactional, Tree,
+};
+
+use crate::storage::{Backend, Encoding, IndexKeys, RawRecord, Scan};
+
+/// Meta tree of the offset added to the counter of sled, it can't be set
+const IDS_TREE: &str = "ids";
+const IDS_OFFSET: &[u8] = b"offset";
+
+/// Length of the schema version tag that prefixes every value
+const VERSION_TAG_LEN: usize = 4;
+
+/// The value, prefixed with its schema version
+fn tag(version: u32, value: &[u8]) -> Vec<u8> {
+    let mut bytes = version.to_be_bytes().to_vec();
+    bytes.extend_from_slice(value);
+    bytes
+}
+
+/// Schema version and serialized value of a stored record
+fn split_version(bytes: &[u8]) -> anyhow::Result<RawRecord> {
+    if bytes.len() < VERSION_TAG_LEN {
+        bail!("record without a schema version");
+    }
+    let (tag, value) = bytes.split_at(VERSION_TAG_LEN);
+    Ok((u32::from_be_bytes(tag.try_into()?), value.to_vec()))
+}
+
+/// A tree per collection and per index, the records are tagged bincode
+#[derive(Debug, Clone)]
+pub struct SledBackend(Db);
+
+impl SledBackend {
+    pub fn new(db: Db) -> Self {
+        Self(db)
+    }
+
+    fn tree(&self, name: &str) -> anyhow::Result<Tree> {
+        Ok(self.0.open_tree(name)?)
+    }
+
+    fn ids_offset(&self) -> anyhow::Result<u64> {
+        let offset = self.tree(IDS_TREE)?.get(IDS_OFFSET)?;
+        Ok(match offset {
+            Some(offset) => u64::from_be_bytes(offset.as_ref().try_into()?),
+            None => 0,
+        })
+    }
+}
+
+fn entry(res: sled::Result<(IVec, IVec)>) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+    let (key, value) = res?;
+    Ok((key.to_vec(), value.to_vec()))
+}
+
+impl Backend for SledBackend {
+    fn encoding(&self) -> Encoding {
+        Encoding::Bincode
+    }
+
+    fn get(&self, collection: &str, key: &str) -> anyhow::Result<Option<RawRecord>> {
+        let bytes = self.tree(collection)?.get(key)?;
+        bytes.map(|bytes| split_version(&bytes)).transpose()
+    }
+
+    fn put(&self, collection: &str, key: &str, version: u32, value: &[u8]) -> anyhow::Result<()> {
+        self.tree(collection)?.insert(key, tag(version, value))?;
+        Ok(())
+    }
+
+    fn update(
+        &self,
+        collection: &str,
+        key: &str,
+        record: Option<(u32, &[u8])>,
+        indexes: &[&str],
+        index_keys: &IndexKeys,
+    ) -> anyhow::Result<Option<RawRecord>> {
+        let mut trees = vec![self.tree(collection)?];
+        for index in indexes {
+            trees.push(self.tree(index)?);
+        }
+        let bytes = record.map(|(version, value)| tag(version, value));
+        let new_keys = match record {
+            Some((version, value)) if !indexes.is_empty() => index_keys(version, value)?,
+            _ => vec![],
+        };
+        let result = trees.as_slice().transaction(|trees| {
+            let (records, indexes) = trees.split_first().expect("the collection tree");
+            let previous = match &bytes {
+                Some(bytes) => records.insert(key, bytes.as_slice())?,
+                None => records.remove(key)?,
+            };
+            let abort = ConflictableTransactionError::Abort;
+            let previous = previous
+                .map(|bytes| split_version(&bytes))
+                .transpose()
+                .map_err(abort)?;
+            if let Some((version, value)) = &previous {
+                if !indexes.is_empty() {
+                    let previous_keys = index_keys(*version, value).map_err(abort)?;
+                    for (index, index_key) in indexes.iter().zip(previous_keys) {
+                        index.remove(index_key)?;
+                    }
+                }
+            }
+            for (index, index_key) in indexes.iter().zip(&new_keys) {
+                index.insert(index_key.as_slice(), key.as_bytes())?;
+            }
+            Ok(previous)
+        });
+        result.map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e.into(),
+        })
+    }
+
+    fn records(&self, collection: &str, reverse: bool) -> anyhow::Result<Scan<String, RawRecord>> {
+        let record = |res| {
+            let (key, bytes) = entry(res)?;
+            Ok((String::from_utf8(key)?, split_version(&bytes)?))
+        };
+        let iter = self.tree(collection)?.iter();
+        Ok(if reverse {
+            Box::new(iter.rev().map(record))
+        } else {
+            Box::new(iter.map(record))
+        })
+    }
+
+    fn tree_get(&self, tree: &str, key: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self.tree(tree)?.get(key)?.map(|value| value.to_vec()))
+    }
+
+    fn tree_insert(&self, tree: &str, key: &[u8], value: &[u8]) -> anyhow::Result<()> {
+        self.tree(tree)?.insert(key, value)?;
+        Ok(())
+    }
+
+    fn tree_remove(&self, tree: &str, key: &[u8]) -> anyhow::Result<()> {
+        self.tree(tree)?.remove(key)?;
+        Ok(())
+    }
+
+    fn tree_clear(&self, tree: &str) -> anyhow::Result<()> {
+        Ok(self.tree(tree)?.clear()?)
+    }
+
+    fn tree_scan(
+        &self,
+        tree: &str,
+        prefix: &[u8],
+        reverse: bool,
+    ) -> anyhow::Result<Scan<Vec<u8>, Vec<u8>>> {
+        let iter = self.tree(tree)?.scan_prefix(prefix);
+        Ok(if reverse {
+            Box::new(iter.rev().map(entry))
+        } else {
+            Box::new(iter.map(entry))
+        })
+    }
+
+    fn tree_seek(&self, tree: &str, start: &[u8]) -> anyhow::Result<Option<(Vec<u8>, Vec<u8>)>> {
+        self.tree(tree)?
+            .range(start..)
+            .next()
+            .map(entry)
+            .transpose()
+    }
+
+    fn generate_id(&self) -> anyhow::Result<u64> {
+        Ok(self.0.generate_id()? + self.ids_offset()?)
+    }
+
+    fn advance_ids(&self, next: u64) -> anyhow::Result<()> {
+        // sled only increments its counter, the offset makes up the difference
+        let counter = self.0.generate_id()?;
+        self.tree(IDS_TREE)?
+            .fetch_and_update(IDS_OFFSET, |offset| {
+                let offset = offset
+                    .and_then(|offset| offset.try_into().ok())
+                    .map_or(0, u64::from_be_bytes);
+                let offset = offset.max(next.saturating_sub(counter + 1));
+                Some(offset.to_be_bytes().to_vec())
+            })?;
+        Ok(())
+    }
+
+    fn flush(&self) -> anyhow::Result<()> {
+        self.0.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,323 @@
+use std::{
+    fs::{self, File},
+    io::{BufReader, Read, Seek, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use actix_multipart::{Field, Multipart};
+use chrono::Utc;
+use flate2::read::GzDecoder;
+use futures_util::TryStreamExt;
+
+use crate::{
+    config,
+    error::{ApiError, ApiResult},
+    report::{Request, UploadEncoding},
+    utils::TempDir,
+};
+
+/// Upload of a report with the sources of the project, instead of cloning its repository.
+///
+/// The multipart form contains a `report` field, with the same json as [Request] optionally
+/// compressed with its `Content-Encoding`, and then a `sources` field with a tar archive,
+/// optionally gzipped, of the project sources.
+///
+/// The report comes first, so the upload is authorized before its archive is received.
+pub struct SourcesUpload {
+    pub request: Request,
+    payload: Multipart,
+    /// The archive can be large, so it's written to disk instead of kept in memory
+    work_dir: TempDir,
+}
+
+impl SourcesUpload {
+    /// Read the `report` field of the form
+    pub async fn from_multipart(mut payload: Multipart) -> ApiResult<Self> {
+        let work_dir = TempDir::new(&format!(
+            "sources-upload-{}",
+            Utc::now().timestamp_nanos_opt().unwrap()
+        ))?;
+
+        while let Some(mut field) = payload.try_next().await? {
+            match field.name() {
+                Some("report") => {
+                    let encoding = UploadEncoding::from_headers(field.headers())?;
+                    let report_path = work_dir.path().join("report.json");
+                    write_field(&mut field, &report_path, config::get().json_limit).await?;
+                    let request =
+                        Request::from_upload(&report_path, encoding, config::get().json_limit)?;
+                    fs::remove_file(&report_path)?;
+                    return Ok(Self {
+                        request,
+                        payload,
+                        work_dir,
+                    });
+                }
+                Some("sources") => {
+                    return Err(ApiError::InvalidUpload(
+                        "the report field must come before the sources".to_string(),
+                    ))
+                }
+                _ => continue,
+            }
+        }
+        Err(ApiError::MissingMultipartField("report"))
+    }
+
+    fn archive_path(&self) -> PathBuf {
+        self.work_dir.path().join("sources.tar")
+    }
+
+    /// Read the `sources` field of the form, once the upload is authorized
+    pub async fn receive_sources(&mut self) -> ApiResult<()> {
+        let archive_path = self.archive_path();
+        while let Some(mut field) = self.payload.try_next().await? {
+            if field.name() == Some("sources") {
+                return write_field(&mut field, &archive_path, config::get().sources_limit).await;
+            }
+        }
+        Err(ApiError::MissingMultipartField("sources"))
+    }
+
+    /// Extract the sources archive, replacing the sources of a previous upload for the same branch.
+    ///
+    /// Returns the extracted directory, to be used in place of the cloned repository.
+    pub fn extract(&self) -> ApiResult<PathBuf> {
+        let sources_path = config::get()
+            .output
+            .sources_dir
+            .join(self.request.unique_name());
+        extract_archive(
+            &self.archive_path(),
+            &sources_path,
+            config::get().sources_unpacked_limit,
+        )?;
+
+        Ok(sources_path.canonicalize()?)
+    }
+}
+
+/// Like [crate::routes::save_upload], the field is refused once it exceeds `limit` bytes
+async fn write_field(field: &mut Field, path: &Path, limit: usize) -> ApiResult<()> {
+    let mut file = File::create(path)?;
+    let mut size = 0;
+    while let Some(chunk) = field.try_next().await? {
+        size += chunk.len();
+        if size > limit {
+            return Err(ApiError::UploadTooLarge(limit));
+        }
+        file.write_all(&chunk)?;
+    }
+    file.flush()?;
+    Ok(())
+}
+
+/// Unpack a tar archive, gzipped or not, in a clean directory.
+///
+/// Only regular files and directories are extracted, a symlink or hardlink could point to a file
+/// of the server that would end up in the report. Entries escaping the directory are refused too.
+/// The extraction stops once the files exceed `limit` bytes, the archive can be a gzip bomb.
+pub fn extract_archive(archive_path: &Path, destination: &Path, limit: usize) -> ApiResult<()> {
+    if destination.exists() {
+        fs::remove_dir_all(destination)?;
+    }
+    fs::create_dir_all(destination)?;
+
+    let mut file = BufReader::new(File::open(archive_path)?);
+    let mut magic = [0u8; 2];
+    let is_gzip = file.read_exact(&mut magic).is_ok() && magic == [0x1f, 0x8b];
+    file.rewind()?;
+
+    let destination = destination.canonicalize()?;
+    if is_gzip {
+        unpack_entries(tar::Archive::new(GzDecoder::new(file)), &destination, limit)
+    } else {
+        unpack_entries(tar::Archive::new(file), &destination, limit)
+    }
+}
+
+fn unpack_entries(
+    mut archive: tar::Archive<impl Read>,
+    destination: &Path,
+    limit: usize,
+) -> ApiResult<()> {
+    let entries = archive
+        .entries()
+        .map_err(|_| ApiError::InvalidSourcesArchive)?;
+    let mut size = 0;
+    for entry in entries {
+        let mut entry = entry.map_err(|_| ApiError::InvalidSourcesArchive)?;
+        size += entry.size();
+        if size > limit as u64 {
+            return Err(ApiError::UploadTooLarge(limit));
+        }
+        let name = entry.path()?.to_string_lossy().to_string();
+        let entry_type = entry.header().entry_type();
+        if !entry_type.is_file() && !entry_type.is_dir() {
+            return Err(ApiError::UnsafeSourcesEntry(name));
+        }
+        // `false` for the entries with a `..` path
+        let unpacked = entry
+            .unpack_in(destination)
+            .map_err(|_| ApiError::InvalidSourcesArchive)?;
+        if !unpacked {
+            return Err(ApiError::UnsafeSourcesEntry(name));
+        }
+
+        // Like `unpack_in`, the root of absolute paths is the destination
+        let relative: PathBuf = entry
+            .path()?
+            .components()
+            .filter(|component| matches!(component, Component::Normal(_)))
+            .collect();
+        if !destination
+            .join(relative)
+            .canonicalize()?
+            .starts_with(destination)
+        {
+            return Err(ApiError::UnsafeSourcesEntry(name));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+    use crate::{model::Report, report::find_matching_project_path};
+
+    #[test]
+    fn test_extract_sources_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive_path = dir.path().join("sources.tar.gz");
+
+        let mut builder = tar::Builder::new(GzEncoder::new(
+            fs::File::create(&archive_path).unwrap(),
+            Compression::default(),
+        ));
+        let content = b"fn main() {}";
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        builder
+            .append_data(&mut header, "api/src/main.rs", &content[..])
+            .unwrap();
+        builder.into_inner().unwrap().finish().unwrap();
+
+        let destination = dir.path().join("extracted");
+        fs::create_dir_all(destination.join("stale")).unwrap();
+        extract_archive(&archive_path, &destination, 1024).unwrap();
+        assert!(!destination.join("stale").exists());
+
+        let old_path =
+            find_matching_project_path(&destination, "/builds/project/api/src/main.rs").unwrap();
+        assert_eq!(old_path, "/builds/project");
+
+        // The extracted size is limited, whatever the size of the archive
+        assert!(matches!(
+            extract_archive(&archive_path, &destination, content.len() - 1),
+            Err(ApiError::UploadTooLarge(_))
+        ));
+
+        // The links could point to any file of the server
+        let link = |name: &str, entry_type: tar::EntryType, target: &str| {
+            let mut builder = tar::Builder::new(fs::File::create(&archive_path).unwrap());
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(entry_type);
+            header.set_size(0);
+            header.set_mode(0o644);
+            builder.append_link(&mut header, name, target).unwrap();
+            builder.into_inner().unwrap();
+        };
+        link("api/src/lib.rs", tar::EntryType::Symlink, "/etc/passwd");
+        assert!(matches!(
+            extract_archive(&archive_path, &destination, 1024),
+            Err(ApiError::UnsafeSourcesEntry(_))
+        ));
+        assert!(fs::symlink_metadata(destination.join("api/src/lib.rs")).is_err());
+        link("api/src", tar::EntryType::Symlink, "/");
+        assert!(extract_archive(&archive_path, &destination, 1024).is_err());
+        link("api/src/lib.rs", tar::EntryType::Link, "/etc/passwd");
+        assert!(matches!(
+            extract_archive(&archive_path, &destination, 1024),
+            Err(ApiError::UnsafeSourcesEntry(_))
+        ));
+
+        fs::write(&archive_path, "not an archive").unwrap();
+        assert!(extract_archive(&archive_path, &destination, 1024).is_err());
+    }
+
+    /// Multipart form of the fields `(name, content)`, for [SourcesUpload]
+    fn multipart_form(fields: &[(&str, &[u8])]) -> Multipart {
+        let mut body = vec![];
+        for (name, content) in fields {
+            body.extend_from_slice(
+                format!(
+                    "--boundary\r\nContent-Disposition: form-data; name=\"{name}\"; filename=\"{name}\"\r\n\r\n"
+                )
+                .as_bytes(),
+            );
+            body.extend_from_slice(content);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(b"--boundary--\r\n");
+
+        let mut headers = actix_web::http::header::HeaderMap::new();
+        headers.insert(
+            actix_web::http::header::CONTENT_TYPE,
+            "multipart/form-data; boundary=boundary".parse().unwrap(),
+        );
+        let stream = futures_util::stream::once(async move {
+            Ok::<_, actix_web::error::PayloadError>(actix_web::web::Bytes::from(body))
+        });
+        Multipart::new(&headers, stream)
+    }
+
+    #[test]
+    fn test_sources_upload() {
+        let report = serde_json::to_vec(&serde_json::json!({
+            "git": "https://github.com/GreeFine/app",
+            "branch": "main",
+            "json_report": Report::default(),
+        }))
+        .unwrap();
+
+        actix_web::rt::System::new().block_on(async {
+            let mut upload = SourcesUpload::from_multipart(multipart_form(&[
+                ("report", &report),
+                ("sources", b"archive"),
+            ]))
+            .await
+            .unwrap();
+            assert_eq!(upload.request.branch, "main");
+            upload.receive_sources().await.unwrap();
+
+            // The upload must be authorized with the report before the archive is received
+            let result = SourcesUpload::from_multipart(multipart_form(&[
+                ("sources", b"archive"),
+                ("report", &report),
+            ]))
+            .await;
+            assert!(matches!(result, Err(ApiError::InvalidUpload(_))));
+
+            let mut form = multipart_form(&[("sources", b"archive")]);
+            let mut field = form.try_next().await.unwrap().unwrap();
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("sources.tar");
+            assert!(matches!(
+                write_field(&mut field, &path, 6).await,
+                Err(ApiError::UploadTooLarge(6))
+            ));
+
+            let mut upload = SourcesUpload::from_multipart(multipart_form(&[("report", &report)]))
+                .await
+                .unwrap();
+            assert!(matches!(
+                upload.receive_sources().await,
+                Err(ApiError::MissingMultipartField("sources"))
+            ));
+        });
+    }
+}
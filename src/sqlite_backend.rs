@@ -0,0 +1,308 @@
+use std::{
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::storage::{prefix_successor, Backend, Encoding, IndexKeys, RawRecord, Scan};
+
+/// The records are JSON, to be queried with `json_extract(value, '$.field')`
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS records (
+    collection TEXT NOT NULL,
+    key TEXT NOT NULL,
+    version INTEGER NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (collection, key)
+) WITHOUT ROWID;
+CREATE TABLE IF NOT EXISTS trees (
+    tree TEXT NOT NULL,
+    key BLOB NOT NULL,
+    value BLOB NOT NULL,
+    PRIMARY KEY (tree, key)
+) WITHOUT ROWID;
+CREATE TABLE IF NOT EXISTS ids (next INTEGER NOT NULL);
+INSERT INTO ids SELECT 0 WHERE NOT EXISTS (SELECT 1 FROM ids);
+";
+
+/// Rows read by a query of a scan
+const SCAN_PAGE_SIZE: usize = 256;
+
+/// A table of the records of every collection, and a table of the entries of the trees
+#[derive(Debug, Clone)]
+pub struct SqliteBackend(Arc<Mutex<Connection>>);
+
+impl SqliteBackend {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let connection = Connection::open(path)?;
+        // Readers, like a backup, don't block the server
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        Self::new(connection)
+    }
+
+    /// Create the tables if they don't exist
+    pub fn new(connection: Connection) -> anyhow::Result<Self> {
+        connection.execute_batch(SCHEMA)?;
+        Ok(Self(Arc::new(Mutex::new(connection))))
+    }
+
+    fn connection(&self) -> MutexGuard<'_, Connection> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Next page of a scan, from after the last key read
+type ReadPage<K, V> = Box<dyn FnMut(Option<&K>) -> anyhow::Result<Vec<(K, V)>>>;
+
+/// Rows of a scan, read a page at a time after the last key, so a scan neither loads the whole
+/// table nor holds the connection
+struct Pages<K, V> {
+    read_page: ReadPage<K, V>,
+    last_key: Option<K>,
+    page: std::vec::IntoIter<(K, V)>,
+    done: bool,
+}
+
+impl<K: Clone + 'static, V: 'static> Pages<K, V> {
+    fn scan(
+        read_page: impl FnMut(Option<&K>) -> anyhow::Result<Vec<(K, V)>> + 'static,
+    ) -> Scan<K, V> {
+        Box::new(Self {
+            read_page: Box::new(read_page),
+            last_key: None,
+            page: vec![].into_iter(),
+            done: false,
+        })
+    }
+}
+
+impl<K: Clone, V> Iterator for Pages<K, V> {
+    type Item = anyhow::Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some((key, value)) = self.page.next() {
+            self.last_key = Some(key.clone());
+            return Some(Ok((key, value)));
+        }
+        if self.done {
+            return None;
+        }
+        match (self.read_page)(self.last_key.as_ref()) {
+            Ok(page) => {
+                self.done = page.len() < SCAN_PAGE_SIZE;
+                self.page = page.into_iter();
+                let (key, value) = self.page.next()?;
+                self.last_key = Some(key.clone());
+                Some(Ok((key, value)))
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+fn get_record(
+    connection: &Connection,
+    collection: &str,
+    key: &str,
+) -> anyhow::Result<Option<RawRecord>> {
+    let record = connection
+        .query_row(
+            "SELECT version, value FROM records WHERE collection = ?1 AND key = ?2",
+            params![collection, key],
+            |row| Ok((row.get(0)?, row.get::<_, String>(1)?.into_bytes())),
+        )
+        .optional()?;
+    Ok(record)
+}
+
+fn put_record(
+    connection: &Connection,
+    collection: &str,
+    key: &str,
+    version: u32,
+    value: &[u8],
+) -> anyhow::Result<()> {
+    connection.execute(
+        "INSERT OR REPLACE INTO records (collection, key, version, value) VALUES (?1, ?2, ?3, ?4)",
+        params![collection, key, version, std::str::from_utf8(value)?],
+    )?;
+    Ok(())
+}
+
+impl Backend for SqliteBackend {
+    fn encoding(&self) -> Encoding {
+        Encoding::Json
+    }
+
+    fn get(&self, collection: &str, key: &str) -> anyhow::Result<Option<RawRecord>> {
+        get_record(&self.connection(), collection, key)
+    }
+
+    fn put(&self, collection: &str, key: &str, version: u32, value: &[u8]) -> anyhow::Result<()> {
+        put_record(&self.connection(), collection, key, version, value)
+    }
+
+    fn update(
+        &self,
+        collection: &str,
+        key: &str,
+        record: Option<(u32, &[u8])>,
+        indexes: &[&str],
+        index_keys: &IndexKeys,
+    ) -> anyhow::Result<Option<RawRecord>> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        let previous = get_record(&transaction, collection, key)?;
+        match record {
+            Some((version, value)) => put_record(&transaction, collection, key, version, value)?,
+            None => {
+                transaction.execute(
+                    "DELETE FROM records WHERE collection = ?1 AND key = ?2",
+                    params![collection, key],
+                )?;
+            }
+        }
+        if !indexes.is_empty() {
+            if let Some((version, value)) = &previous {
+                for (index, index_key) in indexes.iter().zip(index_keys(*version, value)?) {
+                    transaction.execute(
+                        "DELETE FROM trees WHERE tree = ?1 AND key = ?2",
+                        params![index, index_key],
+                    )?;
+                }
+            }
+            if let Some((version, value)) = record {
+                for (index, index_key) in indexes.iter().zip(index_keys(version, value)?) {
+                    transaction.execute(
+                        "INSERT OR REPLACE INTO trees (tree, key, value) VALUES (?1, ?2, ?3)",
+                        params![index, index_key, key.as_bytes()],
+                    )?;
+                }
+            }
+        }
+        transaction.commit()?;
+        Ok(previous)
+    }
+
+    fn records(&self, collection: &str, reverse: bool) -> anyhow::Result<Scan<String, RawRecord>> {
+        let sql = if reverse {
+            "SELECT key, version, value FROM records WHERE collection = ?1
+             AND (?2 IS NULL OR key < ?2) ORDER BY key DESC LIMIT ?3"
+        } else {
+            "SELECT key, version, value FROM records WHERE collection = ?1
+             AND (?2 IS NULL OR key > ?2) ORDER BY key LIMIT ?3"
+        };
+        let backend = self.clone();
+        let collection = collection.to_string();
+        Ok(Pages::scan(move |last_key: Option<&String>| {
+            let connection = backend.connection();
+            let mut statement = connection.prepare_cached(sql)?;
+            let rows =
+                statement.query_map(params![collection, last_key, SCAN_PAGE_SIZE], |row| {
+                    Ok((
+                        row.get(0)?,
+                        (row.get(1)?, row.get::<_, String>(2)?.into_bytes()),
+                    ))
+                })?;
+            Ok(rows.collect::<Result<_, _>>()?)
+        }))
+    }
+
+    fn tree_get(&self, tree: &str, key: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        let value = self
+            .connection()
+            .query_row(
+                "SELECT value FROM trees WHERE tree = ?1 AND key = ?2",
+                params![tree, key],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(value)
+    }
+
+    fn tree_insert(&self, tree: &str, key: &[u8], value: &[u8]) -> anyhow::Result<()> {
+        self.connection().execute(
+            "INSERT OR REPLACE INTO trees (tree, key, value) VALUES (?1, ?2, ?3)",
+            params![tree, key, value],
+        )?;
+        Ok(())
+    }
+
+    fn tree_remove(&self, tree: &str, key: &[u8]) -> anyhow::Result<()> {
+        self.connection().execute(
+            "DELETE FROM trees WHERE tree = ?1 AND key = ?2",
+            params![tree, key],
+        )?;
+        Ok(())
+    }
+
+    fn tree_clear(&self, tree: &str) -> anyhow::Result<()> {
+        self.connection()
+            .execute("DELETE FROM trees WHERE tree = ?1", params![tree])?;
+        Ok(())
+    }
+
+    fn tree_scan(
+        &self,
+        tree: &str,
+        prefix: &[u8],
+        reverse: bool,
+    ) -> anyhow::Result<Scan<Vec<u8>, Vec<u8>>> {
+        let sql = if reverse {
+            "SELECT key, value FROM trees WHERE tree = ?1 AND key >= ?2 AND (?3 IS NULL OR key < ?3)
+             AND (?4 IS NULL OR key < ?4) ORDER BY key DESC LIMIT ?5"
+        } else {
+            "SELECT key, value FROM trees WHERE tree = ?1 AND key >= ?2 AND (?3 IS NULL OR key < ?3)
+             AND (?4 IS NULL OR key > ?4) ORDER BY key LIMIT ?5"
+        };
+        let backend = self.clone();
+        let tree = tree.to_string();
+        let prefix = prefix.to_vec();
+        let end = prefix_successor(&prefix);
+        Ok(Pages::scan(move |last_key: Option<&Vec<u8>>| {
+            let connection = backend.connection();
+            let mut statement = connection.prepare_cached(sql)?;
+            let rows = statement.query_map(
+                params![tree, prefix, end, last_key, SCAN_PAGE_SIZE],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )?;
+            Ok(rows.collect::<Result<_, _>>()?)
+        }))
+    }
+
+    fn tree_seek(&self, tree: &str, start: &[u8]) -> anyhow::Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let entry = self
+            .connection()
+            .query_row(
+                "SELECT key, value FROM trees WHERE tree = ?1 AND key >= ?2 ORDER BY key LIMIT 1",
+                params![tree, start],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        Ok(entry)
+    }
+
+    fn generate_id(&self) -> anyhow::Result<u64> {
+        let id = self.connection().query_row(
+            "UPDATE ids SET next = next + 1 RETURNING next - 1",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(id)
+    }
+
+    fn advance_ids(&self, next: u64) -> anyhow::Result<()> {
+        self.connection()
+            .execute("UPDATE ids SET next = max(next, ?1)", params![next])?;
+        Ok(())
+    }
+
+    fn flush(&self) -> anyhow::Result<()> {
+        // Every write is a committed transaction
+        Ok(())
+    }
+}
@@ -1,48 +1,313 @@
-use std::ops::Deref;
+use std::{fmt::Debug, sync::Arc};
 
+use anyhow::anyhow;
 use serde::{de::DeserializeOwned, Serialize};
 use sled::Db;
 
+use crate::{
+    config::{OutputConfig, StorageBackend},
+    sled_backend::SledBackend,
+    sqlite_backend::SqliteBackend,
+};
+
+/// A type stored in its own collection, with the version of its serialized layout
+pub trait Record: Serialize + DeserializeOwned {
+    /// Name of the collection, it must not change with the name or the module of the type
+    const COLLECTION: &'static str;
+    /// Incremented with a migration in [crate::migrations] whenever the serialized layout changes
+    const VERSION: u32;
+    /// Trees of the secondary indexes, `index key -> primary key`, maintained with the records
+    const INDEXES: &'static [&'static str] = &[];
+
+    /// Key of the record in each of the [Record::INDEXES], in the same order
+    fn index_keys(&self, _key: &str) -> Vec<Vec<u8>> {
+        vec![]
+    }
+}
+
+/// Serialization of the records, JSON in SQLite so they can be read with its JSON functions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Bincode,
+    Json,
+}
+
+impl Encoding {
+    pub fn encode<T: Serialize>(self, value: &T) -> anyhow::Result<Vec<u8>> {
+        Ok(match self {
+            Self::Bincode => bincode::serialize(value)?,
+            Self::Json => serde_json::to_vec(value)?,
+        })
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> anyhow::Result<T> {
+        Ok(match self {
+            Self::Bincode => bincode::deserialize(bytes)?,
+            Self::Json => serde_json::from_slice(bytes)?,
+        })
+    }
+}
+
+/// Schema version and serialized value of a stored record
+pub type RawRecord = (u32, Vec<u8>);
+
+/// Entries of a scan, in the order of their keys or the reverse
+pub type Scan<K, V> = Box<dyn Iterator<Item = anyhow::Result<(K, V)>>>;
+
+/// Keys of a serialized record in each of its indexes, from its schema version and value
+pub type IndexKeys<'a> = dyn Fn(u32, &[u8]) -> anyhow::Result<Vec<Vec<u8>>> + 'a;
+
+/// Storage of the serialized records by collection, and of the trees of bytes used for the
+/// indexes and the schema, both sorted by key
+pub trait Backend: Debug + Send + Sync {
+    fn encoding(&self) -> Encoding;
+
+    fn get(&self, collection: &str, key: &str) -> anyhow::Result<Option<RawRecord>>;
+
+    /// Write the record without updating the indexes, for the migrations
+    fn put(&self, collection: &str, key: &str, version: u32, value: &[u8]) -> anyhow::Result<()>;
+
+    /// Write the record, or remove it without one, and replace its entries in the `indexes` in
+    /// the same transaction, so they stay consistent with concurrent writes
+    fn update(
+        &self,
+        collection: &str,
+        key: &str,
+        record: Option<(u32, &[u8])>,
+        indexes: &[&str],
+        index_keys: &IndexKeys,
+    ) -> anyhow::Result<Option<RawRecord>>;
+
+    fn records(&self, collection: &str, reverse: bool) -> anyhow::Result<Scan<String, RawRecord>>;
+
+    fn tree_get(&self, tree: &str, key: &[u8]) -> anyhow::Result<Option<Vec<u8>>>;
+
+    fn tree_insert(&self, tree: &str, key: &[u8], value: &[u8]) -> anyhow::Result<()>;
+
+    fn tree_remove(&self, tree: &str, key: &[u8]) -> anyhow::Result<()>;
+
+    fn tree_clear(&self, tree: &str) -> anyhow::Result<()>;
+
+    /// Entries of the keys starting with `prefix`
+    fn tree_scan(
+        &self,
+        tree: &str,
+        prefix: &[u8],
+        reverse: bool,
+    ) -> anyhow::Result<Scan<Vec<u8>, Vec<u8>>>;
+
+    /// The first entry from `start`
+    fn tree_seek(&self, tree: &str, start: &[u8]) -> anyhow::Result<Option<(Vec<u8>, Vec<u8>)>>;
+
+    /// Unique and increasing
+    fn generate_id(&self) -> anyhow::Result<u64>;
+
+    /// Make the next generated ids at least `next`, after a copy from another database
+    fn advance_ids(&self, next: u64) -> anyhow::Result<()>;
+
+    fn flush(&self) -> anyhow::Result<()>;
+}
+
 #[derive(Debug, Clone)]
-pub struct TypedDb(Db);
+pub struct TypedDb(Arc<dyn Backend>);
 
 impl TypedDb {
+    /// On a sled database
     pub fn new(db: Db) -> Self {
-        Self(db)
+        Self::with_backend(SledBackend::new(db))
+    }
+
+    pub fn with_backend(backend: impl Backend + 'static) -> Self {
+        Self(Arc::new(backend))
     }
 
-    pub fn insert<T: Serialize>(&self, key: &str, value: &T) -> anyhow::Result<()> {
-        let type_name = std::any::type_name::<T>().to_string();
+    /// The database of the backend, in the output configuration
+    pub fn open(output: &OutputConfig, backend: StorageBackend) -> anyhow::Result<Self> {
+        Ok(match backend {
+            StorageBackend::Sled => Self::new(sled::open(&output.sled_dir)?),
+            StorageBackend::Sqlite => Self::with_backend(SqliteBackend::open(&output.sqlite_path)?),
+        })
+    }
+
+    /// The untyped storage, for the migrations
+    pub fn backend(&self) -> &dyn Backend {
+        self.0.as_ref()
+    }
+
+    fn decode<T: Record>(&self, version: u32, value: &[u8]) -> anyhow::Result<T> {
+        if version != T::VERSION {
+            return Err(anyhow!(
+                "{} record has the schema version {version} instead of {}, the migrations didn't run",
+                T::COLLECTION,
+                T::VERSION
+            ));
+        }
+        self.0.encoding().decode(value)
+    }
+
+    /// Write the record and its index keys in the same transaction
+    fn update<T: Record>(&self, key: &str, value: Option<&T>) -> anyhow::Result<Option<T>> {
+        let bytes = value
+            .map(|value| self.0.encoding().encode(value))
+            .transpose()?;
+        let index_keys =
+            |version: u32, value: &[u8]| Ok(self.decode::<T>(version, value)?.index_keys(key));
+        let previous = self.0.update(
+            T::COLLECTION,
+            key,
+            bytes.as_deref().map(|bytes| (T::VERSION, bytes)),
+            T::INDEXES,
+            &index_keys,
+        )?;
+        previous
+            .map(|(version, value)| self.decode(version, &value))
+            .transpose()
+    }
 
-        let bytes = bincode::serialize(value)?;
-        self.0.insert(type_name + key, bytes)?;
+    pub fn insert<T: Record>(&self, key: &str, value: &T) -> anyhow::Result<()> {
+        if T::INDEXES.is_empty() {
+            let bytes = self.0.encoding().encode(value)?;
+            self.0.put(T::COLLECTION, key, T::VERSION, &bytes)?;
+        } else {
+            self.update(key, Some(value))?;
+        }
         Ok(())
     }
 
-    pub fn get<T: DeserializeOwned>(&self, key: &str) -> anyhow::Result<Option<T>> {
-        let type_name = std::any::type_name::<T>().to_string();
-        let bytes = self.0.get(type_name + key)?;
-        let Some(bytes) = bytes else {
+    pub fn get<T: Record>(&self, key: &str) -> anyhow::Result<Option<T>> {
+        let Some((version, value)) = self.0.get(T::COLLECTION, key)? else {
             return Ok(None);
         };
-        let value = bincode::deserialize_from(bytes.deref())?;
-        Ok(Some(value))
-    }
-
-    pub fn get_all<T: DeserializeOwned>(&self) -> anyhow::Result<Vec<T>> {
-        let type_name = std::any::type_name::<T>();
-
-        let all: anyhow::Result<Vec<_>> = self
-            .0
-            .scan_prefix(type_name)
-            .map(|res| {
-                let (_key, bytes) = res?;
-                let value = bincode::deserialize_from(bytes.deref())?;
-                Ok(value)
-            })
-            // expected to be in order of insertion, we want the most recent(last) first
-            .rev()
-            .collect();
-        all
+        Ok(Some(self.decode(version, &value)?))
+    }
+
+    pub fn remove<T: Record>(&self, key: &str) -> anyhow::Result<Option<T>> {
+        self.update::<T>(key, None)
+    }
+
+    /// Unique and increasing, for the keys of the append-only records
+    pub fn generate_id(&self) -> anyhow::Result<u64> {
+        self.0.generate_id()
+    }
+
+    /// Records and their keys, in the order of the keys
+    pub fn entries<T: Record>(
+        &self,
+        reverse: bool,
+    ) -> anyhow::Result<impl Iterator<Item = anyhow::Result<(String, T)>>> {
+        let storage = self.clone();
+        Ok(self.0.records(T::COLLECTION, reverse)?.map(move |res| {
+            let (key, (version, value)) = res?;
+            Ok((key, storage.decode(version, &value)?))
+        }))
+    }
+
+    /// Same order as [TypedDb::get_all], without loading every value
+    pub fn iter<T: Record>(&self) -> anyhow::Result<impl Iterator<Item = anyhow::Result<T>>> {
+        Ok(self.entries(true)?.map(|res| Ok(res?.1)))
+    }
+
+    /// Remove the values for which `keep` is false, and return how many were removed
+    pub fn retain<T: Record>(&self, keep: impl Fn(&T) -> bool) -> anyhow::Result<usize> {
+        let mut removed = 0;
+        for res in self.entries::<T>(false)? {
+            let (key, value) = res?;
+            if !keep(&value) {
+                self.update::<T>(&key, None)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    pub fn get_all<T: Record>(&self) -> anyhow::Result<Vec<T>> {
+        // expected to be in order of insertion, we want the most recent(last) first
+        self.iter()?.collect()
+    }
+
+    /// Index the records again, for the indexes that didn't exist when they were written
+    pub fn rebuild_indexes<T: Record>(&self) -> anyhow::Result<usize> {
+        for index in T::INDEXES {
+            self.0.tree_clear(index)?;
+        }
+        let mut indexed = 0;
+        for res in self.entries::<T>(false)? {
+            let (key, value) = res?;
+            for (index, index_key) in T::INDEXES.iter().zip(value.index_keys(&key)) {
+                self.0.tree_insert(index, &index_key, key.as_bytes())?;
+            }
+            indexed += 1;
+        }
+        Ok(indexed)
+    }
+
+    /// Copy the records of the collection to another database, with their indexes
+    pub fn copy_collection<T: Record>(&self, destination: &TypedDb) -> anyhow::Result<usize> {
+        let mut copied = 0;
+        for res in self.entries::<T>(false)? {
+            let (key, value) = res?;
+            destination.insert(&key, &value)?;
+            copied += 1;
+        }
+        Ok(copied)
+    }
+
+    /// Records of the index keys starting with `prefix`, in the order of the index keys
+    pub fn scan_index<T: Record>(
+        &self,
+        index: &str,
+        prefix: &[u8],
+        reverse: bool,
+    ) -> anyhow::Result<impl Iterator<Item = anyhow::Result<T>>> {
+        let storage = self.clone();
+        let entries = self.0.tree_scan(index, prefix, reverse)?;
+        Ok(entries.filter_map(move |res| {
+            res.and_then(|(_index_key, key)| storage.resolve(&key))
+                .transpose()
+        }))
+    }
+
+    /// The record of the last index key of each group of keys starting with `prefix`,
+    /// `group` is the prefix shared by the keys of the group of an index key
+    pub fn last_per_group<T: Record>(
+        &self,
+        index: &str,
+        prefix: &[u8],
+        group: impl Fn(&[u8]) -> &[u8],
+    ) -> anyhow::Result<Vec<T>> {
+        let mut last = vec![];
+        let mut start = prefix.to_vec();
+        // A seek per group, instead of reading all the keys of the groups
+        while let Some((index_key, _)) = self.0.tree_seek(index, &start)? {
+            if !index_key.starts_with(prefix) {
+                break;
+            }
+            let group = group(&index_key);
+            if let Some(entry) = self.0.tree_scan(index, group, true)?.next() {
+                last.extend(self.resolve(&entry?.1)?);
+            }
+            match prefix_successor(group) {
+                Some(successor) => start = successor,
+                None => break,
+            }
+        }
+        Ok(last)
+    }
+
+    /// The record of an index entry, none if it was removed since the index was read
+    fn resolve<T: Record>(&self, key: &[u8]) -> anyhow::Result<Option<T>> {
+        self.get(std::str::from_utf8(key)?)
+    }
+}
+
+/// The first key after all the keys starting with `prefix`
+pub fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut successor = prefix.to_vec();
+    while let Some(last) = successor.pop() {
+        if last < u8::MAX {
+            successor.push(last + 1);
+            return Some(successor);
+        }
     }
+    None
 }
@@ -0,0 +1,201 @@
+use std::cmp::Reverse;
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{Duration, NaiveDateTime, Utc};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    error::{ApiError, ApiResult},
+    filter::glob_set,
+    storage::{Record, TypedDb},
+};
+
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+/// A user of the `local` viewer authentication, only the argon2 hash of the password is kept
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct User {
+    pub username: String,
+    /// Matched with the groups of the `viewer_auth.access` rules
+    pub groups: Vec<String>,
+    /// Glob patterns of the repositories the user can read, on top of the access rules
+    pub repositories: Vec<String>,
+    pub created_at: NaiveDateTime,
+    password_hash: String,
+}
+
+impl Record for User {
+    const COLLECTION: &'static str = "users";
+    const VERSION: u32 = 1;
+}
+
+/// Body of `POST /admin/users`
+#[derive(Debug, Deserialize)]
+pub struct NewUser {
+    pub username: String,
+    pub password: String,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    #[serde(default)]
+    pub repositories: Vec<String>,
+}
+
+/// A user as returned by the admin endpoints, without its password hash
+#[derive(Debug, Serialize)]
+pub struct UserInfo<'a> {
+    pub username: &'a str,
+    pub groups: &'a [String],
+    pub repositories: &'a [String],
+    pub created_at: NaiveDateTime,
+}
+
+impl User {
+    pub fn create(storage: &TypedDb, new_user: NewUser) -> ApiResult<Self> {
+        let valid_username = !new_user.username.is_empty()
+            && new_user.username.len() <= 100
+            && new_user
+                .username
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-' | '@'));
+        if !valid_username {
+            return Err(ApiError::InvalidUser(format!(
+                "invalid username {:?}",
+                new_user.username
+            )));
+        }
+        if new_user.password.len() < MIN_PASSWORD_LENGTH {
+            return Err(ApiError::InvalidUser(format!(
+                "the password must be at least {MIN_PASSWORD_LENGTH} characters"
+            )));
+        }
+        glob_set(new_user.repositories.iter().map(String::as_str))?;
+        if storage.get::<Self>(&new_user.username)?.is_some() {
+            return Err(ApiError::UserAlreadyExists(new_user.username));
+        }
+
+        let salt = SaltString::generate(&mut OsRng);
+        let password_hash = Argon2::default()
+            .hash_password(new_user.password.as_bytes(), &salt)
+            .map_err(|e| anyhow::anyhow!("hashing password: {e}"))?
+            .to_string();
+        let user = Self {
+            username: new_user.username,
+            groups: new_user.groups,
+            repositories: new_user.repositories,
+            created_at: Utc::now().naive_utc(),
+            password_hash,
+        };
+        storage.insert(&user.username, &user)?;
+        Ok(user)
+    }
+
+    pub fn get(storage: &TypedDb, username: &str) -> ApiResult<Option<Self>> {
+        Ok(storage.get(username)?)
+    }
+
+    /// The user, if the password is correct
+    pub fn authenticate(
+        storage: &TypedDb,
+        username: &str,
+        password: &str,
+    ) -> ApiResult<Option<Self>> {
+        let Some(user) = Self::get(storage, username)? else {
+            return Ok(None);
+        };
+        let hash = PasswordHash::new(&user.password_hash)
+            .map_err(|e| anyhow::anyhow!("invalid password hash of {username}: {e}"))?;
+        let verified = Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok();
+        Ok(verified.then_some(user))
+    }
+
+    pub fn list(storage: &TypedDb) -> ApiResult<Vec<Self>> {
+        let mut users: Vec<Self> = storage.get_all()?;
+        users.sort_by_key(|user| Reverse(user.created_at));
+        Ok(users)
+    }
+
+    /// The sessions of the user are refused once it is deleted
+    pub fn delete(storage: &TypedDb, username: &str) -> ApiResult<Self> {
+        storage
+            .remove(username)?
+            .ok_or_else(|| ApiError::UserNotFound(username.to_string()))
+    }
+
+    pub fn info(&self) -> UserInfo<'_> {
+        UserInfo {
+            username: &self.username,
+            groups: &self.groups,
+            repositories: &self.repositories,
+            created_at: self.created_at,
+        }
+    }
+}
+
+/// A signed in user, the key in the database is the hash of the cookie value
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Session {
+    pub username: String,
+    /// Groups of the OIDC claims, the local users have theirs in [User]
+    pub groups: Vec<String>,
+    pub expires_at: NaiveDateTime,
+}
+
+impl Record for Session {
+    const COLLECTION: &'static str = "sessions";
+    const VERSION: u32 = 1;
+}
+
+fn session_key(token: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(token.as_bytes()))
+}
+
+impl Session {
+    /// Store a new session, and return the token to set in the cookie
+    pub fn create(
+        storage: &TypedDb,
+        username: &str,
+        groups: Vec<String>,
+        ttl: Duration,
+    ) -> ApiResult<String> {
+        let token = URL_SAFE_NO_PAD.encode(rand::thread_rng().gen::<[u8; 32]>());
+        let session = Self {
+            username: username.to_string(),
+            groups,
+            expires_at: Utc::now().naive_utc() + ttl,
+        };
+        storage.insert(&session_key(&token), &session)?;
+        Ok(token)
+    }
+
+    /// The session of the token, expired sessions are removed
+    pub fn find(storage: &TypedDb, token: &str) -> ApiResult<Option<Self>> {
+        let key = session_key(token);
+        let Some(session) = storage.get::<Self>(&key)? else {
+            return Ok(None);
+        };
+        if session.expires_at < Utc::now().naive_utc() {
+            storage.remove::<Self>(&key)?;
+            return Ok(None);
+        }
+        Ok(Some(session))
+    }
+
+    pub fn delete(storage: &TypedDb, token: &str) -> ApiResult<()> {
+        storage.remove::<Self>(&session_key(token))?;
+        Ok(())
+    }
+
+    /// Sessions that were never used after their expiration
+    pub fn remove_expired(storage: &TypedDb) -> ApiResult<usize> {
+        let now = Utc::now().naive_utc();
+        Ok(storage.retain::<Self>(|session| session.expires_at >= now)?)
+    }
+}
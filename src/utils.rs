@@ -1,6 +1,9 @@
-use std::{fs, path::Path};
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+};
 
-use crate::config;
+use crate::config::{self, ArtifactBackend};
 
 pub fn init_environment() {
     dotenvy::dotenv().ok();
@@ -9,18 +12,17 @@ pub fn init_environment() {
     }
     pretty_env_logger::init();
 
-    let json_dir = Path::new(config::JSON_REPORTS_DIR);
-    if !json_dir.is_dir() {
-        fs::create_dir_all(json_dir).unwrap();
-    };
-    let html_dir = Path::new(config::HTML_REPORTS_DIR);
-    if !html_dir.is_dir() {
-        fs::create_dir_all(html_dir).unwrap();
-    };
-    let repos_dir = Path::new(config::REPOSITORIES_DIR);
-    if !repos_dir.is_dir() {
-        fs::create_dir_all(repos_dir).unwrap();
-    };
+    let config = config::init().expect("invalid server configuration");
+    let output = &config.output;
+    let mut dirs = vec![&output.repositories_dir, &output.sources_dir];
+    if output.artifact_backend == ArtifactBackend::Local {
+        dirs.extend([&output.json_reports_dir, &output.html_reports_dir]);
+    }
+    for dir in dirs {
+        if !dir.is_dir() {
+            fs::create_dir_all(dir).unwrap();
+        };
+    }
 }
 
 pub fn url_safe_string(input: &str) -> String {
@@ -40,3 +42,55 @@ pub fn url_safe_string(input: &str) -> String {
         })
         .collect()
 }
+
+/// Size of a file, or of a directory and its content, symbolic links aren't followed
+pub fn disk_usage(path: &Path) -> io::Result<u64> {
+    let metadata = fs::symlink_metadata(path)?;
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        size += disk_usage(&entry?.path())?;
+    }
+    Ok(size)
+}
+
+/// Remove the file or directory if it exists, and return its size
+pub fn remove_path(path: &Path) -> io::Result<Option<u64>> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let size = disk_usage(path)?;
+    if metadata.is_dir() {
+        fs::remove_dir_all(path)?;
+    } else {
+        fs::remove_file(path)?;
+    }
+    Ok(Some(size))
+}
+
+/// Directory in the system temporary directory, removed when dropped
+#[derive(Debug)]
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> io::Result<Self> {
+        let path = env::temp_dir().join(format!("llvm-cov-host-{name}"));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path)?;
+        Ok(Self(path))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,326 @@
+use std::future::{ready, Ready};
+
+use actix_web::{
+    dev::{Payload, ServiceRequest},
+    http::header,
+    web, FromRequest, HttpRequest,
+};
+
+use crate::{
+    api_keys::{Permission, Principal, API_KEY_HEADER},
+    config::{self, AccessRule, ViewerAuthMode},
+    error::{ApiError, ApiResult},
+    filter::glob_set,
+    report,
+    storage::TypedDb,
+    users::{Session, User},
+};
+
+/// Cookie containing the session token of the `local` and `oidc` viewer authentications
+pub const SESSION_COOKIE: &str = "llvm_cov_host_session";
+
+/// A signed in user, with the groups used to match the access rules
+#[derive(Debug, Clone)]
+pub struct ViewerIdentity {
+    pub username: String,
+    pub groups: Vec<String>,
+    /// Read access granted to the user itself, on top of the access rules
+    pub repositories: Vec<String>,
+}
+
+/// Caller of the dashboard, the reports api and the HTML reports
+#[derive(Debug)]
+pub enum Viewer {
+    /// The viewer authentication is disabled, everything is public
+    Public,
+    /// An api key or OIDC token, it needs the [Permission::Read] permission
+    Principal(Principal),
+    User(ViewerIdentity),
+}
+
+/// Repository patterns of the rules matching the user or one of its groups
+fn rule_patterns<'a>(
+    rules: &'a [AccessRule],
+    identity: &'a ViewerIdentity,
+) -> impl Iterator<Item = &'a str> {
+    rules
+        .iter()
+        .filter(|rule| {
+            rule.users.contains(&identity.username)
+                || rule
+                    .groups
+                    .iter()
+                    .any(|group| identity.groups.contains(group))
+        })
+        .flat_map(|rule| rule.repositories.iter().map(String::as_str))
+}
+
+impl ViewerIdentity {
+    pub fn can_read(&self, rules: &[AccessRule], repository: &str) -> ApiResult<bool> {
+        let patterns = self
+            .repositories
+            .iter()
+            .map(String::as_str)
+            .chain(rule_patterns(rules, self));
+        Ok(glob_set(patterns)?.is_match(repository))
+    }
+}
+
+impl Viewer {
+    fn authenticate(req: &HttpRequest) -> ApiResult<Self> {
+        let viewer_auth = &config::get().viewer_auth;
+        if viewer_auth.mode == ViewerAuthMode::None {
+            return Ok(Self::Public);
+        }
+        let storage = req
+            .app_data::<web::Data<TypedDb>>()
+            .expect("storage in the app data");
+
+        // Scripts use the same credentials as the uploads
+        let has_credentials = req.headers().contains_key(API_KEY_HEADER)
+            || req
+                .headers()
+                .get(header::AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .is_some_and(|value| value.starts_with("Bearer "));
+        if has_credentials {
+            return Principal::authenticate(req).map(Self::Principal);
+        }
+
+        match viewer_auth.mode {
+            ViewerAuthMode::None => Ok(Self::Public),
+            ViewerAuthMode::Local => {
+                let cookie = req.cookie(SESSION_COOKIE).ok_or(ApiError::LoginRequired)?;
+                let session =
+                    Session::find(storage, cookie.value())?.ok_or(ApiError::LoginRequired)?;
+                // The user is read on every request, so its deletion or new groups apply right away
+                let user = User::get(storage, &session.username)?.ok_or(ApiError::LoginRequired)?;
+                Ok(Self::User(ViewerIdentity {
+                    username: user.username,
+                    groups: user.groups,
+                    repositories: user.repositories,
+                }))
+            }
+            ViewerAuthMode::Oidc => {
+                // The groups come from the claims at sign in, they apply on the next sign in
+                let cookie = req.cookie(SESSION_COOKIE).ok_or(ApiError::LoginRequired)?;
+                let session =
+                    Session::find(storage, cookie.value())?.ok_or(ApiError::LoginRequired)?;
+                Ok(Self::User(ViewerIdentity {
+                    username: session.username,
+                    groups: session.groups,
+                    repositories: vec![],
+                }))
+            }
+            ViewerAuthMode::TrustedHeader => {
+                let peer = req.peer_addr().map(|addr| addr.ip());
+                if !peer.is_some_and(|ip| viewer_auth.trusted_proxies.contains(&ip)) {
+                    return Err(ApiError::Unauthorized);
+                }
+                let header = |name: &str| {
+                    req.headers()
+                        .get(name)
+                        .and_then(|value| value.to_str().ok())
+                        .map(str::trim)
+                        .filter(|value| !value.is_empty())
+                };
+                let username = header(&viewer_auth.user_header).ok_or(ApiError::Unauthorized)?;
+                let groups = viewer_auth
+                    .groups_header
+                    .as_deref()
+                    .and_then(header)
+                    .map(|groups| groups.split(',').map(|g| g.trim().to_string()).collect())
+                    .unwrap_or_default();
+                Ok(Self::User(ViewerIdentity {
+                    username: username.to_string(),
+                    groups,
+                    repositories: vec![],
+                }))
+            }
+        }
+    }
+
+    pub fn can_read(&self, repository: &str) -> ApiResult<bool> {
+        match self {
+            Self::Public => Ok(true),
+            Self::Principal(principal) => {
+                Ok(principal.authorize(repository, Permission::Read).is_ok())
+            }
+            Self::User(identity) => {
+                identity.can_read(&config::get().viewer_auth.access, repository)
+            }
+        }
+    }
+
+    pub fn authorize_read(&self, repository: &str) -> ApiResult<()> {
+        if self.can_read(repository)? {
+            Ok(())
+        } else {
+            // Same as a missing report, so the names of the private repositories aren't leaked
+            Err(ApiError::ReportNotFound)
+        }
+    }
+
+    pub fn is_admin(&self) -> bool {
+        match self {
+            Self::Public => false,
+            Self::Principal(principal) => principal.authorize_admin().is_ok(),
+            Self::User(identity) => {
+                let admins = &config::get().viewer_auth.admins;
+                admins.users.contains(&identity.username)
+                    || identity
+                        .groups
+                        .iter()
+                        .any(|group| admins.groups.contains(group))
+            }
+        }
+    }
+
+    /// The audit log isn't available without the viewer authentication, as it would be public
+    pub fn authorize_admin(&self) -> ApiResult<()> {
+        if self.is_admin() {
+            Ok(())
+        } else {
+            Err(ApiError::Forbidden("Admin isn't allowed".to_string()))
+        }
+    }
+
+    pub fn username(&self) -> Option<&str> {
+        match self {
+            Self::User(identity) => Some(&identity.username),
+            Self::Public | Self::Principal(_) => None,
+        }
+    }
+}
+
+/// Check the read access of `/view/{name}/...`, the first path component is the name of the report
+pub fn authorize_view(req: &ServiceRequest) -> ApiResult<()> {
+    let viewer = Viewer::authenticate(req.request())?;
+    if let Viewer::Public = viewer {
+        return Ok(());
+    }
+    let storage = req
+        .app_data::<web::Data<TypedDb>>()
+        .expect("storage in the app data");
+    let name = req
+        .path()
+        .trim_start_matches("/view/")
+        .split('/')
+        .next()
+        .unwrap_or_default();
+    let repository = report::report_repository(storage, name)?.ok_or(ApiError::ReportNotFound)?;
+    viewer.authorize_read(&repository)
+}
+
+impl FromRequest for Viewer {
+    type Error = ApiError;
+    type Future = Ready<ApiResult<Self>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(Self::authenticate(req))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compare::Comparison,
+        report::{report_repository, ReportHistory},
+        test_utils::memory_db,
+        users::NewUser,
+    };
+
+    #[test]
+    fn test_viewer_access() {
+        let storage = memory_db();
+
+        let new_user = |username: &str, password: &str| NewUser {
+            username: username.to_string(),
+            password: password.to_string(),
+            groups: vec!["dev".to_string()],
+            repositories: vec!["GreeFine/public-*".to_string()],
+        };
+        User::create(&storage, new_user("alice", "correct horse")).unwrap();
+        assert!(User::create(&storage, new_user("alice", "correct horse")).is_err());
+        assert!(User::create(&storage, new_user("bob", "short")).is_err());
+        assert!(User::create(&storage, new_user("../bob", "correct horse")).is_err());
+
+        let user = User::authenticate(&storage, "alice", "correct horse")
+            .unwrap()
+            .unwrap();
+        assert!(User::authenticate(&storage, "alice", "wrong horse")
+            .unwrap()
+            .is_none());
+        assert!(User::authenticate(&storage, "nobody", "correct horse")
+            .unwrap()
+            .is_none());
+
+        let token =
+            Session::create(&storage, &user.username, vec![], chrono::Duration::hours(1)).unwrap();
+        assert_eq!(
+            Session::find(&storage, &token).unwrap().unwrap().username,
+            "alice"
+        );
+        Session::delete(&storage, &token).unwrap();
+        assert!(Session::find(&storage, &token).unwrap().is_none());
+        let expired = Session::create(
+            &storage,
+            &user.username,
+            vec![],
+            chrono::Duration::hours(-1),
+        )
+        .unwrap();
+        assert!(Session::find(&storage, &expired).unwrap().is_none());
+
+        let rules = [
+            AccessRule {
+                groups: vec!["dev".to_string()],
+                repositories: vec!["GreeFine/llvm-cov-host".to_string()],
+                ..Default::default()
+            },
+            AccessRule {
+                users: vec!["bob".to_string()],
+                repositories: vec!["*".to_string()],
+                ..Default::default()
+            },
+        ];
+        let identity = ViewerIdentity {
+            username: user.username.clone(),
+            groups: user.groups.clone(),
+            repositories: user.repositories.clone(),
+        };
+        assert!(identity.can_read(&rules, "GreeFine/llvm-cov-host").unwrap());
+        assert!(identity.can_read(&rules, "GreeFine/public-docs").unwrap());
+        assert!(!identity.can_read(&rules, "GreeFine/private").unwrap());
+        assert!(!identity.can_read(&[], "GreeFine/llvm-cov-host").unwrap());
+
+        assert_eq!(User::list(&storage).unwrap().len(), 1);
+        User::delete(&storage, "alice").unwrap();
+        assert!(User::get(&storage, "alice").unwrap().is_none());
+        assert!(User::delete(&storage, "alice").is_err());
+
+        // Reports sent before the index are added from the history by the migrations
+        storage
+            .insert(
+                "2024-01-01 00:00:00",
+                &ReportHistory {
+                    repository_name: "GreeFine/private".to_string(),
+                    branch: "main".to_string(),
+                    commit: None,
+                    name: "greefine-private-main".to_string(),
+                    comparison: Comparison::default(),
+                    date: chrono::Utc::now().naive_utc(),
+                },
+            )
+            .unwrap();
+        crate::migrations::run(&storage).unwrap();
+        assert_eq!(
+            report_repository(&storage, "greefine-private-main")
+                .unwrap()
+                .as_deref(),
+            Some("GreeFine/private")
+        );
+        assert!(report_repository(&storage, "unknown").unwrap().is_none());
+    }
+}